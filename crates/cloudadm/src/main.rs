@@ -1,8 +1,14 @@
+use arc_bytes::serde::Bytes;
 use bonsaidb::client::fabruic::{error, Certificate};
 use bonsaidb::client::url::ParseError;
-use bonsaidb::client::AsyncClient;
+use bonsaidb::client::{ApiError, AsyncClient};
 use bonsaidb::core::connection::AsyncStorageConnection;
-use bonsaidb::core::schema::{Collection, Qualified, SchemaName};
+use bonsaidb::core::schema::{Qualified, SchemaName};
+use cloud::api::{
+    ApplyDeployment, ApplyDeploymentError, ApproveNodeKey, ApproveNodeKeyError, ApproveNodeLabels,
+    ApproveNodeLabelsError, StoreSecret, StoreSecretError,
+};
+use cloud::{Deployment, ResourceIdentifier};
 use miette::Diagnostic;
 use std::fs::File;
 use std::io::Read;
@@ -10,7 +16,6 @@ use std::path::PathBuf;
 use thiserror::Error;
 
 use clap::{Parser, Subcommand};
-use serde::{Deserialize, Serialize};
 
 #[derive(Parser, Debug)]
 pub(crate) struct Args {
@@ -31,12 +36,23 @@ pub(crate) enum Commands {
     List,
     /// Create a Database
     Create { name: String },
+    /// Validate a deployment against the cluster topology and roll it out
+    Apply { file: PathBuf },
+    /// Store the value of a secret, read from a file or `-` for stdin
+    Secret {
+        identifier: ResourceIdentifier,
+        file: PathBuf,
+    },
+    /// Seal secrets for a node with the new encryption key it registered with
+    ApproveKey { node: String, key: String },
+    /// Let selectors match a node on the labels it registered with
+    ApproveLabels { node: String, labels: Vec<String> },
 }
 
 #[derive(Error, Debug, Diagnostic)]
 enum Error {
     #[error(transparent)]
-    BonsaidbClient(#[from] bonsaidb::client::Error),
+    BonsaidbClient(Box<bonsaidb::client::Error>),
     #[error(transparent)]
     BonsaidbCore(#[from] bonsaidb::core::Error),
     #[error(transparent)]
@@ -44,16 +60,52 @@ enum Error {
     #[error(transparent)]
     Certificate(#[from] error::Certificate),
     #[error(transparent)]
-    IOError(#[from] std::io::Error),
+    IO(#[from] std::io::Error),
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+    #[error(transparent)]
+    Apply(Box<ApiError<ApplyDeploymentError>>),
+    #[error(transparent)]
+    StoreSecret(Box<ApiError<StoreSecretError>>),
+    #[error(transparent)]
+    ApproveNodeKey(Box<ApiError<ApproveNodeKeyError>>),
+    #[error(transparent)]
+    ApproveNodeLabels(Box<ApiError<ApproveNodeLabelsError>>),
 }
 
-type Result<T, E = Error> = miette::Result<T, E>;
+// The client errors are large, they are boxed so every result is not.
+impl From<bonsaidb::client::Error> for Error {
+    fn from(value: bonsaidb::client::Error) -> Self {
+        Error::BonsaidbClient(Box::new(value))
+    }
+}
+
+impl From<ApiError<ApplyDeploymentError>> for Error {
+    fn from(value: ApiError<ApplyDeploymentError>) -> Self {
+        Error::Apply(Box::new(value))
+    }
+}
+
+impl From<ApiError<StoreSecretError>> for Error {
+    fn from(value: ApiError<StoreSecretError>) -> Self {
+        Error::StoreSecret(Box::new(value))
+    }
+}
+
+impl From<ApiError<ApproveNodeKeyError>> for Error {
+    fn from(value: ApiError<ApproveNodeKeyError>) -> Self {
+        Error::ApproveNodeKey(Box::new(value))
+    }
+}
 
-#[derive(Serialize, Deserialize, Collection)]
-#[collection(name = "Test")]
-struct TestCollection {
-    pub name: String,
+impl From<ApiError<ApproveNodeLabelsError>> for Error {
+    fn from(value: ApiError<ApproveNodeLabelsError>) -> Self {
+        Error::ApproveNodeLabels(Box::new(value))
+    }
 }
+
+type Result<T, E = Error> = miette::Result<T, E>;
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
@@ -72,7 +124,7 @@ async fn main() -> Result<()> {
             let dbs = client.list_databases().await?;
             println!("Listing databases");
             for db in dbs {
-                println!("{}: {}", db.name, db.schema.to_string());
+                println!("{}: {}", db.name, db.schema);
             }
         }
         Commands::Create { name } => {
@@ -80,6 +132,40 @@ async fn main() -> Result<()> {
                 .create_database_with_schema(&name, SchemaName::new("private", "deployments"), true)
                 .await?;
         }
+        Commands::Apply { file } => {
+            let deployment: Deployment = serde_yaml::from_reader(File::open(file)?)?;
+            println!("Applying {}", deployment.resource_identifier);
+            client
+                .send_api_request(&ApplyDeployment { deployment })
+                .await?;
+        }
+        Commands::Secret { identifier, file } => {
+            let mut value = vec![];
+            if file.as_os_str() == "-" {
+                std::io::stdin().read_to_end(&mut value)?;
+            } else {
+                File::open(file)?.read_to_end(&mut value)?;
+            }
+            println!("Storing secret {identifier}");
+            client
+                .send_api_request(&StoreSecret {
+                    identifier,
+                    value: Bytes::from(value),
+                })
+                .await?;
+        }
+        Commands::ApproveKey { node, key } => {
+            println!("Approving the encryption key of {node}");
+            client
+                .send_api_request(&ApproveNodeKey { node, key })
+                .await?;
+        }
+        Commands::ApproveLabels { node, labels } => {
+            println!("Approving the labels of {node}");
+            client
+                .send_api_request(&ApproveNodeLabels { node, labels })
+                .await?;
+        }
     }
 
     Ok(())
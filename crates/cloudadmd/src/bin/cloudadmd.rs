@@ -11,8 +11,14 @@ async fn main() -> Result<()> {
         .init();
 
     let args = Args::parse();
+    let print_public_key = args.public_key;
 
     let cfg = load_config(args)?;
 
+    if print_public_key {
+        println!("{}", cfg.signer()?.public_key());
+        return Ok(());
+    }
+
     listen(cfg).await
 }
@@ -0,0 +1,479 @@
+use std::fmt::{Debug, Formatter};
+use std::time::Instant;
+
+use async_trait::async_trait;
+use bonsaidb::core::connection::AsyncStorageConnection;
+use bonsaidb::core::document::CollectionDocument;
+use bonsaidb::core::schema::SerializedCollection;
+use bonsaidb::server::api::{Handler, HandlerError, HandlerResult, HandlerSession};
+use bonsaidb::server::{Backend, ServerDatabase};
+use chrono::Utc;
+use cloud::api::{ApplyDeployment, ApplyDeploymentError};
+use cloud::{
+    Deployment, DeploymentEvent, DeploymentReport, DeploymentState, Node, ResourceIdentifier,
+    Secret,
+};
+use deadpool_lapin::lapin::options::{
+    BasicAckOptions, BasicConsumeOptions, BasicPublishOptions, ExchangeDeclareOptions,
+    QueueBindOptions, QueueDeclareOptions,
+};
+use deadpool_lapin::lapin::types::FieldTable;
+use deadpool_lapin::lapin::{BasicProperties, Consumer, ExchangeKind};
+use deadpool_lapin::Pool;
+use futures::StreamExt;
+use nodelet::bus::{self, NETWORK_TOPIC, ZONE_TOPIC};
+use nodelet::secrets::{self, SealedSecret};
+use nodelet::signing::Signer;
+use nodelet::NodeObject;
+use tracing::{debug, info, instrument, warn};
+
+use crate::metrics;
+use crate::secrets::Vault;
+use crate::topology::Topology;
+use crate::{Error, Result};
+
+pub const DEPLOYMENT_EXCHANGE: &str = "deployment.nodelet";
+pub const REPORT_EXCHANGE: &str = "deployment_reports";
+const REPORT_QUEUE: &str = "cloudadmd.reports";
+
+/// Server side state of cloudadmd, available to all API handlers.
+pub struct Controller {
+    amqp: Pool,
+    signer: Signer,
+    vault: Vault,
+}
+
+impl Debug for Controller {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Controller").finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl Backend for Controller {
+    type Error = Error;
+    type ClientData = ();
+}
+
+impl Controller {
+    pub fn new(amqp: Pool, signer: Signer, vault: Vault) -> Self {
+        Self {
+            amqp,
+            signer,
+            vault,
+        }
+    }
+
+    pub(crate) fn vault(&self) -> &Vault {
+        &self.vault
+    }
+
+    /// Validate the deployment against everything stored, store it and publish its
+    /// resources and the secrets its zones use to the nodes it selects.
+    #[instrument(skip_all, fields(identifier = %deployment.resource_identifier))]
+    pub async fn apply(
+        &self,
+        db: &ServerDatabase<Controller>,
+        nodes: &ServerDatabase<Controller>,
+        secrets: &ServerDatabase<Controller>,
+        deployment: Deployment,
+    ) -> Result<()> {
+        let identifier = deployment.resource_identifier.clone();
+        let resources = resources(&deployment)?;
+        if resources
+            .iter()
+            .any(|(_, object)| matches!(object, NodeObject::Secret(_)))
+        {
+            return Err(Error::SecretInDeployment(identifier));
+        }
+
+        let mut graph = vec![];
+        for doc in Deployment::all_async(db).await? {
+            if doc.contents.resource_identifier == identifier {
+                continue;
+            }
+            graph.extend(self::resources(&doc.contents)?);
+        }
+        graph.extend(resources.iter().cloned());
+        Topology::new(&graph).validate()?;
+        debug!("topology is consistent");
+
+        let targets: Vec<Node> = Node::all_async(nodes)
+            .await?
+            .into_iter()
+            .map(|doc| doc.contents)
+            .filter(|node| deployment.selector.matches(node))
+            .collect();
+        if targets.is_empty() {
+            warn!("no registered node matches the selector, the deployment is not sent anywhere");
+        }
+        let sealed = self
+            .seal_secrets(secrets, &deployment, &resources, &targets)
+            .await?;
+
+        deployment
+            .overwrite_into_async(&identifier, db)
+            .await
+            .map_err(|e| e.error)?;
+        self.publish(&targets, resources, sealed).await?;
+        info!("deployment applied");
+        Ok(())
+    }
+
+    /// Follow the reports of the nodes and keep the state of the deployments up to date.
+    pub async fn consume_reports(&self, db: &ServerDatabase<Controller>) -> Result<()> {
+        let mut consumer = self.subscribe(REPORT_EXCHANGE, REPORT_QUEUE).await?;
+        info!("following deployment reports");
+        while let Some(delivery) = consumer.next().await {
+            let delivery = delivery?;
+            match serde_json::from_slice::<DeploymentReport>(&delivery.data) {
+                Ok(report) => self.record(db, report).await?,
+                Err(e) => warn!(error = %e, "dropping malformed report"),
+            }
+            delivery.ack(BasicAckOptions::default()).await?;
+        }
+        Ok(())
+    }
+
+    /// Consume a durable queue bound to the fanout `exchange`.
+    pub(crate) async fn subscribe(&self, exchange: &str, queue: &str) -> Result<Consumer> {
+        let conn = self.amqp.get().await?;
+        let channel = conn.create_channel().await?;
+        channel
+            .exchange_declare(
+                exchange,
+                ExchangeKind::Fanout,
+                ExchangeDeclareOptions {
+                    durable: true,
+                    ..Default::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+        channel
+            .queue_declare(
+                queue,
+                QueueDeclareOptions {
+                    durable: true,
+                    ..Default::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+        channel
+            .queue_bind(
+                queue,
+                exchange,
+                "",
+                QueueBindOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+        Ok(channel
+            .basic_consume(
+                queue,
+                queue,
+                BasicConsumeOptions::default(),
+                FieldTable::default(),
+            )
+            .await?)
+    }
+
+    /// Store the state and progress a node reported for a deployment.
+    pub async fn record(
+        &self,
+        db: &ServerDatabase<Controller>,
+        report: DeploymentReport,
+    ) -> Result<()> {
+        let (identifier, state, result, progress) = match report {
+            DeploymentReport::Ensure {
+                identifier,
+                state,
+                result,
+                progress,
+                ..
+            }
+            | DeploymentReport::Remove {
+                identifier,
+                state,
+                result,
+                progress,
+            } => (identifier, state, result, progress),
+            DeploymentReport::List { .. } => return Ok(()),
+            DeploymentReport::Health {
+                identifier,
+                health,
+                action,
+                ..
+            } => {
+                if let Some(action) = action {
+                    info!(%identifier, action, "node acted on failing services");
+                }
+                if let Some(mut doc) = deployment_of(db, &identifier).await? {
+                    doc.contents.health = Some(health);
+                    doc.contents.updated_at = Some(Utc::now().naive_utc());
+                    doc.update_async(db).await?;
+                }
+                return Ok(());
+            }
+            // Whoever sent the event picked the identifier, the deployment is left alone.
+            DeploymentReport::Rejected {
+                routing_key,
+                identifier,
+                reason,
+            } => {
+                warn!(?identifier, routing_key, reason, "node rejected an event");
+                return Ok(());
+            }
+        };
+
+        let Some(mut doc) = deployment_of(db, &identifier).await? else {
+            debug!(%identifier, "report for unknown deployment");
+            return Ok(());
+        };
+        doc.contents.state = state;
+        match result {
+            // Status report
+            None => doc.contents.progress = progress,
+            Some(Ok(())) => doc.contents.progress = None,
+            // Keep the last step, so it shows where the deployment failed.
+            Some(Err(e)) => {
+                warn!(%identifier, error = e, "node reported failure");
+                doc.contents.state = DeploymentState::Failed;
+            }
+        }
+        doc.contents.updated_at = Some(Utc::now().naive_utc());
+        doc.update_async(db).await?;
+        Ok(())
+    }
+
+    /// Publish the resources to each of the `targets`, under routing keys only that node
+    /// is bound to.
+    async fn publish(
+        &self,
+        targets: &[Node],
+        mut resources: Vec<(ResourceIdentifier, NodeObject)>,
+        sealed: Vec<(String, ResourceIdentifier, SealedSecret)>,
+    ) -> Result<()> {
+        let conn = self.amqp.get().await?;
+        let channel = conn.create_channel().await?;
+        channel
+            .exchange_declare(
+                DEPLOYMENT_EXCHANGE,
+                ExchangeKind::Topic,
+                ExchangeDeclareOptions {
+                    durable: true,
+                    ..Default::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+
+        // Zones write their secrets on the node, so those go out first and only to the
+        // nodes they were sealed for.
+        let mut events = vec![];
+        for (node, secret, data) in sealed {
+            events.push((
+                secrets::routing_key(&node),
+                serde_json::to_vec(&DeploymentEvent::Ensure {
+                    data,
+                    identifier: secret,
+                })?,
+            ));
+        }
+        // Nodes resolve zone interfaces against their networks, so networks go out first.
+        resources.sort_by_key(|(_, object)| matches!(object, NodeObject::Zone(_)));
+        for (identifier, object) in resources {
+            let (topic, payload) = match object {
+                NodeObject::Zone(data) => (
+                    ZONE_TOPIC,
+                    serde_json::to_vec(&DeploymentEvent::Ensure { data, identifier })?,
+                ),
+                NodeObject::Network(data) => (
+                    NETWORK_TOPIC,
+                    serde_json::to_vec(&DeploymentEvent::Ensure { data, identifier })?,
+                ),
+                // Refused by apply, secrets are never part of a deployment.
+                NodeObject::Secret(_) => continue,
+            };
+            for node in targets {
+                events.push((bus::routing_key(topic, &node.name), payload.clone()));
+            }
+        }
+
+        for (routing_key, payload) in events {
+            let mut headers = FieldTable::default();
+            self.signer
+                .sign(&routing_key, &payload)
+                .insert_into(&mut headers);
+            channel
+                .basic_publish(
+                    DEPLOYMENT_EXCHANGE,
+                    &routing_key,
+                    BasicPublishOptions::default(),
+                    &payload,
+                    BasicProperties::default().with_headers(headers),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Resources of a deployment are stored as JSON encoded [`NodeObject`]s. Each is
+/// deployed under its own identifier: its name, or the one of the deployment, with the
+/// tenant and version of the deployment.
+fn resources(deployment: &Deployment) -> Result<Vec<(ResourceIdentifier, NodeObject)>> {
+    let deployed = &deployment.resource_identifier;
+    let mut resources: Vec<(ResourceIdentifier, NodeObject)> = vec![];
+    for (index, resource) in deployment.resources.iter().enumerate() {
+        let object: NodeObject =
+            serde_json::from_slice(resource).map_err(|source| Error::InvalidResource {
+                identifier: deployed.clone(),
+                index,
+                source,
+            })?;
+        let name = match &object {
+            NodeObject::Zone(zone) => zone.name.clone(),
+            NodeObject::Network(network) => network.name.clone(),
+            NodeObject::Secret(_) => None,
+        };
+        let identifier = ResourceIdentifier {
+            name: name.unwrap_or_else(|| deployed.name.clone()),
+            ..deployed.clone()
+        };
+        if resources.iter().any(|(other, _)| *other == identifier) {
+            return Err(Error::DuplicateResource {
+                identifier: deployed.clone(),
+                resource: Box::new(identifier),
+            });
+        }
+        resources.push((identifier, object));
+    }
+    Ok(resources)
+}
+
+/// The deployment a resource reported by a node belongs to.
+async fn deployment_of(
+    db: &ServerDatabase<Controller>,
+    identifier: &ResourceIdentifier,
+) -> Result<Option<CollectionDocument<Deployment>>> {
+    if let Some(doc) = Deployment::get_async(identifier, db).await? {
+        return Ok(Some(doc));
+    }
+    // Named resources carry the tenant and version of their deployment.
+    Ok(Deployment::all_async(db).await?.into_iter().find(|doc| {
+        let deployed = &doc.contents.resource_identifier;
+        deployed.tenant == identifier.tenant
+            && deployed.version == identifier.version
+            && deployed.revision == identifier.revision
+            && resources(&doc.contents)
+                .is_ok_and(|resources| resources.iter().any(|(other, _)| other == identifier))
+    }))
+}
+
+#[derive(Debug)]
+pub struct ApplyDeploymentHandler;
+
+#[async_trait]
+impl Handler<ApplyDeployment, Controller> for ApplyDeploymentHandler {
+    async fn handle(
+        session: HandlerSession<'_, Controller>,
+        request: ApplyDeployment,
+    ) -> HandlerResult<ApplyDeployment> {
+        let controller = session.server.backend();
+        let started = Instant::now();
+        let result = async {
+            let db = session
+                .as_client
+                .database::<Deployment>("deployments")
+                .await?;
+            let nodes = session.as_client.database::<Node>("nodes").await?;
+            let secrets = session.as_client.database::<Secret>("secrets").await?;
+            controller
+                .apply(&db, &nodes, &secrets, request.deployment)
+                .await
+        }
+        .await;
+        metrics::request_handled("apply_deployment", started.elapsed(), result.is_ok());
+        result.map_err(|e| HandlerError::Api(e.into()))
+    }
+}
+
+impl From<Error> for ApplyDeploymentError {
+    fn from(value: Error) -> Self {
+        match value {
+            Error::Topology(e) => ApplyDeploymentError::Rejected {
+                conflicts: e.conflicts.iter().map(ToString::to_string).collect(),
+            },
+            e @ (Error::InvalidResource { .. }
+            | Error::DuplicateResource { .. }
+            | Error::SecretInDeployment(_)
+            | Error::MissingSecret(_)
+            | Error::ForeignSecret { .. }
+            | Error::NoNodeKey(_)) => ApplyDeploymentError::Invalid(e.to_string()),
+            e => ApplyDeploymentError::Internal(e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cloud::Selector;
+
+    fn deployment(resources: &[&str]) -> Deployment {
+        Deployment {
+            resource_identifier: "res://example.org/web@1.0.0".parse().unwrap(),
+            created_at: Utc::now().naive_utc(),
+            updated_at: None,
+            resources: resources
+                .iter()
+                .map(|resource| resource.as_bytes().to_vec().into())
+                .collect(),
+            state: DeploymentState::Configured,
+            files: vec![],
+            selector: Selector::Node {
+                name: "node1".to_owned(),
+            },
+            progress: None,
+            health: None,
+        }
+    }
+
+    fn zone(name: Option<&str>) -> String {
+        let name = name.map_or(String::new(), |name| format!(r#""name":"{name}","#));
+        format!(r#"{{"Zone":{{{name}"brand":"LinkedPkg","autoboot":true,"ip_type":"Exclusive"}}}}"#)
+    }
+
+    fn names(deployment: &Deployment) -> Vec<String> {
+        resources(deployment)
+            .unwrap()
+            .into_iter()
+            .map(|(identifier, _)| identifier.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn resources_are_named_under_the_tenant_and_version_of_their_deployment() {
+        let network = r#"{"Network":{"tenant":"example.org","name":"lan","switches":[]}}"#;
+        let deployment = deployment(&[network, &zone(None), &zone(Some("db"))]);
+        assert_eq!(
+            names(&deployment),
+            [
+                "res://example.org/lan@1.0.0",
+                "res://example.org/web@1.0.0",
+                "res://example.org/db@1.0.0",
+            ]
+        );
+    }
+
+    #[test]
+    fn resources_of_one_deployment_need_distinct_names() {
+        let deployment = deployment(&[&zone(None), &zone(Some("web"))]);
+        assert!(matches!(
+            resources(&deployment),
+            Err(Error::DuplicateResource { .. })
+        ));
+    }
+}
@@ -0,0 +1,180 @@
+//! Prometheus metrics of cloudadmd. Without the `metrics` feature recording them does
+//! nothing and no endpoint is served.
+
+use std::net::SocketAddr;
+
+use serde::Deserialize;
+
+#[cfg(not(feature = "metrics"))]
+pub use self::disabled::*;
+#[cfg(feature = "metrics")]
+pub use self::enabled::*;
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct MetricsConfig {
+    /// Address of the HTTP endpoint serving `/metrics`. Not served without one.
+    #[serde(default)]
+    pub listen: Option<SocketAddr>,
+}
+
+#[cfg(feature = "metrics")]
+mod enabled {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::sync::OnceLock;
+    use std::time::Duration;
+
+    use bonsaidb::core::schema::SerializedCollection;
+    use bonsaidb::server::ServerDatabase;
+    use cloud::{Deployment, Node};
+    use prometheus::{
+        Encoder, HistogramOpts, HistogramVec, IntGaugeVec, Opts, Registry, TextEncoder,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tracing::{debug, info, warn};
+
+    use crate::controller::Controller;
+
+    struct Metrics {
+        registry: Registry,
+        deployments: IntGaugeVec,
+        nodes: IntGaugeVec,
+        requests: HistogramVec,
+    }
+
+    impl Metrics {
+        fn new() -> prometheus::Result<Self> {
+            let registry = Registry::new_custom(Some("cloudadmd".into()), None)?;
+            let deployments =
+                IntGaugeVec::new(Opts::new("deployments", "Deployments by state"), &["state"])?;
+            let nodes = IntGaugeVec::new(Opts::new("nodes", "Known nodes by status"), &["status"])?;
+            let requests = HistogramVec::new(
+                HistogramOpts::new("request_duration_seconds", "Time to answer an API request"),
+                &["request", "outcome"],
+            )?;
+            registry.register(Box::new(deployments.clone()))?;
+            registry.register(Box::new(nodes.clone()))?;
+            registry.register(Box::new(requests.clone()))?;
+            Ok(Self {
+                registry,
+                deployments,
+                nodes,
+                requests,
+            })
+        }
+    }
+
+    fn metrics() -> &'static Metrics {
+        static METRICS: OnceLock<Metrics> = OnceLock::new();
+        METRICS.get_or_init(|| Metrics::new().expect("metric definitions are valid"))
+    }
+
+    pub fn request_handled(request: &str, elapsed: Duration, ok: bool) {
+        let outcome = if ok { "ok" } else { "error" };
+        metrics()
+            .requests
+            .with_label_values(&[request, outcome])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub async fn serve(
+        addr: SocketAddr,
+        deployments: ServerDatabase<Controller>,
+        nodes: ServerDatabase<Controller>,
+    ) -> io::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        info!(%addr, "serving metrics");
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let deployments = deployments.clone();
+            let nodes = nodes.clone();
+            tokio::spawn(async move {
+                if let Err(e) = scrape(stream, &deployments, &nodes).await {
+                    debug!(error = %e, "metrics scrape failed");
+                }
+            });
+        }
+    }
+
+    /// Answer one request, only `GET /metrics` is known.
+    async fn scrape(
+        mut stream: TcpStream,
+        deployments: &ServerDatabase<Controller>,
+        nodes: &ServerDatabase<Controller>,
+    ) -> io::Result<()> {
+        let mut request = [0; 1024];
+        let read = stream.read(&mut request).await?;
+        let response = if request[..read].starts_with(b"GET /metrics ") {
+            count(deployments, nodes).await;
+            let encoder = TextEncoder::new();
+            let mut body = vec![];
+            encoder
+                .encode(&metrics().registry.gather(), &mut body)
+                .map_err(io::Error::other)?;
+            let mut response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                encoder.format_type(),
+                body.len()
+            )
+            .into_bytes();
+            response.append(&mut body);
+            response
+        } else {
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec()
+        };
+        stream.write_all(&response).await?;
+        stream.shutdown().await
+    }
+
+    /// Deployments and nodes are counted on every scrape instead of tracking each change.
+    async fn count(deployments: &ServerDatabase<Controller>, nodes: &ServerDatabase<Controller>) {
+        match Deployment::all_async(deployments).await {
+            Ok(docs) => {
+                metrics().deployments.reset();
+                for doc in docs {
+                    metrics()
+                        .deployments
+                        .with_label_values(&[&format!("{:?}", doc.contents.state)])
+                        .inc();
+                }
+            }
+            Err(e) => warn!(error = %e, "failed to count deployments"),
+        }
+        match Node::all_async(nodes).await {
+            Ok(docs) => {
+                metrics().nodes.reset();
+                for doc in docs {
+                    metrics()
+                        .nodes
+                        .with_label_values(&[&format!("{:?}", doc.contents.status)])
+                        .inc();
+                }
+            }
+            Err(e) => warn!(error = %e, "failed to count nodes"),
+        }
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+mod disabled {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use bonsaidb::server::ServerDatabase;
+    use tracing::warn;
+
+    use crate::controller::Controller;
+
+    pub fn request_handled(_request: &str, _elapsed: Duration, _ok: bool) {}
+
+    pub async fn serve(
+        addr: SocketAddr,
+        _deployments: ServerDatabase<Controller>,
+        _nodes: ServerDatabase<Controller>,
+    ) -> io::Result<()> {
+        warn!(%addr, "built without the metrics feature, not serving metrics");
+        Ok(())
+    }
+}
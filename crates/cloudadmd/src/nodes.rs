@@ -0,0 +1,354 @@
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use bonsaidb::core::connection::AsyncStorageConnection;
+use bonsaidb::core::schema::SerializedCollection;
+use bonsaidb::server::api::{Handler, HandlerError, HandlerResult, HandlerSession};
+use bonsaidb::server::ServerDatabase;
+use chrono::Utc;
+use cloud::api::{ApproveNodeKey, ApproveNodeKeyError, ApproveNodeLabels, ApproveNodeLabelsError};
+use cloud::{Node, NodeEvent, NodeStatus};
+use deadpool_lapin::lapin::options::BasicAckOptions;
+use futures::StreamExt;
+use serde::Deserialize;
+use tracing::{debug, info, warn};
+
+use crate::controller::Controller;
+use crate::{metrics, Error, Result};
+
+pub const NODE_EXCHANGE: &str = "node_events";
+const NODE_QUEUE: &str = "cloudadmd.nodes";
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct NodesConfig {
+    /// Seconds without a heartbeat after which a node counts as unreachable.
+    #[serde(default = "default_unreachable_after")]
+    pub unreachable_after_secs: u64,
+}
+
+fn default_unreachable_after() -> u64 {
+    60
+}
+
+impl Default for NodesConfig {
+    fn default() -> Self {
+        Self {
+            unreachable_after_secs: default_unreachable_after(),
+        }
+    }
+}
+
+impl Controller {
+    /// Keep the `nodes` collection up to date with the registrations and heartbeats of the
+    /// nodelets.
+    pub async fn consume_node_events(&self, db: &ServerDatabase<Controller>) -> Result<()> {
+        let mut consumer = self.subscribe(NODE_EXCHANGE, NODE_QUEUE).await?;
+        info!("following node events");
+        while let Some(delivery) = consumer.next().await {
+            let delivery = delivery?;
+            match serde_json::from_slice::<NodeEvent>(&delivery.data) {
+                Ok(event) => self.record_node_event(db, event).await?,
+                Err(e) => warn!(error = %e, "dropping malformed node event"),
+            }
+            delivery.ack(BasicAckOptions::default()).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn record_node_event(
+        &self,
+        db: &ServerDatabase<Controller>,
+        event: NodeEvent,
+    ) -> Result<()> {
+        let now = Utc::now().naive_utc();
+        match event {
+            NodeEvent::Register(registration) => {
+                let known = Node::get_async(&registration.name, db)
+                    .await?
+                    .map(|doc| doc.contents);
+                let (encryption_key, pending_encryption_key) = hold_for_approval(
+                    known.as_ref().and_then(|node| node.encryption_key.clone()),
+                    registration.encryption_key,
+                );
+                if pending_encryption_key.is_some() {
+                    warn!(
+                        node = registration.name,
+                        key = pending_encryption_key,
+                        "node offers an encryption key which is not approved, secrets are not \
+                         sealed for it until it is"
+                    );
+                }
+                // A node nobody approved labels for yet is not selected by any of them.
+                let (labels, pending_labels) = hold_for_approval(
+                    Some(known.as_ref().map_or(vec![], |node| node.labels.clone())),
+                    Some(registration.labels),
+                );
+                if let Some(pending) = &pending_labels {
+                    warn!(
+                        node = registration.name,
+                        labels = pending.join(", "),
+                        "node offers labels which are not approved, selectors match the \
+                         approved ones until they are"
+                    );
+                }
+                let registered_at = known.map_or(now, |node| node.registered_at);
+                info!(node = registration.name, "node registered");
+                let name = registration.name.clone();
+                Node {
+                    name: registration.name,
+                    labels: labels.unwrap_or_default(),
+                    pending_labels,
+                    version: registration.version,
+                    capabilities: registration.capabilities,
+                    capacity: registration.capacity,
+                    facts: registration.facts,
+                    encryption_key,
+                    pending_encryption_key,
+                    registered_at,
+                    last_seen: now,
+                    status: NodeStatus::Ready,
+                }
+                .overwrite_into_async(&name, db)
+                .await
+                .map_err(|e| e.error)?;
+            }
+            NodeEvent::Heartbeat { name } => {
+                let Some(mut doc) = Node::get_async(&name, db).await? else {
+                    warn!(node = name, "heartbeat of unregistered node");
+                    return Ok(());
+                };
+                if doc.contents.status == NodeStatus::Unreachable {
+                    info!(node = name, "node is reachable again");
+                }
+                doc.contents.last_seen = now;
+                doc.contents.status = NodeStatus::Ready;
+                doc.update_async(db).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Pin the key the node offered last, once an administrator made sure it is the node's.
+    pub async fn approve_node_key(
+        &self,
+        db: &ServerDatabase<Controller>,
+        node: &str,
+        key: &str,
+    ) -> Result<()> {
+        let Some(mut doc) = Node::get_async(&node, db).await? else {
+            return Err(Error::UnknownNode(node.to_owned()));
+        };
+        if doc.contents.pending_encryption_key.as_deref() != Some(key) {
+            return Err(Error::KeyNotOffered {
+                node: node.to_owned(),
+                key: key.to_owned(),
+            });
+        }
+        doc.contents.encryption_key = doc.contents.pending_encryption_key.take();
+        doc.update_async(db).await?;
+        info!(node, key, "approved encryption key");
+        Ok(())
+    }
+
+    /// Let selectors match the labels the node offered last, once an administrator made
+    /// sure they are the node's.
+    pub async fn approve_node_labels(
+        &self,
+        db: &ServerDatabase<Controller>,
+        node: &str,
+        labels: &[String],
+    ) -> Result<()> {
+        let Some(mut doc) = Node::get_async(&node, db).await? else {
+            return Err(Error::UnknownNode(node.to_owned()));
+        };
+        if doc.contents.pending_labels.as_deref() != Some(labels) {
+            return Err(Error::LabelsNotOffered {
+                node: node.to_owned(),
+                labels: labels.to_vec(),
+            });
+        }
+        doc.contents.labels = doc.contents.pending_labels.take().unwrap_or_default();
+        doc.update_async(db).await?;
+        info!(node, labels = labels.join(", "), "approved labels");
+        Ok(())
+    }
+
+    /// Mark the nodes which missed their heartbeats for longer than `after` unreachable.
+    pub async fn mark_unreachable(
+        &self,
+        db: &ServerDatabase<Controller>,
+        after: Duration,
+    ) -> Result<()> {
+        let after = chrono::Duration::seconds(after.as_secs() as i64);
+        let now = Utc::now().naive_utc();
+        for mut doc in Node::all_async(db).await? {
+            if doc.contents.status == NodeStatus::Ready && now - doc.contents.last_seen > after {
+                warn!(
+                    node = doc.contents.name,
+                    last_seen = %doc.contents.last_seen,
+                    "node is unreachable"
+                );
+                doc.contents.status = NodeStatus::Unreachable;
+                doc.update_async(db).await?;
+            }
+        }
+        debug!("checked node heartbeats");
+        Ok(())
+    }
+
+    pub async fn watch_nodes(
+        &self,
+        db: &ServerDatabase<Controller>,
+        config: NodesConfig,
+    ) -> Result<()> {
+        let after = Duration::from_secs(config.unreachable_after_secs);
+        let mut interval = tokio::time::interval((after / 2).max(Duration::from_secs(1)));
+        loop {
+            interval.tick().await;
+            self.mark_unreachable(db, after).await?;
+        }
+    }
+}
+
+/// The approved and the pending value of what a registering node offers, its key or its
+/// labels. Anyone on the broker can register under the name of a node, so nothing it
+/// offers is taken as is, not even the first time. Secrets sealed for a key, or for the
+/// labels a selector matches, could be read by whoever sent it.
+fn hold_for_approval<T: PartialEq>(
+    approved: Option<T>,
+    offered: Option<T>,
+) -> (Option<T>, Option<T>) {
+    match (approved, offered) {
+        (Some(approved), Some(offered)) if approved == offered => (Some(approved), None),
+        (approved, offered) => (approved, offered),
+    }
+}
+
+#[derive(Debug)]
+pub struct ApproveNodeKeyHandler;
+
+#[async_trait]
+impl Handler<ApproveNodeKey, Controller> for ApproveNodeKeyHandler {
+    async fn handle(
+        session: HandlerSession<'_, Controller>,
+        request: ApproveNodeKey,
+    ) -> HandlerResult<ApproveNodeKey> {
+        let controller = session.server.backend();
+        let started = Instant::now();
+        let result = match session.as_client.database::<Node>("nodes").await {
+            Ok(db) => {
+                controller
+                    .approve_node_key(&db, &request.node, &request.key)
+                    .await
+            }
+            Err(e) => Err(Error::from(e)),
+        };
+        metrics::request_handled("approve_node_key", started.elapsed(), result.is_ok());
+        result.map_err(|e| HandlerError::Api(e.into()))
+    }
+}
+
+impl From<Error> for ApproveNodeKeyError {
+    fn from(value: Error) -> Self {
+        match value {
+            Error::UnknownNode(node) => ApproveNodeKeyError::UnknownNode(node),
+            Error::KeyNotOffered { node, key } => ApproveNodeKeyError::NotOffered { node, key },
+            e => ApproveNodeKeyError::Internal(e.to_string()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ApproveNodeLabelsHandler;
+
+#[async_trait]
+impl Handler<ApproveNodeLabels, Controller> for ApproveNodeLabelsHandler {
+    async fn handle(
+        session: HandlerSession<'_, Controller>,
+        request: ApproveNodeLabels,
+    ) -> HandlerResult<ApproveNodeLabels> {
+        let controller = session.server.backend();
+        let started = Instant::now();
+        let result = match session.as_client.database::<Node>("nodes").await {
+            Ok(db) => {
+                controller
+                    .approve_node_labels(&db, &request.node, &request.labels)
+                    .await
+            }
+            Err(e) => Err(Error::from(e)),
+        };
+        metrics::request_handled("approve_node_labels", started.elapsed(), result.is_ok());
+        result.map_err(|e| HandlerError::Api(e.into()))
+    }
+}
+
+impl From<Error> for ApproveNodeLabelsError {
+    fn from(value: Error) -> Self {
+        match value {
+            Error::UnknownNode(node) => ApproveNodeLabelsError::UnknownNode(node),
+            Error::LabelsNotOffered { node, labels } => {
+                ApproveNodeLabelsError::NotOffered { node, labels }
+            }
+            e => ApproveNodeLabelsError::Internal(e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(key: &str) -> Option<String> {
+        Some(key.to_owned())
+    }
+
+    fn labels(labels: &[&str]) -> Option<Vec<String>> {
+        Some(labels.iter().map(|label| (*label).to_owned()).collect())
+    }
+
+    #[test]
+    fn the_first_key_waits_for_approval() {
+        assert_eq!(hold_for_approval(None, key("first")), (None, key("first")));
+    }
+
+    #[test]
+    fn another_key_waits_for_approval() {
+        assert_eq!(
+            hold_for_approval(key("first"), key("second")),
+            (key("first"), key("second"))
+        );
+        assert_eq!(hold_for_approval(key("first"), None), (key("first"), None));
+    }
+
+    #[test]
+    fn coming_back_with_the_approved_key_drops_the_pending_one() {
+        assert_eq!(
+            hold_for_approval(key("first"), key("first")),
+            (key("first"), None)
+        );
+    }
+
+    #[test]
+    fn new_nodes_are_not_selected_by_their_labels_before_approval() {
+        assert_eq!(
+            hold_for_approval(labels(&[]), labels(&["zone=a"])),
+            (labels(&[]), labels(&["zone=a"]))
+        );
+        assert_eq!(
+            hold_for_approval(labels(&[]), labels(&[])),
+            (labels(&[]), None)
+        );
+    }
+
+    #[test]
+    fn relabelling_a_node_waits_for_approval() {
+        assert_eq!(
+            hold_for_approval(labels(&["zone=a"]), labels(&["zone=a", "gpu"])),
+            (labels(&["zone=a"]), labels(&["zone=a", "gpu"]))
+        );
+        assert_eq!(
+            hold_for_approval(labels(&["zone=a"]), labels(&["zone=a"])),
+            (labels(&["zone=a"]), None)
+        );
+    }
+}
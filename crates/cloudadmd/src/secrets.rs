@@ -0,0 +1,211 @@
+//! Secrets are stored encrypted with XChaCha20-Poly1305 under a key of cloudadmd. The
+//! identifier of a secret is authenticated along with its value, so a stored value cannot
+//! be passed off as another secret.
+//!
+//! They only leave cloudadmd sealed for the nodes a deployment using them targets, see
+//! [`nodelet::secrets`].
+
+use std::path::Path;
+use std::time::Instant;
+
+use arc_bytes::serde::Bytes;
+use async_trait::async_trait;
+use bonsaidb::core::connection::AsyncStorageConnection;
+use bonsaidb::core::schema::SerializedCollection;
+use bonsaidb::server::api::{Handler, HandlerError, HandlerResult, HandlerSession};
+use bonsaidb::server::ServerDatabase;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use chrono::Utc;
+use cloud::api::{StoreSecret, StoreSecretError};
+use cloud::{Deployment, Node, NodeStatus, ResourceIdentifier, Secret};
+use nodelet::keyfile;
+use nodelet::secrets::{seal, SealedSecret, SecretValue};
+use nodelet::NodeObject;
+use tracing::{info, instrument, warn};
+
+use crate::controller::Controller;
+use crate::metrics;
+use crate::{Error, Result};
+
+const NONCE_LENGTH: usize = 24;
+
+/// Encrypts and decrypts the stored secrets.
+pub struct Vault {
+    cipher: XChaCha20Poly1305,
+}
+
+impl Vault {
+    /// Load the key stored at `path`, a new one is generated if there is none yet.
+    pub fn load_or_generate(path: &Path) -> Result<Self> {
+        let key = keyfile::load_or_generate(path)?;
+        Ok(Self {
+            cipher: XChaCha20Poly1305::new(&Key::from(key)),
+        })
+    }
+
+    pub fn encrypt(&self, identifier: &ResourceIdentifier, value: &SecretValue) -> Result<Secret> {
+        let aad = identifier.to_string();
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: value.expose(),
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map_err(|_| Error::Vault(identifier.clone()))?;
+        Ok(Secret {
+            resource_identifier: identifier.clone(),
+            created_at: Utc::now().naive_utc(),
+            updated_at: None,
+            nonce: Bytes::from(nonce.to_vec()),
+            ciphertext: Bytes::from(ciphertext),
+        })
+    }
+
+    pub fn decrypt(&self, secret: &Secret) -> Result<SecretValue> {
+        let corrupt = || Error::Vault(secret.resource_identifier.clone());
+        if secret.nonce.len() != NONCE_LENGTH {
+            return Err(corrupt());
+        }
+        let aad = secret.resource_identifier.to_string();
+        self.cipher
+            .decrypt(
+                XNonce::from_slice(&secret.nonce),
+                Payload {
+                    msg: &secret.ciphertext,
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map(SecretValue::new)
+            .map_err(|_| corrupt())
+    }
+}
+
+impl Controller {
+    /// Encrypt a secret and store it, replacing an older value.
+    #[instrument(skip_all, fields(%identifier))]
+    pub async fn store_secret(
+        &self,
+        db: &ServerDatabase<Controller>,
+        identifier: ResourceIdentifier,
+        value: SecretValue,
+    ) -> Result<()> {
+        if value.expose().is_empty() {
+            return Err(Error::EmptySecret(identifier));
+        }
+        let mut secret = self.vault().encrypt(&identifier, &value)?;
+        if let Some(stored) = Secret::get_async(&identifier, db).await? {
+            secret.created_at = stored.contents.created_at;
+            secret.updated_at = Some(Utc::now().naive_utc());
+        }
+        secret
+            .overwrite_into_async(&identifier, db)
+            .await
+            .map_err(|e| e.error)?;
+        info!("secret stored");
+        Ok(())
+    }
+
+    /// Seal the secrets used by the zones of a deployment for each of the `targets` it
+    /// selects. Returns the node, secret and sealed value of each.
+    pub(crate) async fn seal_secrets(
+        &self,
+        secrets: &ServerDatabase<Controller>,
+        deployment: &Deployment,
+        resources: &[(ResourceIdentifier, NodeObject)],
+        targets: &[Node],
+    ) -> Result<Vec<(String, ResourceIdentifier, SealedSecret)>> {
+        let identifier = &deployment.resource_identifier;
+        let mut used: Vec<&ResourceIdentifier> = resources
+            .iter()
+            .filter_map(|(_, object)| match object {
+                NodeObject::Zone(zone) => Some(zone),
+                _ => None,
+            })
+            .flat_map(|zone| zone.secrets.iter().map(|mount| &mount.secret))
+            .collect();
+        used.sort();
+        used.dedup();
+        if used.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut values = vec![];
+        for secret in used {
+            if secret.tenant != identifier.tenant {
+                return Err(Error::ForeignSecret {
+                    identifier: identifier.clone(),
+                    secret: Box::new(secret.clone()),
+                });
+            }
+            let stored = Secret::get_async(secret, secrets)
+                .await?
+                .ok_or_else(|| Error::MissingSecret(secret.clone()))?;
+            values.push((secret, self.vault().decrypt(&stored.contents)?));
+        }
+
+        let mut sealed = vec![];
+        for node in targets {
+            // Sealed secrets wait in the queue of an unreachable node for whoever comes
+            // back under its name. It gets them when the deployment is applied again.
+            if node.status != NodeStatus::Ready {
+                warn!(
+                    node = node.name,
+                    "not sealing secrets for an unreachable node"
+                );
+                continue;
+            }
+            let key = node
+                .encryption_key
+                .as_deref()
+                .ok_or_else(|| Error::NoNodeKey(node.name.clone()))?;
+            if node.pending_encryption_key.is_some() {
+                warn!(
+                    node = node.name,
+                    "sealing for the approved key, the one the node offers is not approved yet"
+                );
+            }
+            for (secret, value) in &values {
+                sealed.push((node.name.clone(), (*secret).clone(), seal(key, value)?));
+            }
+        }
+        Ok(sealed)
+    }
+}
+
+#[derive(Debug)]
+pub struct StoreSecretHandler;
+
+#[async_trait]
+impl Handler<StoreSecret, Controller> for StoreSecretHandler {
+    async fn handle(
+        session: HandlerSession<'_, Controller>,
+        request: StoreSecret,
+    ) -> HandlerResult<StoreSecret> {
+        let controller = session.server.backend();
+        let started = Instant::now();
+        let result = match session.as_client.database::<Secret>("secrets").await {
+            Ok(db) => {
+                controller
+                    .store_secret(&db, request.identifier, SecretValue::new(request.value.0))
+                    .await
+            }
+            Err(e) => Err(Error::from(e)),
+        };
+        metrics::request_handled("store_secret", started.elapsed(), result.is_ok());
+        result.map_err(|e| HandlerError::Api(e.into()))
+    }
+}
+
+impl From<Error> for StoreSecretError {
+    fn from(value: Error) -> Self {
+        match value {
+            e @ Error::EmptySecret(_) => StoreSecretError::Invalid(e.to_string()),
+            e => StoreSecretError::Internal(e.to_string()),
+        }
+    }
+}
@@ -0,0 +1,482 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use cloud::ResourceIdentifier;
+use ipnet::IpNet;
+use miette::Diagnostic;
+use nodelet::{Network, NetworkReference, NodeObject, VSwitch, VSwitchKind, Zone};
+use thiserror::Error;
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("the deployment has {} topology conflicts", .conflicts.len())]
+#[diagnostic(help("change or remove the conflicting resources and apply again"))]
+pub struct TopologyError {
+    #[related]
+    pub conflicts: Vec<Conflict>,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum Conflict {
+    #[error("range {range} of {network}#{switch} overlaps {other_range} of {other_network}#{other_switch}")]
+    OverlappingRanges {
+        network: ResourceIdentifier,
+        switch: String,
+        range: IpNet,
+        other_network: ResourceIdentifier,
+        other_switch: String,
+        other_range: IpNet,
+    },
+
+    #[error("address {address} of zone {zone} is outside the ranges of {network}")]
+    AddressOutsideRange {
+        zone: ResourceIdentifier,
+        address: IpNet,
+        network: NetworkReference,
+    },
+
+    #[error("address {address} of zone {zone} is already used by zone {other_zone} on {network}")]
+    DuplicateAddress {
+        zone: ResourceIdentifier,
+        address: IpAddr,
+        other_zone: ResourceIdentifier,
+        network: NetworkReference,
+    },
+
+    #[error("zone {zone} references network {network} which does not exist")]
+    UnknownNetwork {
+        zone: ResourceIdentifier,
+        network: ResourceIdentifier,
+    },
+
+    #[error("zone {zone} references switch {network} which does not exist")]
+    UnknownSwitch {
+        zone: ResourceIdentifier,
+        network: NetworkReference,
+    },
+
+    #[error("external switch of {network} names public network {public} which does not exist")]
+    UnknownPublicNetwork {
+        network: ResourceIdentifier,
+        public: String,
+    },
+
+    #[error("network {network} names tenant {tenant}, which it was not applied under")]
+    ForeignTenant {
+        network: ResourceIdentifier,
+        tenant: String,
+    },
+}
+
+/// Every network and zone the controller knows about, checked as one graph. Networks are
+/// told apart by the identifier they were applied under, whatever they name themselves.
+pub struct Topology<'a> {
+    networks: Vec<(&'a ResourceIdentifier, &'a Network)>,
+    zones: Vec<(&'a ResourceIdentifier, &'a Zone)>,
+}
+
+impl<'a> Topology<'a> {
+    pub fn new(resources: &'a [(ResourceIdentifier, NodeObject)]) -> Self {
+        let mut networks = vec![];
+        let mut zones = vec![];
+        for (identifier, object) in resources {
+            match object {
+                NodeObject::Network(network) => networks.push((identifier, network)),
+                NodeObject::Zone(zone) => zones.push((identifier, &**zone)),
+                NodeObject::Secret(_) => {}
+            }
+        }
+        Self { networks, zones }
+    }
+
+    pub fn validate(&self) -> Result<(), TopologyError> {
+        let mut conflicts = vec![];
+        self.check_tenants(&mut conflicts);
+        self.check_public_networks(&mut conflicts);
+        self.check_overlapping_ranges(&mut conflicts);
+        self.check_zone_addresses(&mut conflicts);
+
+        if conflicts.is_empty() {
+            Ok(())
+        } else {
+            Err(TopologyError { conflicts })
+        }
+    }
+
+    /// The tenant is the one of the identifier, a network must not claim another one.
+    fn check_tenants(&self, conflicts: &mut Vec<Conflict>) {
+        for (identifier, network) in &self.networks {
+            if let Some(tenant) = &network.tenant {
+                if identifier.tenant.as_ref() != Some(tenant) {
+                    conflicts.push(Conflict::ForeignTenant {
+                        network: (*identifier).clone(),
+                        tenant: tenant.clone(),
+                    });
+                }
+            }
+        }
+    }
+
+    fn check_public_networks(&self, conflicts: &mut Vec<Conflict>) {
+        for (identifier, network) in &self.networks {
+            for switch in &network.switches {
+                if matches!(switch.kind, VSwitchKind::External)
+                    && self.public_network(&switch.name).is_none()
+                {
+                    conflicts.push(Conflict::UnknownPublicNetwork {
+                        network: (*identifier).clone(),
+                        public: switch.name.clone(),
+                    });
+                }
+            }
+        }
+    }
+
+    fn check_overlapping_ranges(&self, conflicts: &mut Vec<Conflict>) {
+        let ranges: Vec<_> = self
+            .networks
+            .iter()
+            .flat_map(|(identifier, network)| {
+                network.switches.iter().flat_map(move |switch| {
+                    switch
+                        .allowed_ranges
+                        .iter()
+                        .chain(switch.public_ips.iter().map(|public| &public.range))
+                        .map(move |range| (*identifier, switch, range))
+                })
+            })
+            .collect();
+
+        for (index, (identifier, switch, range)) in ranges.iter().enumerate() {
+            for (other_identifier, other_switch, other_range) in &ranges[index + 1..] {
+                // Versions of the same network may reuse their ranges.
+                if other_version(identifier, other_identifier) {
+                    continue;
+                }
+                if range.contains(*other_range) || other_range.contains(*range) {
+                    conflicts.push(Conflict::OverlappingRanges {
+                        network: (*identifier).clone(),
+                        switch: switch.name.clone(),
+                        range: **range,
+                        other_network: (*other_identifier).clone(),
+                        other_switch: other_switch.name.clone(),
+                        other_range: **other_range,
+                    });
+                }
+            }
+        }
+    }
+
+    fn check_zone_addresses(&self, conflicts: &mut Vec<Conflict>) {
+        let mut used: HashMap<(String, IpAddr), &ResourceIdentifier> = HashMap::new();
+
+        for (identifier, zone) in &self.zones {
+            for interface in &zone.network {
+                let Some(reference) = &interface.network else {
+                    continue;
+                };
+                let Some(network) = self.network(&reference.network) else {
+                    conflicts.push(Conflict::UnknownNetwork {
+                        zone: (*identifier).clone(),
+                        network: reference.network.clone(),
+                    });
+                    continue;
+                };
+                let Some(switch) = network
+                    .switches
+                    .iter()
+                    .find(|switch| switch.name == reference.switch)
+                else {
+                    conflicts.push(Conflict::UnknownSwitch {
+                        zone: (*identifier).clone(),
+                        network: reference.clone(),
+                    });
+                    continue;
+                };
+
+                let ranges = self.switch_ranges(switch);
+                for address in &interface.allowed_address {
+                    if !ranges.is_empty()
+                        && !ranges.iter().any(|range| range.contains(&address.addr()))
+                    {
+                        conflicts.push(Conflict::AddressOutsideRange {
+                            zone: (*identifier).clone(),
+                            address: *address,
+                            network: reference.clone(),
+                        });
+                    }
+
+                    let key = (self.segment(&reference.network, switch), address.addr());
+                    match used.get(&key) {
+                        Some(other) if !other_version(identifier, other) => {
+                            conflicts.push(Conflict::DuplicateAddress {
+                                zone: (*identifier).clone(),
+                                address: address.addr(),
+                                other_zone: (*other).clone(),
+                                network: reference.clone(),
+                            })
+                        }
+                        Some(_) => {}
+                        None => {
+                            used.insert(key, *identifier);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn network(&self, identifier: &ResourceIdentifier) -> Option<&'a Network> {
+        self.networks
+            .iter()
+            .find(|(candidate, _)| *candidate == identifier)
+            .map(|(_, network)| *network)
+    }
+
+    /// External switches are named `tenant/name` after the public network they connect to,
+    /// which is the network applied under that tenant and name.
+    fn public_network(&self, name: &str) -> Option<(&'a ResourceIdentifier, &'a Network)> {
+        let (tenant, name) = match name.split_once('/') {
+            Some((tenant, name)) => (Some(tenant), name),
+            None => (None, name),
+        };
+        self.networks
+            .iter()
+            .find(|(identifier, _)| {
+                identifier.tenant.as_deref() == tenant && identifier.name == name
+            })
+            .copied()
+    }
+
+    /// Zones on the same switch share one address space, no matter which version of the
+    /// network they reference. External switches share the space of their public network.
+    fn segment(&self, network: &ResourceIdentifier, switch: &VSwitch) -> String {
+        if matches!(switch.kind, VSwitchKind::External) {
+            if let Some((public, public_switch)) =
+                self.public_network(&switch.name)
+                    .and_then(|(public, network)| {
+                        network
+                            .switches
+                            .iter()
+                            .find(|switch| !matches!(switch.kind, VSwitchKind::External))
+                            .map(|public_switch| (public, public_switch))
+                    })
+            {
+                return segment_name(public, public_switch);
+            }
+        }
+        segment_name(network, switch)
+    }
+
+    fn switch_ranges(&self, switch: &'a VSwitch) -> Vec<IpNet> {
+        let switches: Vec<&VSwitch> = match switch.kind {
+            VSwitchKind::External => self
+                .public_network(&switch.name)
+                .map(|(_, public)| public.switches.iter().collect())
+                .unwrap_or_default(),
+            _ => vec![switch],
+        };
+        switches
+            .into_iter()
+            .flat_map(|switch| {
+                switch
+                    .allowed_ranges
+                    .iter()
+                    .chain(switch.public_ips.iter().map(|public| &public.range))
+                    .copied()
+            })
+            .collect()
+    }
+}
+
+/// Another version of the same resource replaces it, every other resource must not
+/// conflict with it.
+fn other_version(a: &ResourceIdentifier, b: &ResourceIdentifier) -> bool {
+    a != b && a.tenant == b.tenant && a.name == b.name
+}
+
+fn segment_name(network: &ResourceIdentifier, switch: &VSwitch) -> String {
+    format!(
+        "{}/{}#{}",
+        network.tenant.as_deref().unwrap_or_default(),
+        network.name,
+        switch.name
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identifier(text: &str) -> ResourceIdentifier {
+        text.parse().unwrap()
+    }
+
+    fn network(kdl: &str) -> NodeObject {
+        NodeObject::Network(knuffel::parse("network.kdl", kdl).unwrap())
+    }
+
+    fn zone(address: &str) -> NodeObject {
+        let kdl = format!(
+            r#"
+            brand "linked-pkg"
+            autoboot true
+            ip-type "exclusive"
+            net "net0" {{
+                network "res://example.org/lan@1.0.0#internal"
+                allowed-address "{address}"
+            }}
+            "#
+        );
+        NodeObject::Zone(Box::new(knuffel::parse("zone.kdl", &kdl).unwrap()))
+    }
+
+    fn lan() -> (ResourceIdentifier, NodeObject) {
+        (
+            identifier("res://example.org/lan@1.0.0"),
+            network(
+                r#"
+                tenant "example.org"
+                name "lan"
+                (local)vswitch "internal" {
+                    allowed-range "10.0.0.0/24"
+                }
+                "#,
+            ),
+        )
+    }
+
+    fn conflicts(resources: &[(ResourceIdentifier, NodeObject)]) -> Vec<Conflict> {
+        match Topology::new(resources).validate() {
+            Ok(()) => vec![],
+            Err(e) => e.conflicts,
+        }
+    }
+
+    #[test]
+    fn zones_of_one_deployment_need_distinct_addresses() {
+        let deployment = identifier("res://example.org/web@1.0.0");
+        let resources = vec![
+            lan(),
+            (deployment.clone(), zone("10.0.0.10/24")),
+            (deployment, zone("10.0.0.10/24")),
+        ];
+        assert!(matches!(
+            conflicts(&resources).as_slice(),
+            [Conflict::DuplicateAddress { .. }]
+        ));
+    }
+
+    #[test]
+    fn versions_of_a_zone_may_keep_their_address() {
+        let resources = vec![
+            lan(),
+            (
+                identifier("res://example.org/web@1.0.0"),
+                zone("10.0.0.10/24"),
+            ),
+            (
+                identifier("res://example.org/web@1.1.0"),
+                zone("10.0.0.10/24"),
+            ),
+        ];
+        assert!(conflicts(&resources).is_empty());
+    }
+
+    #[test]
+    fn zones_of_different_resources_need_distinct_addresses() {
+        let resources = vec![
+            lan(),
+            (
+                identifier("res://example.org/web@1.0.0"),
+                zone("10.0.0.10/24"),
+            ),
+            (
+                identifier("res://example.org/db@1.0.0"),
+                zone("10.0.0.10/24"),
+            ),
+        ];
+        assert!(matches!(
+            conflicts(&resources).as_slice(),
+            [Conflict::DuplicateAddress { .. }]
+        ));
+    }
+
+    #[test]
+    fn ranges_within_one_network_must_not_overlap() {
+        let resources = vec![(
+            identifier("res://example.org/lan@1.0.0"),
+            network(
+                r#"
+                tenant "example.org"
+                name "lan"
+                (local)vswitch "internal" {
+                    allowed-range "10.0.0.0/24"
+                }
+                (local)vswitch "storage" {
+                    allowed-range "10.0.0.128/25"
+                }
+                "#,
+            ),
+        )];
+        assert!(matches!(
+            conflicts(&resources).as_slice(),
+            [Conflict::OverlappingRanges { .. }]
+        ));
+    }
+
+    #[test]
+    fn versions_of_a_network_may_keep_their_ranges() {
+        let (_, object) = lan();
+        let resources = vec![lan(), (identifier("res://example.org/lan@1.1.0"), object)];
+        assert!(conflicts(&resources).is_empty());
+    }
+
+    #[test]
+    fn networks_cannot_claim_another_tenant() {
+        // Claims to be a version of the lan of example.org to reuse its range.
+        let (_, object) = lan();
+        let resources = vec![lan(), (identifier("res://evil.org/lan@1.1.0"), object)];
+        assert!(matches!(
+            conflicts(&resources).as_slice(),
+            [
+                Conflict::ForeignTenant { tenant, .. },
+                Conflict::OverlappingRanges { .. }
+            ] if tenant == "example.org"
+        ));
+    }
+
+    #[test]
+    fn public_networks_are_found_by_the_identifier_they_were_applied_under() {
+        let public = network(
+            r#"
+            tenant "provider"
+            name "internet"
+            (local)vswitch "uplink" {
+                allowed-range "192.0.2.0/24"
+            }
+            "#,
+        );
+        let attached = network(
+            r#"
+            (external)vswitch "provider/internet"
+            "#,
+        );
+        let resources = vec![
+            (identifier("res://evil.org/internet@1.0.0"), public.clone()),
+            (identifier("res://example.org/wan@1.0.0"), attached.clone()),
+        ];
+        assert!(matches!(
+            conflicts(&resources).as_slice(),
+            [
+                Conflict::ForeignTenant { .. },
+                Conflict::UnknownPublicNetwork { .. }
+            ]
+        ));
+
+        let resources = vec![
+            (identifier("res://provider/internet@1.0.0"), public),
+            (identifier("res://example.org/wan@1.0.0"), attached),
+        ];
+        assert!(conflicts(&resources).is_empty());
+    }
+}
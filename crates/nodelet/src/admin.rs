@@ -0,0 +1,185 @@
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bonsaidb::core::schema::SerializedCollection;
+use bonsaidb::local::Database;
+use cloud::{ResourceIdentifier, ResourceKind};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{watch, Notify};
+use tracing::{debug, info, warn};
+
+use crate::{DeploymentStatus, NodeEntry};
+
+#[derive(Debug, Error)]
+pub enum AdminError {
+    #[error(transparent)]
+    BonsaidbCore(#[from] bonsaidb::core::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    IO(#[from] std::io::Error),
+
+    #[error("the nodelet closed the admin connection")]
+    Closed,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct AdminConfig {
+    /// Only the owner of the nodelet may connect to it.
+    pub socket: PathBuf,
+}
+
+/// One line of JSON on the admin socket, answered by one [`AdminResponse`] line.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "request")]
+pub enum AdminRequest {
+    List,
+    Show { identifier: ResourceIdentifier },
+    Reconcile,
+    Pause,
+    Resume,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "response")]
+pub enum AdminResponse {
+    Entries { entries: Vec<EntrySummary> },
+    Entry { entry: Box<NodeEntry> },
+    NotFound,
+    Done,
+    Error { message: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EntrySummary {
+    pub identifier: ResourceIdentifier,
+    pub kind: ResourceKind,
+    pub state: DeploymentStatus,
+    /// The step an operation in flight reached.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub step: Option<String>,
+}
+
+/// Lets an operator look into the node without opening the database files.
+pub struct AdminServer {
+    nodedb: Database,
+    reconcile: Arc<Notify>,
+    paused: watch::Sender<bool>,
+}
+
+impl AdminServer {
+    pub fn new(nodedb: Database, reconcile: Arc<Notify>, paused: watch::Sender<bool>) -> Self {
+        Self {
+            nodedb,
+            reconcile,
+            paused,
+        }
+    }
+
+    pub async fn serve(self, socket: &Path) -> Result<(), AdminError> {
+        // Left behind by a nodelet that did not stop cleanly.
+        match tokio::fs::remove_file(socket).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let listener = UnixListener::bind(socket)?;
+        tokio::fs::set_permissions(socket, std::fs::Permissions::from_mode(0o600)).await?;
+        info!(socket = %socket.display(), "admin api listening");
+
+        let server = Arc::new(self);
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let server = server.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.connection(stream).await {
+                    warn!(error = %e, "admin connection failed");
+                }
+            });
+        }
+    }
+
+    async fn connection(&self, stream: UnixStream) -> Result<(), AdminError> {
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        while let Some(line) = lines.next_line().await? {
+            let response = match serde_json::from_str(&line) {
+                Ok(request) => self
+                    .respond(request)
+                    .unwrap_or_else(|e| AdminResponse::Error {
+                        message: e.to_string(),
+                    }),
+                Err(e) => AdminResponse::Error {
+                    message: format!("invalid request: {e}"),
+                },
+            };
+            let mut payload = serde_json::to_vec(&response)?;
+            payload.push(b'\n');
+            write.write_all(&payload).await?;
+        }
+        Ok(())
+    }
+
+    fn respond(&self, request: AdminRequest) -> Result<AdminResponse, AdminError> {
+        debug!(?request, "admin request");
+        let response = match request {
+            AdminRequest::List => {
+                let entries = NodeEntry::all(&self.nodedb)
+                    .query()?
+                    .into_iter()
+                    .map(|doc| {
+                        let entry = doc.contents;
+                        EntrySummary {
+                            kind: entry.object.kind(),
+                            identifier: entry.resource_identifier,
+                            state: entry.state,
+                            step: entry.checkpoint.map(|checkpoint| checkpoint.step),
+                        }
+                    })
+                    .collect();
+                AdminResponse::Entries { entries }
+            }
+            AdminRequest::Show { identifier } => match NodeEntry::get(&identifier, &self.nodedb)? {
+                Some(doc) => AdminResponse::Entry {
+                    entry: Box::new(doc.contents),
+                },
+                None => AdminResponse::NotFound,
+            },
+            AdminRequest::Reconcile => {
+                self.reconcile.notify_one();
+                AdminResponse::Done
+            }
+            AdminRequest::Pause => {
+                info!("pausing consumer");
+                self.paused.send_replace(true);
+                AdminResponse::Done
+            }
+            AdminRequest::Resume => {
+                info!("resuming consumer");
+                self.paused.send_replace(false);
+                AdminResponse::Done
+            }
+        };
+        Ok(response)
+    }
+}
+
+/// Send one request to the nodelet listening on `socket`.
+pub async fn request(socket: &Path, request: &AdminRequest) -> Result<AdminResponse, AdminError> {
+    let stream = UnixStream::connect(socket).await?;
+    let (read, mut write) = stream.into_split();
+    let mut payload = serde_json::to_vec(request)?;
+    payload.push(b'\n');
+    write.write_all(&payload).await?;
+    let line = BufReader::new(read)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or(AdminError::Closed)?;
+    Ok(serde_json::from_str(&line)?)
+}
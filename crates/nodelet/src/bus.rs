@@ -0,0 +1,102 @@
+use std::pin::Pin;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use cloud::{DeploymentReport, NodeEvent};
+use futures::Stream;
+use thiserror::Error;
+
+use crate::signing::Signature;
+
+pub mod amqp;
+pub mod channel;
+
+pub use amqp::AmqpBus;
+pub use channel::ChannelBus;
+
+#[derive(Debug, Error)]
+pub enum BusError {
+    #[error(transparent)]
+    Pool(#[from] deadpool_lapin::PoolError),
+
+    #[error(transparent)]
+    CreatePool(#[from] deadpool_lapin::CreatePoolError),
+
+    #[error(transparent)]
+    Lapin(#[from] deadpool_lapin::lapin::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error("the message bus is closed")]
+    Closed,
+
+    #[error("message {0} is unknown or was settled already")]
+    UnknownMessage(u64),
+}
+
+/// Topic of the zones cloudadmd publishes.
+pub const ZONE_TOPIC: &str = "zones";
+/// Topic of the networks cloudadmd publishes.
+pub const NETWORK_TOPIC: &str = "networks";
+/// Topic of the secrets cloudadmd seals for a node.
+pub const SECRET_TOPIC: &str = "secrets";
+
+/// The routing key of the events of `topic` for the node `node`. Nothing is broadcast,
+/// a node only receives the resources of the deployments selecting it.
+pub fn routing_key(topic: &str, node: &str) -> String {
+    format!("{topic}.{node}")
+}
+
+/// The topic of a routing key, `zones` for `zones.<node>`.
+pub fn topic(routing_key: &str) -> &str {
+    routing_key
+        .split_once('.')
+        .map_or(routing_key, |(topic, _)| topic)
+}
+
+/// A deployment event addressed to this node, as handed out by a [`MessageBus`].
+#[derive(Debug, Clone)]
+pub struct Message {
+    /// Identifies the message towards the bus it came from.
+    pub tag: u64,
+    pub routing_key: String,
+    pub payload: Vec<u8>,
+    /// How often handling the message failed already.
+    pub retries: u32,
+    /// Handed out before without being settled, like after a restart of the nodelet.
+    /// Only the broker sets it, unlike the headers.
+    pub redelivered: bool,
+    /// Who published the message, checked before it is handled.
+    pub signature: Option<Signature>,
+}
+
+pub type MessageStream = Pin<Box<dyn Stream<Item = Result<Message, BusError>> + Send>>;
+
+/// How the nodelet talks to cloudadmd. Every message is either acknowledged or rejected
+/// once it was handled; a message left alone is handed out again after a restart.
+#[async_trait]
+pub trait MessageBus: Send + Sync {
+    /// Start taking the messages for this node. Only called once.
+    async fn consume(&self) -> Result<MessageStream, BusError>;
+
+    /// Stop handing out messages. The ones not settled yet are handed out again later.
+    async fn cancel(&self) -> Result<(), BusError>;
+
+    /// Disconnect, messages not settled by now are handed out again after a restart.
+    async fn close(&self) -> Result<(), BusError>;
+
+    async fn ack(&self, message: &Message) -> Result<(), BusError>;
+
+    /// Hand a message which failed with `error` out again after `delay`, with its retry
+    /// counted in the headers. The count holds across deliveries and restarts.
+    async fn retry(&self, message: &Message, error: &str, delay: Duration) -> Result<(), BusError>;
+
+    /// Give up on a message which failed with `error`. It is kept aside for an operator
+    /// instead of being handed out again.
+    async fn nack(&self, message: &Message, error: &str) -> Result<(), BusError>;
+
+    async fn publish_report(&self, report: &DeploymentReport) -> Result<(), BusError>;
+
+    async fn publish_node_event(&self, event: &NodeEvent) -> Result<(), BusError>;
+}
@@ -0,0 +1,262 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use cloud::{DeploymentReport, NodeEvent};
+use deadpool_lapin::lapin::options::{
+    BasicAckOptions, BasicCancelOptions, BasicConsumeOptions, BasicPublishOptions, BasicQosOptions,
+    ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions,
+};
+use deadpool_lapin::lapin::types::{AMQPValue, FieldTable, LongString, ShortString};
+use deadpool_lapin::lapin::{BasicProperties, Channel, ExchangeKind};
+use deadpool_lapin::Pool;
+use deadpool_lapin::Runtime::Tokio1;
+use futures::StreamExt;
+use tracing::{debug, info};
+
+use super::{
+    routing_key, BusError, Message, MessageBus, MessageStream, NETWORK_TOPIC, SECRET_TOPIC,
+    ZONE_TOPIC,
+};
+use crate::retry::{RetryState, DEAD_LETTER_EXCHANGE};
+use crate::signing::Signature;
+
+const DEPLOYMENT_EXCHANGE: &str = "deployment.nodelet";
+const REPORT_EXCHANGE: &str = "deployment_reports";
+const NODE_EXCHANGE: &str = "node_events";
+const CONSUMER_TAG: &str = "nodelet.consumer";
+
+/// The node's queue on RabbitMQ, bound to the zones, networks and secrets cloudadmd
+/// publishes for the node.
+///
+/// Retries wait in a queue of their own until they expire and go back to the node's
+/// queue. Messages expire in order, a retry also waits for the ones queued before it.
+pub struct AmqpBus {
+    // Keeps the connection of the channel open.
+    _pool: Pool,
+    channel: Channel,
+    queue_name: String,
+    retry_queue: String,
+}
+
+impl AmqpBus {
+    /// Declare the exchanges and queues the nodelet uses. `prefetch` bounds the messages
+    /// handed out before the first of them is settled.
+    pub async fn connect(
+        config: &deadpool_lapin::Config,
+        node: &str,
+        prefetch: u16,
+    ) -> Result<Self, BusError> {
+        let queue_name = format!("nodelet.{node}");
+        let pool = config.create_pool(Some(Tokio1))?;
+        let conn = pool.get().await?;
+        debug!(
+            "Connected to {} as {}",
+            conn.status().vhost(),
+            conn.status().username()
+        );
+        let channel = conn.create_channel().await?;
+
+        for (exchange, kind) in [
+            (REPORT_EXCHANGE, ExchangeKind::Fanout),
+            (NODE_EXCHANGE, ExchangeKind::Fanout),
+            (DEPLOYMENT_EXCHANGE, ExchangeKind::Topic),
+            (DEAD_LETTER_EXCHANGE, ExchangeKind::Fanout),
+        ] {
+            channel
+                .exchange_declare(
+                    exchange,
+                    kind,
+                    ExchangeDeclareOptions {
+                        durable: true,
+                        ..Default::default()
+                    },
+                    FieldTable::default(),
+                )
+                .await?;
+        }
+
+        debug!("Defining queue to listen to the exchanges");
+        let dead_queue = format!("{queue_name}.dead");
+        let retry_queue = format!("{queue_name}.retry");
+        let mut expired = FieldTable::default();
+        expired.insert(
+            ShortString::from("x-dead-letter-exchange"),
+            AMQPValue::LongString(LongString::from("")),
+        );
+        expired.insert(
+            ShortString::from("x-dead-letter-routing-key"),
+            AMQPValue::LongString(LongString::from(queue_name.as_str())),
+        );
+        for (queue, arguments) in [
+            (&queue_name, FieldTable::default()),
+            (&dead_queue, FieldTable::default()),
+            (&retry_queue, expired),
+        ] {
+            channel
+                .queue_declare(
+                    queue,
+                    QueueDeclareOptions {
+                        durable: true,
+                        ..Default::default()
+                    },
+                    arguments,
+                )
+                .await?;
+        }
+        channel
+            .queue_bind(
+                &dead_queue,
+                DEAD_LETTER_EXCHANGE,
+                "",
+                QueueBindOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+        for topic in [ZONE_TOPIC, NETWORK_TOPIC, SECRET_TOPIC] {
+            let topic = routing_key(topic, node);
+            debug!("Binding topic {} from channel id {}", topic, channel.id());
+            channel
+                .queue_bind(
+                    &queue_name,
+                    DEPLOYMENT_EXCHANGE,
+                    &topic,
+                    QueueBindOptions::default(),
+                    FieldTable::default(),
+                )
+                .await?;
+        }
+        channel
+            .basic_qos(prefetch, BasicQosOptions::default())
+            .await?;
+
+        Ok(Self {
+            _pool: pool,
+            channel,
+            queue_name,
+            retry_queue,
+        })
+    }
+
+    async fn publish(&self, exchange: &str, payload: &[u8]) -> Result<(), BusError> {
+        self.channel
+            .basic_publish(
+                exchange,
+                "",
+                BasicPublishOptions::default(),
+                payload,
+                BasicProperties::default(),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MessageBus for AmqpBus {
+    async fn consume(&self) -> Result<MessageStream, BusError> {
+        info!("connecting amqp consumer...");
+        let consumer = self
+            .channel
+            .basic_consume(
+                &self.queue_name,
+                CONSUMER_TAG,
+                BasicConsumeOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+        let messages = consumer.map(|delivery| -> Result<Message, BusError> {
+            let delivery = delivery?;
+            // Dead-lettered messages replayed onto the queue keep their routing key here.
+            let headers = delivery.properties.headers().as_ref();
+            let retry = RetryState::from_headers(headers);
+            let signature = Signature::from_headers(headers);
+            Ok(Message {
+                tag: delivery.delivery_tag,
+                routing_key: retry
+                    .routing_key
+                    .unwrap_or_else(|| delivery.routing_key.as_str().to_owned()),
+                payload: delivery.data,
+                retries: retry.retries,
+                redelivered: delivery.redelivered,
+                signature,
+            })
+        });
+        Ok(Box::pin(messages))
+    }
+
+    async fn cancel(&self) -> Result<(), BusError> {
+        self.channel
+            .basic_cancel(CONSUMER_TAG, BasicCancelOptions::default())
+            .await?;
+        Ok(())
+    }
+
+    async fn close(&self) -> Result<(), BusError> {
+        self.channel.close(200, "nodelet shutting down").await?;
+        Ok(())
+    }
+
+    async fn ack(&self, message: &Message) -> Result<(), BusError> {
+        self.channel
+            .basic_ack(message.tag, BasicAckOptions::default())
+            .await?;
+        Ok(())
+    }
+
+    async fn retry(&self, message: &Message, error: &str, delay: Duration) -> Result<(), BusError> {
+        let retry = RetryState {
+            retries: message.retries,
+            routing_key: Some(message.routing_key.clone()),
+        };
+        let mut headers = retry.next_headers(&message.routing_key, error);
+        if let Some(signature) = &message.signature {
+            signature.insert_into(&mut headers);
+        }
+        self.channel
+            .basic_publish(
+                "",
+                &self.retry_queue,
+                BasicPublishOptions::default(),
+                &message.payload,
+                BasicProperties::default()
+                    .with_headers(headers)
+                    .with_expiration(ShortString::from(delay.as_millis().to_string())),
+            )
+            .await?;
+        // Only once the retry is out, otherwise it is handed out again.
+        self.ack(message).await
+    }
+
+    async fn nack(&self, message: &Message, error: &str) -> Result<(), BusError> {
+        let retry = RetryState {
+            retries: message.retries,
+            routing_key: Some(message.routing_key.clone()),
+        };
+        let mut headers = retry.next_headers(&message.routing_key, error);
+        // Replayed dead letters are verified again.
+        if let Some(signature) = &message.signature {
+            signature.insert_into(&mut headers);
+        }
+        self.channel
+            .basic_publish(
+                DEAD_LETTER_EXCHANGE,
+                &message.routing_key,
+                BasicPublishOptions::default(),
+                &message.payload,
+                BasicProperties::default().with_headers(headers),
+            )
+            .await?;
+        // Only once the dead letter is out, otherwise it is handed out again.
+        self.ack(message).await
+    }
+
+    async fn publish_report(&self, report: &DeploymentReport) -> Result<(), BusError> {
+        self.publish(REPORT_EXCHANGE, &serde_json::to_vec(report)?)
+            .await
+    }
+
+    async fn publish_node_event(&self, event: &NodeEvent) -> Result<(), BusError> {
+        self.publish(NODE_EXCHANGE, &serde_json::to_vec(event)?)
+            .await
+    }
+}
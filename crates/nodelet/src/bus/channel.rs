@@ -0,0 +1,211 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use cloud::{DeploymentReport, NodeEvent};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use super::{BusError, Message, MessageBus, MessageStream};
+use crate::retry::RetryState;
+use crate::signing::Signature;
+
+/// Passes messages within the process, so the nodelet can be driven without a broker.
+/// Everything the nodelet sends back is kept for inspection.
+#[derive(Debug)]
+pub struct ChannelBus {
+    sender: UnboundedSender<Message>,
+    receiver: Mutex<Option<UnboundedReceiver<Message>>>,
+    state: Mutex<ChannelState>,
+}
+
+#[derive(Debug, Default)]
+struct ChannelState {
+    next_tag: u64,
+    /// Sent but neither acknowledged nor rejected yet.
+    unsettled: BTreeMap<u64, Message>,
+    acked: Vec<Message>,
+    dead_letters: Vec<(Message, String)>,
+    reports: Vec<DeploymentReport>,
+    node_events: Vec<NodeEvent>,
+}
+
+impl Default for ChannelBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChannelBus {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Self {
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+            state: Mutex::new(ChannelState::default()),
+        }
+    }
+
+    /// Queue a message for the nodelet, like cloudadmd publishing an event. Returns the
+    /// tag it is known by.
+    pub fn send(&self, routing_key: &str, payload: Vec<u8>) -> u64 {
+        self.push(routing_key, payload, None)
+    }
+
+    /// Like [`ChannelBus::send`], for nodelets that verify signatures.
+    pub fn send_signed(&self, routing_key: &str, payload: Vec<u8>, signature: Signature) -> u64 {
+        self.push(routing_key, payload, Some(signature))
+    }
+
+    fn push(&self, routing_key: &str, payload: Vec<u8>, signature: Option<Signature>) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.next_tag += 1;
+        let message = Message {
+            tag: state.next_tag,
+            routing_key: routing_key.to_owned(),
+            payload,
+            retries: 0,
+            redelivered: false,
+            signature,
+        };
+        state.unsettled.insert(message.tag, message.clone());
+        // The receiver lives as long as the bus.
+        let _ = self.sender.send(message);
+        state.next_tag
+    }
+
+    /// Hand out the unsettled message `tag` again, as the broker does once the nodelet
+    /// that had it is gone.
+    pub fn redeliver(&self, tag: u64) -> Result<(), BusError> {
+        let mut message = self
+            .state
+            .lock()
+            .unwrap()
+            .unsettled
+            .get(&tag)
+            .cloned()
+            .ok_or(BusError::UnknownMessage(tag))?;
+        message.redelivered = true;
+        self.sender.send(message).map_err(|_| BusError::Closed)
+    }
+
+    /// Messages which were sent and not settled yet.
+    pub fn unsettled(&self) -> Vec<Message> {
+        self.state
+            .lock()
+            .unwrap()
+            .unsettled
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    pub fn acked(&self) -> Vec<Message> {
+        self.state.lock().unwrap().acked.clone()
+    }
+
+    /// Rejected messages with the error they failed with.
+    pub fn dead_letters(&self) -> Vec<(Message, String)> {
+        self.state.lock().unwrap().dead_letters.clone()
+    }
+
+    /// The reports published since the last call.
+    pub fn take_reports(&self) -> Vec<DeploymentReport> {
+        std::mem::take(&mut self.state.lock().unwrap().reports)
+    }
+
+    /// The node events published since the last call.
+    pub fn take_node_events(&self) -> Vec<NodeEvent> {
+        std::mem::take(&mut self.state.lock().unwrap().node_events)
+    }
+
+    fn settle(&self, message: &Message) -> Result<Message, BusError> {
+        self.state
+            .lock()
+            .unwrap()
+            .unsettled
+            .remove(&message.tag)
+            .ok_or(BusError::UnknownMessage(message.tag))
+    }
+}
+
+#[async_trait]
+impl MessageBus for ChannelBus {
+    async fn consume(&self) -> Result<MessageStream, BusError> {
+        let receiver = self
+            .receiver
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or(BusError::Closed)?;
+        let messages = futures::stream::unfold(receiver, |mut receiver| async move {
+            let message = receiver.recv().await?;
+            Some((Ok(message), receiver))
+        });
+        Ok(Box::pin(messages))
+    }
+
+    async fn cancel(&self) -> Result<(), BusError> {
+        Ok(())
+    }
+
+    async fn close(&self) -> Result<(), BusError> {
+        Ok(())
+    }
+
+    async fn ack(&self, message: &Message) -> Result<(), BusError> {
+        let message = self.settle(message)?;
+        self.state.lock().unwrap().acked.push(message);
+        Ok(())
+    }
+
+    /// The retry goes through the same headers as on the broker.
+    async fn retry(&self, message: &Message, error: &str, delay: Duration) -> Result<(), BusError> {
+        self.settle(message)?;
+        let retry = RetryState {
+            retries: message.retries,
+            routing_key: Some(message.routing_key.clone()),
+        };
+        let retry =
+            RetryState::from_headers(Some(&retry.next_headers(&message.routing_key, error)));
+        let mut state = self.state.lock().unwrap();
+        state.next_tag += 1;
+        let retried = Message {
+            tag: state.next_tag,
+            routing_key: retry.routing_key.unwrap_or_default(),
+            retries: retry.retries,
+            redelivered: false,
+            ..message.clone()
+        };
+        state.unsettled.insert(retried.tag, retried.clone());
+        let sender = self.sender.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let _ = sender.send(retried);
+        });
+        Ok(())
+    }
+
+    async fn nack(&self, message: &Message, error: &str) -> Result<(), BusError> {
+        self.settle(message)?;
+        self.state
+            .lock()
+            .unwrap()
+            .dead_letters
+            .push((message.clone(), error.to_owned()));
+        Ok(())
+    }
+
+    async fn publish_report(&self, report: &DeploymentReport) -> Result<(), BusError> {
+        // Through JSON like on the wire, the reports are not `Clone` either.
+        let report = serde_json::from_slice(&serde_json::to_vec(report)?)?;
+        self.state.lock().unwrap().reports.push(report);
+        Ok(())
+    }
+
+    async fn publish_node_event(&self, event: &NodeEvent) -> Result<(), BusError> {
+        let event = serde_json::from_slice(&serde_json::to_vec(event)?)?;
+        self.state.lock().unwrap().node_events.push(event);
+        Ok(())
+    }
+}
@@ -0,0 +1,336 @@
+use std::fmt::Display;
+
+use cloud::{ChangeImpact, ResourceChange};
+
+use crate::{
+    Hook, NetworkInterface, ResourceControl, RestartPolicy, SMFService, SecretMount, Zone,
+    ZoneImage,
+};
+
+/// The differences between the spec of a deployed zone and a requested one, each sorted
+/// by what it takes to apply it.
+#[derive(Debug, Clone, Default)]
+pub struct ZoneDiff {
+    pub changes: Vec<ResourceChange>,
+}
+
+impl ZoneDiff {
+    pub fn new(old: &Zone, new: &Zone) -> Self {
+        let mut diff = Self::default();
+
+        if old.brand != new.brand {
+            diff.push(
+                "brand",
+                ChangeImpact::Reinstall,
+                format!("{} -> {}", old.brand, new.brand),
+            );
+        }
+        if old.image != new.image {
+            let image = |image: &Option<ZoneImage>| {
+                image
+                    .as_ref()
+                    .map(ToString::to_string)
+                    .unwrap_or_else(|| "none".to_owned())
+            };
+            diff.push(
+                "image",
+                ChangeImpact::Reinstall,
+                format!("{} -> {}", image(&old.image), image(&new.image)),
+            );
+        }
+        if let Some(description) = set_changes(&old.packages, &new.packages) {
+            diff.push("packages", ChangeImpact::Reinstall, description);
+        }
+
+        if old.ip_type != new.ip_type {
+            diff.push(
+                "ip-type",
+                ChangeImpact::Reboot,
+                format!("{} -> {}", old.ip_type, new.ip_type),
+            );
+        }
+        diff.keyed(
+            "net",
+            ChangeImpact::Reboot,
+            &old.network,
+            &new.network,
+            interface_key,
+        );
+        diff.keyed(
+            "rctl",
+            ChangeImpact::Reboot,
+            &old.rctls,
+            &new.rctls,
+            |_, rctl: &ResourceControl| rctl.name.clone(),
+        );
+
+        if old.autoboot != new.autoboot {
+            diff.push(
+                "autoboot",
+                ChangeImpact::Live,
+                format!("{} -> {}", old.autoboot, new.autoboot),
+            );
+        }
+        if let Some(description) = list_changes(&old.nameservers, &new.nameservers) {
+            diff.push("nameservers", ChangeImpact::Live, description);
+        }
+        if let Some(description) = list_changes(&old.dns_search, &new.dns_search) {
+            diff.push("dns-search", ChangeImpact::Live, description);
+        }
+        diff.keyed(
+            "service",
+            ChangeImpact::Live,
+            &old.services,
+            &new.services,
+            |_, service: &SMFService| service.name.clone(),
+        );
+        diff.keyed(
+            "hook",
+            ChangeImpact::Live,
+            &old.hooks,
+            &new.hooks,
+            |index, hook: &Hook| format!("{} #{index}", hook.stage),
+        );
+        diff.keyed(
+            "secret",
+            ChangeImpact::Live,
+            &old.secrets,
+            &new.secrets,
+            |_, mount: &SecretMount| mount.path.display().to_string(),
+        );
+        // Only applied on the first boot, reinstalling for it would lose the zone's data.
+        if old.system_config != new.system_config {
+            diff.push(
+                "system-config",
+                ChangeImpact::Live,
+                "changed, takes effect when the zone is installed again".into(),
+            );
+        }
+        if old.restart != new.restart {
+            let policy = |restart: &Option<RestartPolicy>| {
+                restart
+                    .as_ref()
+                    .map(ToString::to_string)
+                    .unwrap_or_else(|| "none".to_owned())
+            };
+            diff.push(
+                "restart",
+                ChangeImpact::Live,
+                format!("{} -> {}", policy(&old.restart), policy(&new.restart)),
+            );
+        }
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// The most disruptive action needed to apply all changes.
+    pub fn impact(&self) -> Option<ChangeImpact> {
+        self.changes.iter().map(|change| change.impact).max()
+    }
+
+    pub fn has(&self, impact: ChangeImpact) -> bool {
+        self.changes.iter().any(|change| change.impact == impact)
+    }
+
+    fn push(&mut self, field: &str, impact: ChangeImpact, description: String) {
+        self.changes.push(ResourceChange {
+            field: field.to_owned(),
+            impact,
+            description,
+        });
+    }
+
+    /// Compare resources which are identified by a key, like interfaces by their name.
+    fn keyed<T: PartialEq>(
+        &mut self,
+        field: &str,
+        impact: ChangeImpact,
+        old: &[T],
+        new: &[T],
+        key: impl Fn(usize, &T) -> String,
+    ) {
+        let old: Vec<_> = old
+            .iter()
+            .enumerate()
+            .map(|(index, item)| (key(index, item), item))
+            .collect();
+        let new: Vec<_> = new
+            .iter()
+            .enumerate()
+            .map(|(index, item)| (key(index, item), item))
+            .collect();
+
+        for (name, item) in &new {
+            match old.iter().find(|(old_name, _)| old_name == name) {
+                None => self.push(&format!("{field} {name}"), impact, "added".into()),
+                Some((_, old_item)) if old_item != item => {
+                    self.push(&format!("{field} {name}"), impact, "changed".into())
+                }
+                Some(_) => {}
+            }
+        }
+        for (name, _) in &old {
+            if !new.iter().any(|(new_name, _)| new_name == name) {
+                self.push(&format!("{field} {name}"), impact, "removed".into());
+            }
+        }
+    }
+}
+
+/// Unnamed interfaces are known by their position.
+fn interface_key(index: usize, interface: &NetworkInterface) -> String {
+    interface
+        .name
+        .clone()
+        .unwrap_or_else(|| format!("#{index}"))
+}
+
+/// Like `list_changes`, for lists whose order means nothing.
+fn set_changes<T: PartialEq + Display>(old: &[T], new: &[T]) -> Option<String> {
+    if old.iter().all(|item| new.contains(item)) && new.iter().all(|item| old.contains(item)) {
+        return None;
+    }
+    list_changes(old, new)
+}
+
+fn list_changes<T: PartialEq + Display>(old: &[T], new: &[T]) -> Option<String> {
+    if old == new {
+        return None;
+    }
+    let added: Vec<String> = new
+        .iter()
+        .filter(|item| !old.contains(item))
+        .map(ToString::to_string)
+        .collect();
+    let removed: Vec<String> = old
+        .iter()
+        .filter(|item| !new.contains(item))
+        .map(ToString::to_string)
+        .collect();
+
+    let mut parts = vec![];
+    if !added.is_empty() {
+        parts.push(format!("added {}", added.join(", ")));
+    }
+    if !removed.is_empty() {
+        parts.push(format!("removed {}", removed.join(", ")));
+    }
+    if parts.is_empty() {
+        parts.push("reordered".into());
+    }
+    Some(parts.join("; "))
+}
+
+#[cfg(test)]
+mod tests {
+    use cloud::ChangeImpact::{Live, Reboot, Reinstall};
+
+    use super::*;
+    use crate::testing::zone;
+    use crate::{ZoneBrand, ZoneIpType};
+
+    const IMAGE: &str = r#"image "/images/pg16.zfs" format="zfs-send" digest="sha256:9f86""#;
+    const NET: &str = r#"net "oinet" { network "res://example.org/net@1.0.0#internal"; }"#;
+    const HOOK: &str = r#"hook "pre-uninstall" { command "true"; }"#;
+    const SECRET: &str = r#"secret "res://example.org/password@1.0.0" path="/etc/password""#;
+
+    fn fields(diff: &ZoneDiff) -> Vec<(&str, ChangeImpact)> {
+        diff.changes
+            .iter()
+            .map(|change| (change.field.as_str(), change.impact))
+            .collect()
+    }
+
+    #[test]
+    fn each_field_is_sorted_by_what_it_takes_to_apply() {
+        let cases = [
+            ("", IMAGE, "image", Reinstall),
+            (r#"package "a""#, r#"package "b""#, "packages", Reinstall),
+            ("", NET, "net oinet", Reboot),
+            (
+                NET,
+                r#"net "oinet" { network "res://example.org/net@1.0.0#internal"; maxbw "10M"; }"#,
+                "net oinet",
+                Reboot,
+            ),
+            (
+                r#"rctl "zone.max-lwps" limit=2000"#,
+                "",
+                "rctl zone.max-lwps",
+                Reboot,
+            ),
+            (r#"nameserver "8.8.8.8""#, "", "nameservers", Live),
+            (r#"dns-search "example.org""#, "", "dns-search", Live),
+            (
+                r#"service "svc:/a:default""#,
+                "",
+                "service svc:/a:default",
+                Live,
+            ),
+            ("", HOOK, "hook pre-uninstall #0", Live),
+            ("", SECRET, "secret /etc/password", Live),
+            (
+                "",
+                r#"system-config { hostname "db1"; }"#,
+                "system-config",
+                Live,
+            ),
+            ("", r#"restart "on-failure""#, "restart", Live),
+        ];
+        for (old, new, field, impact) in cases {
+            let diff = ZoneDiff::new(&zone(old), &zone(new));
+            assert_eq!(fields(&diff), [(field, impact)], "{old} -> {new}");
+        }
+    }
+
+    #[test]
+    fn the_brand_ip_type_and_autoboot_are_compared() {
+        let old = zone("");
+        let mut new = old.clone();
+        new.brand = ZoneBrand::UnlinkedPkg;
+        new.ip_type = ZoneIpType::Shared;
+        new.autoboot = !old.autoboot;
+        let diff = ZoneDiff::new(&old, &new);
+        assert_eq!(
+            fields(&diff),
+            [
+                ("brand", Reinstall),
+                ("ip-type", Reboot),
+                ("autoboot", Live)
+            ]
+        );
+        assert_eq!(diff.impact(), Some(Reinstall));
+    }
+
+    #[test]
+    fn the_same_spec_is_no_change() {
+        let spec =
+            format!("{IMAGE}\n{NET}\n{HOOK}\n{SECRET}\npackage \"a\"\nnameserver \"8.8.8.8\"");
+        let diff = ZoneDiff::new(&zone(&spec), &zone(&spec));
+        assert!(diff.is_empty());
+        assert_eq!(diff.impact(), None);
+    }
+
+    #[test]
+    fn reordered_packages_are_no_change() {
+        let diff = ZoneDiff::new(
+            &zone("package \"a\"\npackage \"b\""),
+            &zone("package \"b\"\npackage \"a\""),
+        );
+        assert!(diff.is_empty());
+    }
+
+    #[test]
+    fn reordered_nameservers_are_a_change() {
+        let diff = ZoneDiff::new(
+            &zone("nameserver \"8.8.8.8\"\nnameserver \"9.9.9.9\""),
+            &zone("nameserver \"9.9.9.9\"\nnameserver \"8.8.8.8\""),
+        );
+        assert_eq!(fields(&diff), [("nameservers", Live)]);
+        assert_eq!(diff.changes[0].description, "reordered");
+    }
+}
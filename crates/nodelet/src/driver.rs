@@ -0,0 +1,274 @@
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use cloud::{ResourceIdentifier, ServiceHealth, ServiceState};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::network::{EtherstubPlan, InterfacePlan};
+use crate::secrets::SecretValue;
+use crate::sysconfig::ZoneFile;
+use crate::{Hook, SecretMount, Zone};
+
+pub mod fake;
+pub mod illumos;
+
+pub use fake::{FakeDriverConfig, FakeZoneDriver};
+pub use illumos::{IllumosDriverConfig, IllumosZoneDriver};
+
+#[derive(Debug, Error)]
+pub enum DriverError {
+    #[error("zone {0} does not exist")]
+    NotFound(String),
+
+    #[error("cannot {operation} zone {zone} while it is {state}")]
+    InvalidState {
+        zone: String,
+        operation: Operation,
+        state: ZoneState,
+    },
+
+    #[error("`{command}` failed: {stderr}")]
+    CommandFailed { command: String, stderr: String },
+
+    #[error("unknown zone state {0}")]
+    UnknownState(String),
+
+    #[error("`{command}` did not finish within {secs}s")]
+    TimedOut { command: String, secs: u64 },
+
+    #[error("injected failure: {operation} of zone {zone}")]
+    Injected { zone: String, operation: Operation },
+
+    #[error("{0} is a symbolic link or leaves the zone root")]
+    UnsafePath(PathBuf),
+
+    #[error(transparent)]
+    IO(#[from] std::io::Error),
+}
+
+/// The steps of a zone's lifecycle a driver implements.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Operation {
+    Configure,
+    Install,
+    Boot,
+    Halt,
+    Uninstall,
+    Delete,
+    State,
+    Reconfigure,
+    Update,
+    Release,
+    Services,
+    RecoverService,
+    Reboot,
+    Hook,
+    WriteSecret,
+    WriteFiles,
+    EnsureEtherstub,
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operation::Configure => write!(f, "configure"),
+            Operation::Install => write!(f, "install"),
+            Operation::Boot => write!(f, "boot"),
+            Operation::Halt => write!(f, "halt"),
+            Operation::Uninstall => write!(f, "uninstall"),
+            Operation::Delete => write!(f, "delete"),
+            Operation::State => write!(f, "query state"),
+            Operation::Reconfigure => write!(f, "reconfigure"),
+            Operation::Update => write!(f, "update"),
+            Operation::Release => write!(f, "release"),
+            Operation::Services => write!(f, "query services"),
+            Operation::RecoverService => write!(f, "recover service"),
+            Operation::Reboot => write!(f, "reboot"),
+            Operation::Hook => write!(f, "run hook"),
+            Operation::WriteSecret => write!(f, "write secret"),
+            Operation::WriteFiles => write!(f, "write files"),
+            Operation::EnsureEtherstub => write!(f, "ensure etherstub"),
+        }
+    }
+}
+
+/// Zone states as reported by `zoneadm list -p`, plus `Missing` for zones that are not
+/// configured at all.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ZoneState {
+    Missing,
+    Configured,
+    Incomplete,
+    Installed,
+    Ready,
+    Running,
+    ShuttingDown,
+    Down,
+}
+
+impl FromStr for ZoneState {
+    type Err = DriverError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "configured" => Ok(Self::Configured),
+            "incomplete" => Ok(Self::Incomplete),
+            "installed" => Ok(Self::Installed),
+            "ready" => Ok(Self::Ready),
+            "running" => Ok(Self::Running),
+            "shutting_down" => Ok(Self::ShuttingDown),
+            "down" => Ok(Self::Down),
+            s => Err(DriverError::UnknownState(s.to_owned())),
+        }
+    }
+}
+
+impl Display for ZoneState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ZoneState::Missing => write!(f, "missing"),
+            ZoneState::Configured => write!(f, "configured"),
+            ZoneState::Incomplete => write!(f, "incomplete"),
+            ZoneState::Installed => write!(f, "installed"),
+            ZoneState::Ready => write!(f, "ready"),
+            ZoneState::Running => write!(f, "running"),
+            ZoneState::ShuttingDown => write!(f, "shutting_down"),
+            ZoneState::Down => write!(f, "down"),
+        }
+    }
+}
+
+/// Receives the output of long running operations line by line.
+/// It may borrow from the caller, like the progress tracker of an operation.
+pub type LogSink<'a> = dyn Fn(&str) + Send + Sync + 'a;
+
+/// Everything the nodelet does to a zone goes through a driver, so the lifecycle can be
+/// exercised without the illumos zone tools.
+#[async_trait]
+pub trait ZoneDriver: Send + Sync {
+    async fn configure(
+        &self,
+        name: &str,
+        zone: &Zone,
+        interfaces: &[InterfacePlan],
+    ) -> Result<(), DriverError>;
+
+    /// Install a configured zone, from the fetched `image` of the zone if it has one.
+    /// Its packages are installed on top of the image.
+    async fn install(
+        &self,
+        name: &str,
+        zone: &Zone,
+        image: Option<&Path>,
+        log: &LogSink<'_>,
+    ) -> Result<(), DriverError>;
+
+    async fn boot(&self, name: &str) -> Result<(), DriverError>;
+
+    async fn halt(&self, name: &str) -> Result<(), DriverError>;
+
+    async fn uninstall(&self, name: &str) -> Result<(), DriverError>;
+
+    async fn delete(&self, name: &str) -> Result<(), DriverError>;
+
+    async fn state(&self, name: &str) -> Result<ZoneState, DriverError>;
+
+    /// Replace the interfaces and resource controls of a zone that is not running. They
+    /// take effect on the next boot.
+    async fn reconfigure(
+        &self,
+        name: &str,
+        zone: &Zone,
+        interfaces: &[InterfacePlan],
+    ) -> Result<(), DriverError>;
+
+    /// Apply the settings which do not need a reboot, like nameservers, autoboot and SMF
+    /// properties, to a running zone.
+    async fn update(&self, name: &str, zone: &Zone) -> Result<(), DriverError>;
+
+    /// Free the links created for a zone once it is deleted.
+    async fn release(&self, name: &str, links: &[String]) -> Result<(), DriverError>;
+
+    /// Create the etherstub backing a switch unless it exists, and set its MTU. The
+    /// VNICs of the zones on the switch are created on it.
+    async fn ensure_etherstub(&self, etherstub: &EtherstubPlan) -> Result<(), DriverError>;
+
+    /// The state of each of `services` inside a running zone.
+    async fn services(
+        &self,
+        name: &str,
+        services: &[String],
+    ) -> Result<Vec<ServiceHealth>, DriverError>;
+
+    /// Bring a service back that is in maintenance or disabled.
+    async fn recover_service(
+        &self,
+        name: &str,
+        service: &str,
+        state: ServiceState,
+    ) -> Result<(), DriverError>;
+
+    async fn reboot(&self, name: &str) -> Result<(), DriverError>;
+
+    /// Run a lifecycle hook of the zone and hand its output to `log` line by line.
+    async fn run_hook(&self, name: &str, hook: &Hook, log: &LogSink<'_>)
+        -> Result<(), DriverError>;
+
+    /// Replace the file of `mount` in a running zone with `value`. The value must not
+    /// show up in errors or logs.
+    async fn write_secret(
+        &self,
+        name: &str,
+        mount: &SecretMount,
+        value: &SecretValue,
+    ) -> Result<(), DriverError>;
+
+    /// Write files into the root of an installed zone which has not booted yet.
+    async fn write_files(&self, name: &str, files: &[ZoneFile]) -> Result<(), DriverError>;
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum DriverConfig {
+    Illumos(IllumosDriverConfig),
+    Fake(FakeDriverConfig),
+}
+
+impl DriverConfig {
+    pub fn build(self) -> Arc<dyn ZoneDriver> {
+        match self {
+            DriverConfig::Illumos(config) => Arc::new(IllumosZoneDriver::new(config)),
+            DriverConfig::Fake(config) => Arc::new(FakeZoneDriver::new(config)),
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DriverConfig::Illumos(_) => "illumos",
+            DriverConfig::Fake(_) => "fake",
+        }
+    }
+}
+
+/// Name of the zone managed for a resource. Versions of a resource share the zone.
+pub fn zone_name(identifier: &ResourceIdentifier) -> String {
+    let name = match &identifier.tenant {
+        Some(tenant) => format!("{tenant}-{}", identifier.name),
+        None => identifier.name.clone(),
+    };
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '-'
+            }
+        })
+        .take(64)
+        .collect()
+}
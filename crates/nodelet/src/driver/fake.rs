@@ -0,0 +1,473 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use cloud::{ServiceHealth, ServiceState};
+use serde::Deserialize;
+use tracing::debug;
+
+use super::{DriverError, LogSink, Operation, ZoneDriver, ZoneState};
+use crate::network::{EtherstubPlan, InterfacePlan};
+use crate::secrets::SecretValue;
+use crate::sysconfig::ZoneFile;
+use crate::{Hook, HookStage, SecretMount, Zone};
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct FakeDriverConfig {
+    /// Delay added to every operation, to make races and timeouts visible.
+    #[serde(default)]
+    pub latency_ms: u64,
+    /// Operations which always fail.
+    #[serde(default)]
+    pub fail: Vec<Operation>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Failure {
+    Always,
+    Times(usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct FakeZone {
+    pub state: ZoneState,
+    pub zone: Zone,
+    pub interfaces: Vec<InterfacePlan>,
+    /// Services not in here are online while the zone runs.
+    pub services: HashMap<String, ServiceState>,
+    /// Secret files written into the zone by their path.
+    pub secrets: HashMap<PathBuf, (SecretMount, SecretValue)>,
+    /// Files written into the zone root before it booted, by their path.
+    pub files: HashMap<PathBuf, ZoneFile>,
+}
+
+/// Keeps zones in memory and walks them through the same state machine as zoneadm.
+#[derive(Debug)]
+pub struct FakeZoneDriver {
+    latency: Duration,
+    zones: Mutex<HashMap<String, FakeZone>>,
+    /// VNICs created for zone interfaces, which outlive the zones until released.
+    links: Mutex<BTreeSet<String>>,
+    /// Etherstubs of the switches, by their link name.
+    etherstubs: Mutex<BTreeMap<String, EtherstubPlan>>,
+    failures: Mutex<HashMap<Operation, Failure>>,
+    /// Zone and stage of every hook run, in order.
+    hooks: Mutex<Vec<(String, HookStage)>>,
+}
+
+impl FakeZoneDriver {
+    pub fn new(config: FakeDriverConfig) -> Self {
+        let failures = config
+            .fail
+            .into_iter()
+            .map(|operation| (operation, Failure::Always))
+            .collect();
+        Self {
+            latency: Duration::from_millis(config.latency_ms),
+            zones: Mutex::new(HashMap::new()),
+            links: Mutex::new(BTreeSet::new()),
+            etherstubs: Mutex::new(BTreeMap::new()),
+            failures: Mutex::new(failures),
+            hooks: Mutex::new(vec![]),
+        }
+    }
+
+    /// Make `operation` fail, `times` times or forever when None.
+    pub fn inject_failure(&self, operation: Operation, times: Option<usize>) {
+        let failure = times.map(Failure::Times).unwrap_or(Failure::Always);
+        self.failures.lock().unwrap().insert(operation, failure);
+    }
+
+    pub fn clear_failures(&self) {
+        self.failures.lock().unwrap().clear();
+    }
+
+    /// Change a zone behind the nodelet's back, like a crash or an admin would.
+    pub fn set_state(&self, name: &str, state: ZoneState) {
+        let mut zones = self.zones.lock().unwrap();
+        if state == ZoneState::Missing {
+            zones.remove(name);
+        } else if let Some(zone) = zones.get_mut(name) {
+            zone.state = state;
+        }
+    }
+
+    /// Change a service of a zone, like it crashing into maintenance.
+    pub fn set_service_state(&self, name: &str, service: &str, state: ServiceState) {
+        if let Some(zone) = self.zones.lock().unwrap().get_mut(name) {
+            zone.services.insert(service.to_owned(), state);
+        }
+    }
+
+    pub fn zone(&self, name: &str) -> Option<FakeZone> {
+        self.zones.lock().unwrap().get(name).cloned()
+    }
+
+    pub fn hooks_run(&self) -> Vec<(String, HookStage)> {
+        self.hooks.lock().unwrap().clone()
+    }
+
+    pub fn links(&self) -> Vec<String> {
+        self.links.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn etherstubs(&self) -> Vec<EtherstubPlan> {
+        self.etherstubs.lock().unwrap().values().cloned().collect()
+    }
+
+    fn create_links(&self, interfaces: &[InterfacePlan]) {
+        self.links.lock().unwrap().extend(
+            interfaces
+                .iter()
+                .filter(|interface| interface.lower_link.is_some())
+                .map(|interface| interface.physical.clone()),
+        );
+    }
+
+    async fn enter(&self, name: &str, operation: Operation) -> Result<(), DriverError> {
+        if !self.latency.is_zero() {
+            tokio::time::sleep(self.latency).await;
+        }
+
+        let mut failures = self.failures.lock().unwrap();
+        match failures.get(&operation).copied() {
+            None => Ok(()),
+            Some(Failure::Always) => Err(DriverError::Injected {
+                zone: name.to_owned(),
+                operation,
+            }),
+            Some(Failure::Times(times)) => {
+                if times <= 1 {
+                    failures.remove(&operation);
+                } else {
+                    failures.insert(operation, Failure::Times(times - 1));
+                }
+                Err(DriverError::Injected {
+                    zone: name.to_owned(),
+                    operation,
+                })
+            }
+        }
+    }
+
+    fn transition(
+        &self,
+        name: &str,
+        operation: Operation,
+        from: &[ZoneState],
+        to: ZoneState,
+    ) -> Result<(), DriverError> {
+        let mut zones = self.zones.lock().unwrap();
+        let zone = zones
+            .get_mut(name)
+            .ok_or_else(|| DriverError::NotFound(name.to_owned()))?;
+        if !from.contains(&zone.state) {
+            return Err(DriverError::InvalidState {
+                zone: name.to_owned(),
+                operation,
+                state: zone.state,
+            });
+        }
+        debug!(zone = name, from = %zone.state, to = %to, "fake zone transition");
+        zone.state = to;
+        Ok(())
+    }
+
+    fn replace(
+        &self,
+        name: &str,
+        operation: Operation,
+        from: &[ZoneState],
+        spec: &Zone,
+        interfaces: Option<&[InterfacePlan]>,
+    ) -> Result<(), DriverError> {
+        let mut zones = self.zones.lock().unwrap();
+        let zone = zones
+            .get_mut(name)
+            .ok_or_else(|| DriverError::NotFound(name.to_owned()))?;
+        if !from.contains(&zone.state) {
+            return Err(DriverError::InvalidState {
+                zone: name.to_owned(),
+                operation,
+                state: zone.state,
+            });
+        }
+        zone.zone = spec.clone();
+        if let Some(interfaces) = interfaces {
+            zone.interfaces = interfaces.to_vec();
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ZoneDriver for FakeZoneDriver {
+    async fn configure(
+        &self,
+        name: &str,
+        zone: &Zone,
+        interfaces: &[InterfacePlan],
+    ) -> Result<(), DriverError> {
+        self.enter(name, Operation::Configure).await?;
+        let mut zones = self.zones.lock().unwrap();
+        match zones.get_mut(name) {
+            Some(existing) if existing.state != ZoneState::Configured => {
+                Err(DriverError::InvalidState {
+                    zone: name.to_owned(),
+                    operation: Operation::Configure,
+                    state: existing.state,
+                })
+            }
+            _ => {
+                self.create_links(interfaces);
+                zones.insert(
+                    name.to_owned(),
+                    FakeZone {
+                        state: ZoneState::Configured,
+                        zone: zone.clone(),
+                        interfaces: interfaces.to_vec(),
+                        services: HashMap::new(),
+                        secrets: HashMap::new(),
+                        files: HashMap::new(),
+                    },
+                );
+                Ok(())
+            }
+        }
+    }
+
+    async fn install(
+        &self,
+        name: &str,
+        zone: &Zone,
+        image: Option<&Path>,
+        log: &LogSink<'_>,
+    ) -> Result<(), DriverError> {
+        self.enter(name, Operation::Install).await?;
+        if let Some(image) = image {
+            log(&format!("installed from image {}", image.display()));
+        }
+        for package in &zone.packages {
+            log(&format!("installed {package}"));
+        }
+        self.transition(
+            name,
+            Operation::Install,
+            &[ZoneState::Configured],
+            ZoneState::Installed,
+        )
+    }
+
+    async fn boot(&self, name: &str) -> Result<(), DriverError> {
+        self.enter(name, Operation::Boot).await?;
+        self.transition(
+            name,
+            Operation::Boot,
+            &[ZoneState::Installed, ZoneState::Ready],
+            ZoneState::Running,
+        )
+    }
+
+    async fn halt(&self, name: &str) -> Result<(), DriverError> {
+        self.enter(name, Operation::Halt).await?;
+        self.transition(
+            name,
+            Operation::Halt,
+            &[ZoneState::Running, ZoneState::Ready, ZoneState::Down],
+            ZoneState::Installed,
+        )
+    }
+
+    async fn uninstall(&self, name: &str) -> Result<(), DriverError> {
+        self.enter(name, Operation::Uninstall).await?;
+        self.transition(
+            name,
+            Operation::Uninstall,
+            &[ZoneState::Installed, ZoneState::Incomplete],
+            ZoneState::Configured,
+        )
+    }
+
+    async fn delete(&self, name: &str) -> Result<(), DriverError> {
+        self.enter(name, Operation::Delete).await?;
+        let mut zones = self.zones.lock().unwrap();
+        match zones.get(name).map(|zone| zone.state) {
+            None => Err(DriverError::NotFound(name.to_owned())),
+            Some(ZoneState::Configured) => {
+                zones.remove(name);
+                Ok(())
+            }
+            Some(state) => Err(DriverError::InvalidState {
+                zone: name.to_owned(),
+                operation: Operation::Delete,
+                state,
+            }),
+        }
+    }
+
+    async fn state(&self, name: &str) -> Result<ZoneState, DriverError> {
+        self.enter(name, Operation::State).await?;
+        Ok(self
+            .zones
+            .lock()
+            .unwrap()
+            .get(name)
+            .map(|zone| zone.state)
+            .unwrap_or(ZoneState::Missing))
+    }
+
+    async fn reconfigure(
+        &self,
+        name: &str,
+        zone: &Zone,
+        interfaces: &[InterfacePlan],
+    ) -> Result<(), DriverError> {
+        self.enter(name, Operation::Reconfigure).await?;
+        self.replace(
+            name,
+            Operation::Reconfigure,
+            &[ZoneState::Configured, ZoneState::Installed],
+            zone,
+            Some(interfaces),
+        )?;
+        self.create_links(interfaces);
+        Ok(())
+    }
+
+    async fn update(&self, name: &str, zone: &Zone) -> Result<(), DriverError> {
+        self.enter(name, Operation::Update).await?;
+        self.replace(name, Operation::Update, &[ZoneState::Running], zone, None)
+    }
+
+    async fn release(&self, name: &str, links: &[String]) -> Result<(), DriverError> {
+        self.enter(name, Operation::Release).await?;
+        let mut created = self.links.lock().unwrap();
+        for link in links {
+            created.remove(link);
+        }
+        Ok(())
+    }
+
+    async fn ensure_etherstub(&self, etherstub: &EtherstubPlan) -> Result<(), DriverError> {
+        self.enter(&etherstub.link, Operation::EnsureEtherstub)
+            .await?;
+        self.etherstubs
+            .lock()
+            .unwrap()
+            .insert(etherstub.link.clone(), etherstub.clone());
+        Ok(())
+    }
+
+    async fn services(
+        &self,
+        name: &str,
+        services: &[String],
+    ) -> Result<Vec<ServiceHealth>, DriverError> {
+        self.enter(name, Operation::Services).await?;
+        let zones = self.zones.lock().unwrap();
+        let zone = zones
+            .get(name)
+            .ok_or_else(|| DriverError::NotFound(name.to_owned()))?;
+        if zone.state != ZoneState::Running {
+            return Err(DriverError::InvalidState {
+                zone: name.to_owned(),
+                operation: Operation::Services,
+                state: zone.state,
+            });
+        }
+        Ok(services
+            .iter()
+            .map(|service| ServiceHealth {
+                service: service.clone(),
+                state: zone
+                    .services
+                    .get(service)
+                    .copied()
+                    .unwrap_or(ServiceState::Online),
+            })
+            .collect())
+    }
+
+    async fn recover_service(
+        &self,
+        name: &str,
+        service: &str,
+        _state: ServiceState,
+    ) -> Result<(), DriverError> {
+        self.enter(name, Operation::RecoverService).await?;
+        self.set_service_state(name, service, ServiceState::Online);
+        Ok(())
+    }
+
+    async fn reboot(&self, name: &str) -> Result<(), DriverError> {
+        self.enter(name, Operation::Reboot).await?;
+        self.transition(
+            name,
+            Operation::Reboot,
+            &[ZoneState::Running],
+            ZoneState::Running,
+        )?;
+        if let Some(zone) = self.zones.lock().unwrap().get_mut(name) {
+            zone.services.clear();
+        }
+        Ok(())
+    }
+
+    async fn run_hook(
+        &self,
+        name: &str,
+        hook: &Hook,
+        log: &LogSink<'_>,
+    ) -> Result<(), DriverError> {
+        self.hooks
+            .lock()
+            .unwrap()
+            .push((name.to_owned(), hook.stage));
+        self.enter(name, Operation::Hook).await?;
+        log(&format!("ran {}", hook.command.join(" ")));
+        Ok(())
+    }
+
+    async fn write_secret(
+        &self,
+        name: &str,
+        mount: &SecretMount,
+        value: &SecretValue,
+    ) -> Result<(), DriverError> {
+        self.enter(name, Operation::WriteSecret).await?;
+        let mut zones = self.zones.lock().unwrap();
+        let zone = zones
+            .get_mut(name)
+            .ok_or_else(|| DriverError::NotFound(name.to_owned()))?;
+        if zone.state != ZoneState::Running {
+            return Err(DriverError::InvalidState {
+                zone: name.to_owned(),
+                operation: Operation::WriteSecret,
+                state: zone.state,
+            });
+        }
+        zone.secrets
+            .insert(mount.path.clone(), (mount.clone(), value.clone()));
+        Ok(())
+    }
+
+    async fn write_files(&self, name: &str, files: &[ZoneFile]) -> Result<(), DriverError> {
+        self.enter(name, Operation::WriteFiles).await?;
+        let mut zones = self.zones.lock().unwrap();
+        let zone = zones
+            .get_mut(name)
+            .ok_or_else(|| DriverError::NotFound(name.to_owned()))?;
+        if zone.state != ZoneState::Installed {
+            return Err(DriverError::InvalidState {
+                zone: name.to_owned(),
+                operation: Operation::WriteFiles,
+                state: zone.state,
+            });
+        }
+        zone.files
+            .extend(files.iter().map(|file| (file.path.clone(), file.clone())));
+        Ok(())
+    }
+}
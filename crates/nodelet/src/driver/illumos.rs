@@ -0,0 +1,988 @@
+use std::ffi::OsStr;
+use std::fmt::Write;
+use std::fs::{File, Permissions};
+use std::io::Write as _;
+use std::os::fd::OwnedFd;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use cloud::{ServiceHealth, ServiceState};
+use rustix::fs::{mkdirat, openat, Mode, OFlags, CWD};
+use rustix::io::Errno;
+use serde::Deserialize;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::Command;
+use tracing::{debug, instrument};
+
+use super::{DriverError, LogSink, ZoneDriver, ZoneState};
+use crate::health::worst_state;
+use crate::network::{EtherstubPlan, InterfacePlan};
+use crate::secrets::SecretValue;
+use crate::sysconfig::ZoneFile;
+use crate::{
+    Hook, HookTarget, MacAddressPolicy, SMFService, SecretMount, Zone, ZoneBrand, ZoneIpType,
+};
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct IllumosDriverConfig {
+    /// Directory the zonepaths are created in.
+    #[serde(default = "default_zone_root")]
+    pub zone_root: PathBuf,
+}
+
+fn default_zone_root() -> PathBuf {
+    PathBuf::from("/zones")
+}
+
+/// Drives zones with dladm, zonecfg and zoneadm.
+#[derive(Debug)]
+pub struct IllumosZoneDriver {
+    config: IllumosDriverConfig,
+}
+
+impl IllumosZoneDriver {
+    pub fn new(config: IllumosDriverConfig) -> Self {
+        Self { config }
+    }
+
+    fn zonepath(&self, name: &str) -> PathBuf {
+        self.config.zone_root.join(name)
+    }
+
+    async fn create_vnic(&self, interface: &InterfacePlan) -> Result<(), DriverError> {
+        let Some(lower_link) = &interface.lower_link else {
+            return Ok(());
+        };
+        if run(Command::new("dladm").args(["show-vnic", &interface.physical]))
+            .await
+            .is_ok()
+        {
+            debug!(vnic = interface.physical, "vnic exists");
+            return Ok(());
+        }
+        run(Command::new("dladm").args(create_vnic_args(interface, lower_link))).await?;
+        Ok(())
+    }
+
+    /// The script goes in on stdin, a file in a shared temporary directory could be
+    /// swapped before zonecfg reads it.
+    async fn zonecfg(&self, name: &str, script: String) -> Result<(), DriverError> {
+        run_with_input(
+            Command::new("zonecfg").args(["-z", name, "-f", "-"]),
+            script.as_bytes(),
+        )
+        .await
+    }
+
+    /// Rewritten on updates of a running zone, whose users may have replaced it by a link.
+    fn write_resolv_conf(&self, name: &str, zone: &Zone) -> Result<(), DriverError> {
+        let file = ZoneFile {
+            path: PathBuf::from("etc/resolv.conf"),
+            contents: resolv_conf_contents(zone),
+            mode: 0o644,
+        };
+        write_below(&self.zonepath(name).join("root"), &file)
+    }
+}
+
+#[async_trait]
+impl ZoneDriver for IllumosZoneDriver {
+    #[instrument(skip(self, zone, interfaces))]
+    async fn configure(
+        &self,
+        name: &str,
+        zone: &Zone,
+        interfaces: &[InterfacePlan],
+    ) -> Result<(), DriverError> {
+        for interface in interfaces {
+            self.create_vnic(interface).await?;
+        }
+
+        self.zonecfg(name, zonecfg_script(&self.zonepath(name), zone, interfaces))
+            .await
+    }
+
+    #[instrument(skip(self, zone, log))]
+    async fn install(
+        &self,
+        name: &str,
+        zone: &Zone,
+        image: Option<&Path>,
+        log: &LogSink<'_>,
+    ) -> Result<(), DriverError> {
+        let mut command = Command::new("zoneadm");
+        command.args(["-z", name, "install"]);
+        match image {
+            Some(image) => {
+                command.arg("-s").arg(image);
+                run_logged(&mut command, log).await?;
+                if !zone.packages.is_empty() {
+                    let root = self.zonepath(name).join("root");
+                    let mut pkg = Command::new("pkg");
+                    pkg.arg("-R").arg(root).arg("install").args(&zone.packages);
+                    run_logged(&mut pkg, log).await?;
+                }
+            }
+            None => {
+                if !zone.packages.is_empty() {
+                    command.arg("-e").args(&zone.packages);
+                }
+                run_logged(&mut command, log).await?;
+            }
+        }
+        self.write_resolv_conf(name, zone)
+    }
+
+    #[instrument(skip(self))]
+    async fn boot(&self, name: &str) -> Result<(), DriverError> {
+        zoneadm(name, &["boot"]).await
+    }
+
+    #[instrument(skip(self))]
+    async fn halt(&self, name: &str) -> Result<(), DriverError> {
+        zoneadm(name, &["halt"]).await
+    }
+
+    #[instrument(skip(self))]
+    async fn uninstall(&self, name: &str) -> Result<(), DriverError> {
+        zoneadm(name, &["uninstall", "-F"]).await
+    }
+
+    #[instrument(skip(self))]
+    async fn delete(&self, name: &str) -> Result<(), DriverError> {
+        run(Command::new("zonecfg").args(["-z", name, "delete", "-F"])).await?;
+        Ok(())
+    }
+
+    async fn state(&self, name: &str) -> Result<ZoneState, DriverError> {
+        match run(Command::new("zoneadm").args(["-z", name, "list", "-p"])).await {
+            // id:name:state:path:uuid:brand:ip-type
+            Ok(output) => output
+                .trim()
+                .split(':')
+                .nth(2)
+                .ok_or_else(|| DriverError::UnknownState(output.clone()))?
+                .parse(),
+            Err(DriverError::CommandFailed { stderr, .. }) if stderr.contains("No such zone") => {
+                Ok(ZoneState::Missing)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    #[instrument(skip(self, zone, interfaces))]
+    async fn reconfigure(
+        &self,
+        name: &str,
+        zone: &Zone,
+        interfaces: &[InterfacePlan],
+    ) -> Result<(), DriverError> {
+        // Recreate the VNICs, their properties can only be set on creation.
+        for interface in interfaces {
+            if interface.lower_link.is_some()
+                && run(Command::new("dladm").args(["show-vnic", &interface.physical]))
+                    .await
+                    .is_ok()
+            {
+                run(Command::new("dladm").args(["delete-vnic", &interface.physical])).await?;
+            }
+            self.create_vnic(interface).await?;
+        }
+
+        let info = run(Command::new("zonecfg").args(["-z", name, "info"])).await?;
+        let existing = Resources {
+            net: info.lines().any(|line| line.trim_end() == "net:"),
+            rctl: info.lines().any(|line| line.trim_end() == "rctl:"),
+        };
+        self.zonecfg(name, reconfigure_script(&existing, zone, interfaces))
+            .await
+    }
+
+    #[instrument(skip(self, zone))]
+    async fn update(&self, name: &str, zone: &Zone) -> Result<(), DriverError> {
+        run(Command::new("zonecfg").args(["-z", name, &format!("set autoboot={}", zone.autoboot)]))
+            .await?;
+        self.write_resolv_conf(name, zone)?;
+
+        for service in &zone.services {
+            for args in svccfg_setprop_args(service) {
+                run(&mut zlogin(name, &args)).await?;
+            }
+            run(&mut zlogin(name, ["svcadm", "refresh", &service.name])).await?;
+        }
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn release(&self, name: &str, links: &[String]) -> Result<(), DriverError> {
+        for link in links {
+            if run(Command::new("dladm").args(["show-vnic", link]))
+                .await
+                .is_err()
+            {
+                debug!(vnic = link, "vnic already gone");
+                continue;
+            }
+            run(Command::new("dladm").args(["delete-vnic", link])).await?;
+        }
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn ensure_etherstub(&self, etherstub: &EtherstubPlan) -> Result<(), DriverError> {
+        if run(Command::new("dladm").args(["show-etherstub", &etherstub.link]))
+            .await
+            .is_ok()
+        {
+            debug!(etherstub = etherstub.link, "etherstub exists");
+        } else {
+            run(Command::new("dladm").args(["create-etherstub", &etherstub.link])).await?;
+        }
+        if let Some(mtu) = etherstub.mtu {
+            run(Command::new("dladm").args([
+                "set-linkprop",
+                "-p",
+                &format!("mtu={mtu}"),
+                &etherstub.link,
+            ]))
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn services(
+        &self,
+        name: &str,
+        services: &[String],
+    ) -> Result<Vec<ServiceHealth>, DriverError> {
+        let mut health = vec![];
+        for service in services {
+            let state = match run(&mut zlogin(name, ["svcs", "-H", "-o", "state", service])).await {
+                Ok(output) => parse_service_state(&output)?,
+                // Not imported into the zone's repository (yet).
+                Err(DriverError::CommandFailed { stderr, .. })
+                    if stderr.contains("doesn't match any instances") =>
+                {
+                    ServiceState::Uninitialized
+                }
+                Err(e) => return Err(e),
+            };
+            health.push(ServiceHealth {
+                service: service.clone(),
+                state,
+            });
+        }
+        Ok(health)
+    }
+
+    #[instrument(skip(self))]
+    async fn recover_service(
+        &self,
+        name: &str,
+        service: &str,
+        state: ServiceState,
+    ) -> Result<(), DriverError> {
+        let action = match state {
+            ServiceState::Maintenance => "clear",
+            _ => "enable",
+        };
+        run(&mut zlogin(name, ["svcadm", action, service])).await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn reboot(&self, name: &str) -> Result<(), DriverError> {
+        zoneadm(name, &["reboot"]).await
+    }
+
+    #[instrument(skip(self, hook, log), fields(stage = %hook.stage))]
+    async fn run_hook(
+        &self,
+        name: &str,
+        hook: &Hook,
+        log: &LogSink<'_>,
+    ) -> Result<(), DriverError> {
+        let args = hook_args(name, hook);
+        let Some((program, args)) = args.split_first() else {
+            return Ok(());
+        };
+        let mut command = Command::new(program);
+        command.args(args).kill_on_drop(true);
+        if hook.run_in == HookTarget::Global {
+            command.envs(hook_env(name, hook));
+        }
+        let result = tokio::time::timeout(
+            Duration::from_secs(hook.timeout),
+            run_logged(&mut command, log),
+        )
+        .await;
+        result.unwrap_or_else(|_| {
+            Err(DriverError::TimedOut {
+                command: format!("{:?}", command.as_std()),
+                secs: hook.timeout,
+            })
+        })
+    }
+
+    #[instrument(skip(self, mount, value), fields(path = %mount.path.display()))]
+    async fn write_secret(
+        &self,
+        name: &str,
+        mount: &SecretMount,
+        value: &SecretValue,
+    ) -> Result<(), DriverError> {
+        // Written from inside the zone, so its users resolve and links in the zone root
+        // cannot point the nodelet at files of the global zone.
+        run_with_input(
+            Command::new("zlogin").arg(name).arg(secret_script(mount)),
+            value.expose(),
+        )
+        .await
+    }
+
+    #[instrument(skip(self, files))]
+    async fn write_files(&self, name: &str, files: &[ZoneFile]) -> Result<(), DriverError> {
+        // Nothing in the zone ran yet, so the global zone can write into its root.
+        let root = self.zonepath(name).join("root");
+        for file in files {
+            write_below(&root, file)?;
+            debug!(path = %file.path.display(), "wrote file");
+        }
+        Ok(())
+    }
+}
+
+/// Writes `file` below `root` without following symbolic links. The zone root belongs to
+/// the tenant, so every component is opened relative to its parent with `O_NOFOLLOW` and a
+/// link swapped in at any point cannot point the nodelet at files of the global zone.
+fn write_below(root: &Path, file: &ZoneFile) -> Result<(), DriverError> {
+    let unsafe_path = || DriverError::UnsafePath(file.path.clone());
+    let refused = |e: Errno| {
+        if e == Errno::LOOP || e == Errno::NOTDIR {
+            unsafe_path()
+        } else {
+            DriverError::IO(e.into())
+        }
+    };
+    let mut components = file
+        .path
+        .components()
+        .map(|component| match component {
+            Component::Normal(component) => Ok(component),
+            _ => Err(unsafe_path()),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let Some(name) = components.pop() else {
+        return Err(unsafe_path());
+    };
+
+    // Directories created for private files are private as well.
+    let dir_mode = if file.mode & 0o077 == 0 { 0o700 } else { 0o755 };
+    let mut dir = openat(
+        CWD,
+        root,
+        OFlags::DIRECTORY | OFlags::CLOEXEC,
+        Mode::empty(),
+    )
+    .map_err(std::io::Error::from)?;
+    for component in components {
+        dir = match open_dir(&dir, component) {
+            Err(Errno::NOENT) => {
+                mkdirat(&dir, component, Mode::from_raw_mode(dir_mode))
+                    .map_err(std::io::Error::from)?;
+                open_dir(&dir, component)
+            }
+            opened => opened,
+        }
+        .map_err(refused)?;
+    }
+
+    // Not truncated before it is known to be a regular file, a fifo would block the open
+    // without O_NONBLOCK.
+    let target = openat(
+        &dir,
+        name,
+        OFlags::WRONLY | OFlags::CREATE | OFlags::NOFOLLOW | OFlags::NONBLOCK | OFlags::CLOEXEC,
+        Mode::from_raw_mode(file.mode),
+    )
+    .map_err(refused)?;
+    let mut target = File::from(target);
+    if !target.metadata()?.is_file() {
+        return Err(unsafe_path());
+    }
+    target.set_len(0)?;
+    target.write_all(file.contents.as_bytes())?;
+    // The mode given on open only applies to new files and is masked by the umask.
+    target.set_permissions(Permissions::from_mode(file.mode))?;
+    Ok(())
+}
+
+fn open_dir(parent: &OwnedFd, name: &OsStr) -> Result<OwnedFd, Errno> {
+    openat(
+        parent,
+        name,
+        OFlags::DIRECTORY | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+        Mode::empty(),
+    )
+}
+
+/// The shell script writing the secret of `mount` from its standard input. The file is
+/// written next to its destination with its final permissions and then moved over it, so
+/// the secret is never readable by anyone else.
+pub fn secret_script(mount: &SecretMount) -> String {
+    let path = mount.path.display().to_string();
+    let temporary = shell_quote(&format!("{path}.nodelet"));
+    let mut script = String::new();
+    if let Some(dir) = mount
+        .path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+    {
+        write!(
+            script,
+            "mkdir -p {} && ",
+            shell_quote(&dir.display().to_string())
+        )
+        .unwrap();
+    }
+    write!(
+        script,
+        "umask 077 && cat > {temporary} && chown {} {temporary} && chmod {:o} {temporary} && mv -f {temporary} {}",
+        shell_quote(&format!("{}:{}", mount.owner, mount.group)),
+        mount.mode,
+        shell_quote(&path),
+    )
+    .unwrap();
+    script
+}
+
+/// The variables a hook gets on top of its own.
+pub fn hook_env(name: &str, hook: &Hook) -> Vec<(String, String)> {
+    let mut env = vec![
+        ("NODELET_ZONE".to_owned(), name.to_owned()),
+        ("NODELET_HOOK".to_owned(), hook.stage.to_string()),
+    ];
+    env.extend(
+        hook.env
+            .iter()
+            .map(|var| (var.name.clone(), var.value.clone())),
+    );
+    env
+}
+
+/// The command line of a hook. Inside the zone `zlogin` hands it to a shell, so the
+/// arguments are quoted and the variables set through `env`.
+pub fn hook_args(name: &str, hook: &Hook) -> Vec<String> {
+    match hook.run_in {
+        HookTarget::Global => hook.command.clone(),
+        HookTarget::Zone => {
+            let mut args = vec!["zlogin".to_owned(), name.to_owned(), "env".to_owned()];
+            args.extend(
+                hook_env(name, hook)
+                    .into_iter()
+                    .map(|(name, value)| shell_quote(&format!("{name}={value}"))),
+            );
+            args.extend(hook.command.iter().map(|arg| shell_quote(arg)));
+            args
+        }
+    }
+}
+
+/// A command run inside the zone. `zlogin` hands the command line to a shell, so every
+/// argument is quoted.
+fn zlogin<I, S>(name: &str, args: I) -> Command
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let mut command = Command::new("zlogin");
+    command
+        .arg(name)
+        .args(args.into_iter().map(|arg| shell_quote(arg.as_ref())));
+    command
+}
+
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+/// The state of a service from `svcs -H -o state`. A service name without an instance
+/// can match several instances, the worst of them counts.
+pub fn parse_service_state(output: &str) -> Result<ServiceState, DriverError> {
+    let mut states = vec![];
+    for line in output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+    {
+        // A trailing `*` marks a service on its way to another state.
+        let state = match line.trim_end_matches('*') {
+            "online" | "legacy_run" => ServiceState::Online,
+            "degraded" => ServiceState::Degraded,
+            "maintenance" => ServiceState::Maintenance,
+            "offline" => ServiceState::Offline,
+            "disabled" => ServiceState::Disabled,
+            "uninitialized" => ServiceState::Uninitialized,
+            state => return Err(DriverError::UnknownState(state.to_owned())),
+        };
+        states.push(state);
+    }
+    Ok(worst_state(states).unwrap_or(ServiceState::Uninitialized))
+}
+
+async fn zoneadm(name: &str, args: &[&str]) -> Result<(), DriverError> {
+    run(Command::new("zoneadm").args(["-z", name]).args(args)).await?;
+    Ok(())
+}
+
+async fn run(command: &mut Command) -> Result<String, DriverError> {
+    debug!(?command, "running");
+    let output = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        Err(DriverError::CommandFailed {
+            command: format!("{:?}", command.as_std()),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        })
+    }
+}
+
+/// Like [`run`], but feeds `input` to the command and drops its output.
+async fn run_with_input(command: &mut Command, input: &[u8]) -> Result<(), DriverError> {
+    debug!(?command, "running");
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    stdin.write_all(input).await?;
+    // Closing it ends the input.
+    drop(stdin);
+    let output = child.wait_with_output().await?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(DriverError::CommandFailed {
+            command: format!("{:?}", command.as_std()),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        })
+    }
+}
+
+/// Like [`run`], but hands every line of output to `log` while the command runs.
+async fn run_logged(command: &mut Command, log: &LogSink<'_>) -> Result<(), DriverError> {
+    debug!(?command, "running");
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+
+    // Drain stderr alongside, a full pipe would block the command.
+    let mut errors = String::new();
+    let (lines, errors_read) = tokio::join!(
+        async {
+            let mut lines = BufReader::new(stdout).lines();
+            while let Some(line) = lines.next_line().await? {
+                log(&line);
+            }
+            Ok::<_, std::io::Error>(())
+        },
+        stderr.read_to_string(&mut errors)
+    );
+    lines?;
+    errors_read?;
+
+    if child.wait().await?.success() {
+        Ok(())
+    } else {
+        Err(DriverError::CommandFailed {
+            command: format!("{:?}", command.as_std()),
+            stderr: errors.trim().to_owned(),
+        })
+    }
+}
+
+fn brand_name(brand: &ZoneBrand) -> &'static str {
+    match brand {
+        ZoneBrand::LinkedPkg => "lipkg",
+        ZoneBrand::UnlinkedPkg => "nlipkg",
+    }
+}
+
+/// Arguments for `dladm` creating the VNIC of an interface on its etherstub.
+pub fn create_vnic_args(interface: &InterfacePlan, lower_link: &str) -> Vec<String> {
+    let mut args = vec![
+        "create-vnic".to_owned(),
+        "-l".to_owned(),
+        lower_link.to_owned(),
+    ];
+    if let Some(vlan) = interface.vlan {
+        args.extend(["-v".to_owned(), vlan.to_string()]);
+    }
+    match interface.mac {
+        Some(MacAddressPolicy::Fixed(address)) => {
+            args.extend(["-m".to_owned(), address.to_string()])
+        }
+        Some(MacAddressPolicy::Random) => args.extend(["-m".to_owned(), "random".to_owned()]),
+        Some(MacAddressPolicy::Derived) | None => {}
+    }
+
+    let mut properties = vec![];
+    if let Some(maxbw) = interface.maxbw {
+        properties.push(format!("maxbw={maxbw}"));
+    }
+    if let Some(mtu) = interface.mtu {
+        properties.push(format!("mtu={mtu}"));
+    }
+    if !interface.protection.is_empty() {
+        let protection: Vec<String> = interface
+            .protection
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        properties.push(format!("protection={}", protection.join(",")));
+    }
+    if !properties.is_empty() {
+        args.extend(["-p".to_owned(), properties.join(",")]);
+    }
+
+    args.push(interface.physical.clone());
+    args
+}
+
+/// The zonecfg commands creating a zone from its spec.
+pub fn zonecfg_script(zonepath: &Path, zone: &Zone, interfaces: &[InterfacePlan]) -> String {
+    let mut script = String::new();
+    writeln!(script, "create -b").unwrap();
+    writeln!(script, "set zonepath={}", zonepath.display()).unwrap();
+    writeln!(script, "set brand={}", brand_name(&zone.brand)).unwrap();
+    writeln!(script, "set autoboot={}", zone.autoboot).unwrap();
+    write_resources(&mut script, zone, interfaces);
+    writeln!(script, "verify").unwrap();
+    writeln!(script, "commit").unwrap();
+    script
+}
+
+/// Resource types a configured zone already has.
+#[derive(Debug, Default)]
+pub struct Resources {
+    pub net: bool,
+    pub rctl: bool,
+}
+
+/// The zonecfg commands replacing the interfaces and resource controls of a zone.
+pub fn reconfigure_script(
+    existing: &Resources,
+    zone: &Zone,
+    interfaces: &[InterfacePlan],
+) -> String {
+    let mut script = String::new();
+    // zonecfg refuses to remove resources which do not exist
+    if existing.net {
+        writeln!(script, "remove -F net").unwrap();
+    }
+    if existing.rctl {
+        writeln!(script, "remove -F rctl").unwrap();
+    }
+    write_resources(&mut script, zone, interfaces);
+    writeln!(script, "verify").unwrap();
+    writeln!(script, "commit").unwrap();
+    script
+}
+
+fn write_resources(script: &mut String, zone: &Zone, interfaces: &[InterfacePlan]) {
+    writeln!(script, "set ip-type={}", zone.ip_type).unwrap();
+
+    for interface in interfaces {
+        writeln!(script, "add net").unwrap();
+        writeln!(script, "set physical={}", interface.physical).unwrap();
+        let addresses: Vec<String> = interface
+            .allowed_address
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        if !addresses.is_empty() {
+            match zone.ip_type {
+                ZoneIpType::Exclusive => {
+                    writeln!(script, "set allowed-address={}", addresses.join(",")).unwrap()
+                }
+                ZoneIpType::Shared => writeln!(script, "set address={}", addresses[0]).unwrap(),
+            }
+        }
+        if !interface.defrouter.is_empty() {
+            let routers: Vec<String> = interface
+                .defrouter
+                .iter()
+                .map(ToString::to_string)
+                .collect();
+            writeln!(script, "set defrouter={}", routers.join(",")).unwrap();
+        }
+        writeln!(script, "end").unwrap();
+    }
+
+    for rctl in &zone.rctls {
+        writeln!(script, "add rctl").unwrap();
+        writeln!(script, "set name={}", rctl.name).unwrap();
+        writeln!(
+            script,
+            "add value (priv={},limit={},action={})",
+            rctl.privilege, rctl.limit, rctl.action
+        )
+        .unwrap();
+        writeln!(script, "end").unwrap();
+    }
+}
+
+/// `svccfg` invocations setting the properties of a service, run inside the zone. The
+/// values are quoted for svccfg, quoting for the shell is left to [`zlogin`].
+pub fn svccfg_setprop_args(service: &SMFService) -> Vec<Vec<String>> {
+    let mut properties: Vec<_> = service
+        .property_groups
+        .iter()
+        .flat_map(|group| {
+            group
+                .properties
+                .iter()
+                .map(move |(property, value)| (&group.name, property, value))
+        })
+        .collect();
+    properties.sort();
+    properties
+        .into_iter()
+        .map(|(group, property, value)| {
+            vec![
+                "svccfg".to_owned(),
+                "-s".to_owned(),
+                service.name.clone(),
+                "setprop".to_owned(),
+                format!("{group}/{property}"),
+                "=".to_owned(),
+                format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")),
+            ]
+        })
+        .collect()
+}
+
+pub fn resolv_conf_contents(zone: &Zone) -> String {
+    let mut contents = String::new();
+    for nameserver in &zone.nameservers {
+        writeln!(contents, "nameserver {nameserver}").unwrap();
+    }
+    if !zone.dns_search.is_empty() {
+        writeln!(contents, "search {}", zone.dns_search.join(" ")).unwrap();
+    }
+    contents
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LinkProtection, SMFServicePropertyGroup};
+
+    fn zone() -> Zone {
+        knuffel::parse(
+            "zone.kdl",
+            r#"
+            brand "linked-pkg"
+            autoboot true
+            ip-type "exclusive"
+            rctl "zone.max-swap" limit=1073741824
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn interface() -> InterfacePlan {
+        InterfacePlan {
+            name: Some("net0".to_owned()),
+            physical: "web0".to_owned(),
+            lower_link: Some("cstub0".to_owned()),
+            allowed_address: vec![
+                "10.0.0.10/24".parse().unwrap(),
+                "fd00::10/64".parse().unwrap(),
+            ],
+            defrouter: vec!["10.0.0.1".parse().unwrap()],
+            ipv6: None,
+            vlan: Some(100),
+            mtu: Some(9000),
+            mac: Some(MacAddressPolicy::Random),
+            maxbw: Some("100M".parse().unwrap()),
+            protection: vec![LinkProtection::MacNospoof],
+        }
+    }
+
+    #[test]
+    fn zonecfg_script_creates_the_zone_with_its_resources() {
+        let script = zonecfg_script(Path::new("/zones/web"), &zone(), &[interface()]);
+        assert_eq!(
+            script,
+            "create -b\n\
+             set zonepath=/zones/web\n\
+             set brand=lipkg\n\
+             set autoboot=true\n\
+             set ip-type=exclusive\n\
+             add net\n\
+             set physical=web0\n\
+             set allowed-address=10.0.0.10/24,fd00::10/64\n\
+             set defrouter=10.0.0.1\n\
+             end\n\
+             add rctl\n\
+             set name=zone.max-swap\n\
+             add value (priv=privileged,limit=1073741824,action=deny)\n\
+             end\n\
+             verify\n\
+             commit\n"
+        );
+    }
+
+    #[test]
+    fn reconfigure_script_only_removes_existing_resources() {
+        let existing = Resources {
+            net: true,
+            rctl: false,
+        };
+        let script = reconfigure_script(&existing, &zone(), &[]);
+        assert!(script.starts_with("remove -F net\nset ip-type=exclusive\n"));
+        assert!(!script.contains("remove -F rctl"));
+    }
+
+    #[test]
+    fn vnics_carry_the_link_properties_of_the_interface() {
+        assert_eq!(
+            create_vnic_args(&interface(), "cstub0"),
+            [
+                "create-vnic",
+                "-l",
+                "cstub0",
+                "-v",
+                "100",
+                "-m",
+                "random",
+                "-p",
+                "maxbw=100M,mtu=9000,protection=mac-nospoof",
+                "web0",
+            ]
+        );
+    }
+
+    #[test]
+    fn zlogin_quotes_property_values_for_the_shell() {
+        let service = SMFService {
+            name: "site/app".to_owned(),
+            property_groups: vec![SMFServicePropertyGroup {
+                name: "config".to_owned(),
+                properties: [("motd".to_owned(), r#"it's "on"; reboot"#.to_owned())].into(),
+            }],
+        };
+        let args = svccfg_setprop_args(&service);
+        let command = zlogin("web", &args[0]);
+        let args: Vec<_> = command.as_std().get_args().collect();
+        assert_eq!(
+            args,
+            [
+                "web",
+                "'svccfg'",
+                "'-s'",
+                "'site/app'",
+                "'setprop'",
+                "'config/motd'",
+                "'='",
+                r#"'"it'\''s \"on\"; reboot"'"#,
+            ]
+        );
+    }
+
+    fn file(path: &str, mode: u32) -> ZoneFile {
+        ZoneFile {
+            path: PathBuf::from(path),
+            contents: "contents\n".to_owned(),
+            mode,
+        }
+    }
+
+    #[test]
+    fn files_are_written_below_the_zone_root() {
+        let root = tempfile::tempdir().unwrap();
+        write_below(root.path(), &file("root/.ssh/authorized_keys", 0o600)).unwrap();
+        let path = root.path().join("root/.ssh/authorized_keys");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "contents\n");
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(mode(&root.path().join("root/.ssh")), 0o700);
+    }
+
+    #[test]
+    fn symbolic_links_in_the_zone_root_are_not_followed() {
+        let root = tempfile::tempdir().unwrap();
+        let global = tempfile::tempdir().unwrap();
+        std::fs::write(global.path().join("shadow"), "global\n").unwrap();
+        std::os::unix::fs::symlink(global.path(), root.path().join("etc")).unwrap();
+        std::fs::create_dir(root.path().join("root")).unwrap();
+        std::os::unix::fs::symlink(
+            global.path().join("shadow"),
+            root.path().join("root/sysding.conf"),
+        )
+        .unwrap();
+
+        for path in [
+            "etc/shadow",
+            "root/sysding.conf",
+            "../shadow",
+            "/etc/shadow",
+        ] {
+            assert!(
+                matches!(
+                    write_below(root.path(), &file(path, 0o600)),
+                    Err(DriverError::UnsafePath(_))
+                ),
+                "{path}"
+            );
+        }
+        assert_eq!(
+            std::fs::read_to_string(global.path().join("shadow")).unwrap(),
+            "global\n"
+        );
+    }
+
+    #[test]
+    fn resolv_conf_is_not_written_through_a_symbolic_link() {
+        let zone_root = tempfile::tempdir().unwrap();
+        let global = tempfile::tempdir().unwrap();
+        std::fs::write(global.path().join("passwd"), "global\n").unwrap();
+        let etc = zone_root.path().join("web/root/etc");
+        std::fs::create_dir_all(&etc).unwrap();
+        std::os::unix::fs::symlink(global.path().join("passwd"), etc.join("resolv.conf")).unwrap();
+        let driver = IllumosZoneDriver::new(IllumosDriverConfig {
+            zone_root: zone_root.path().to_owned(),
+        });
+
+        assert!(matches!(
+            driver.write_resolv_conf("web", &zone()),
+            Err(DriverError::UnsafePath(_))
+        ));
+        assert_eq!(
+            std::fs::read_to_string(global.path().join("passwd")).unwrap(),
+            "global\n"
+        );
+
+        std::fs::remove_file(etc.join("resolv.conf")).unwrap();
+        driver.write_resolv_conf("web", &zone()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(etc.join("resolv.conf")).unwrap(),
+            resolv_conf_contents(&zone())
+        );
+    }
+}
@@ -0,0 +1,288 @@
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use async_trait::async_trait;
+use cloud::NodeFacts;
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+pub mod sources;
+
+pub use sources::{CpuSource, LinkSource, MemorySource, OsSource, PoolSource, ZoneSource};
+
+#[derive(Debug, Error)]
+pub enum FactError {
+    #[error("`{command}` failed: {stderr}")]
+    CommandFailed { command: String, stderr: String },
+
+    #[error("no fixture for `{0}`")]
+    MissingFixture(String),
+
+    #[error("unexpected output from {source_name}: {line}")]
+    Parse {
+        source_name: &'static str,
+        line: String,
+    },
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    IO(#[from] std::io::Error),
+}
+
+/// The host operating systems facts can be gathered on.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Platform {
+    Illumos,
+    Linux,
+}
+
+impl Platform {
+    pub fn current() -> Self {
+        if cfg!(target_os = "illumos") {
+            Platform::Illumos
+        } else {
+            Platform::Linux
+        }
+    }
+}
+
+/// How fact sources look at the host. Swapped for [`FixtureProbe`] to gather facts from
+/// recorded output.
+#[async_trait]
+pub trait Probe: Send + Sync {
+    async fn read_file(&self, path: &str) -> Result<String, FactError>;
+
+    async fn command(&self, program: &str, args: &[&str]) -> Result<String, FactError>;
+}
+
+/// Reads files and runs commands on this host.
+#[derive(Debug, Default)]
+pub struct HostProbe;
+
+#[async_trait]
+impl Probe for HostProbe {
+    async fn read_file(&self, path: &str) -> Result<String, FactError> {
+        Ok(tokio::fs::read_to_string(path).await?)
+    }
+
+    async fn command(&self, program: &str, args: &[&str]) -> Result<String, FactError> {
+        let output = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .output()
+            .await?;
+        if output.status.success() {
+            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        } else {
+            Err(FactError::CommandFailed {
+                command: format!("{program} {}", args.join(" ")),
+                stderr: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+            })
+        }
+    }
+}
+
+/// Serves files from `<dir>/files/<path>` and command output from
+/// `<dir>/commands/<program> <args>`.
+#[derive(Debug)]
+pub struct FixtureProbe {
+    dir: PathBuf,
+}
+
+impl FixtureProbe {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    async fn fixture(&self, path: &Path, name: String) -> Result<String, FactError> {
+        match tokio::fs::read_to_string(path).await {
+            Ok(contents) => Ok(contents),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Err(FactError::MissingFixture(name))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl Probe for FixtureProbe {
+    async fn read_file(&self, path: &str) -> Result<String, FactError> {
+        let fixture = self.dir.join("files").join(path.trim_start_matches('/'));
+        self.fixture(&fixture, path.to_owned()).await
+    }
+
+    async fn command(&self, program: &str, args: &[&str]) -> Result<String, FactError> {
+        let command = std::iter::once(program)
+            .chain(args.iter().copied())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let fixture = self.dir.join("commands").join(&command);
+        self.fixture(&fixture, command).await
+    }
+}
+
+/// Fills in one part of the [`NodeFacts`].
+#[async_trait]
+pub trait FactSource: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn gather(&self, probe: &dyn Probe, facts: &mut NodeFacts) -> Result<(), FactError>;
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct FactsConfig {
+    /// Gather facts from recorded output in this directory instead of the host.
+    #[serde(default)]
+    pub fixtures: Option<PathBuf>,
+    /// Defaults to the platform the nodelet was built for.
+    #[serde(default)]
+    pub platform: Option<Platform>,
+}
+
+impl FactsConfig {
+    pub fn probe(&self) -> Box<dyn Probe> {
+        match &self.fixtures {
+            Some(dir) => Box::new(FixtureProbe::new(dir)),
+            None => Box::new(HostProbe),
+        }
+    }
+
+    pub fn platform(&self) -> Platform {
+        self.platform.unwrap_or_else(Platform::current)
+    }
+}
+
+pub fn default_sources(platform: Platform) -> Vec<Box<dyn FactSource>> {
+    vec![
+        Box::new(CpuSource(platform)),
+        Box::new(MemorySource(platform)),
+        Box::new(PoolSource),
+        Box::new(LinkSource(platform)),
+        Box::new(OsSource),
+        Box::new(ZoneSource(platform)),
+    ]
+}
+
+/// Run all sources. A failing source leaves its facts empty instead of failing the rest.
+pub async fn gather(probe: &dyn Probe, sources: &[Box<dyn FactSource>]) -> NodeFacts {
+    let mut facts = NodeFacts::default();
+    for source in sources {
+        match source.gather(probe, &mut facts).await {
+            Ok(()) => debug!(source = source.name(), "gathered facts"),
+            Err(e) => warn!(source = source.name(), error = %e, "failed to gather facts"),
+        }
+    }
+    facts
+}
+
+#[cfg(test)]
+mod tests {
+    use cloud::{LinkFacts, OsFacts, PoolFacts, ZoneFacts};
+
+    use super::*;
+
+    fn fixtures(platform: &str) -> FixtureProbe {
+        FixtureProbe::new(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("../../sample_data/facts")
+                .join(platform),
+        )
+    }
+
+    #[tokio::test]
+    async fn linux_facts_are_gathered_from_fixtures() {
+        let facts = gather(&fixtures("linux"), &default_sources(Platform::Linux)).await;
+        assert_eq!(
+            facts,
+            NodeFacts {
+                cpus: Some(4),
+                memory_mb: Some(15936),
+                pools: vec![
+                    PoolFacts {
+                        name: "rpool".to_owned(),
+                        size_bytes: 499289948160,
+                        free_bytes: 402010136576,
+                    },
+                    PoolFacts {
+                        name: "tank".to_owned(),
+                        size_bytes: 3985729650688,
+                        free_bytes: 2199023255552,
+                    },
+                ],
+                links: vec![LinkFacts {
+                    name: "eth0".to_owned(),
+                    state: "up".to_owned(),
+                    speed_mbps: Some(1000),
+                    mtu: Some(1500),
+                }],
+                os: Some(OsFacts {
+                    id: "debian".to_owned(),
+                    name: "Debian GNU/Linux".to_owned(),
+                    version: Some("12".to_owned()),
+                }),
+                zones: vec![],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn illumos_facts_are_gathered_from_fixtures() {
+        let facts = gather(&fixtures("illumos"), &default_sources(Platform::Illumos)).await;
+        assert_eq!(facts.cpus, Some(2));
+        assert_eq!(facts.memory_mb, Some(8192));
+        assert_eq!(
+            facts.links,
+            [
+                LinkFacts {
+                    name: "e1000g0".to_owned(),
+                    state: "up".to_owned(),
+                    speed_mbps: Some(1000),
+                    mtu: None,
+                },
+                LinkFacts {
+                    name: "e1000g1".to_owned(),
+                    state: "down".to_owned(),
+                    speed_mbps: Some(0),
+                    mtu: None,
+                },
+            ]
+        );
+        // The global zone is not listed.
+        assert_eq!(
+            facts.zones,
+            [
+                ZoneFacts {
+                    name: "example.org-web".to_owned(),
+                    state: "running".to_owned(),
+                    brand: "lipkg".to_owned(),
+                },
+                ZoneFacts {
+                    name: "build".to_owned(),
+                    state: "installed".to_owned(),
+                    brand: "sparse".to_owned(),
+                },
+            ]
+        );
+        assert_eq!(
+            facts.os.and_then(|os| os.version),
+            Some("r151048".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn a_failing_source_leaves_the_others_alone() {
+        // The linux fixtures have no output of the illumos tools.
+        let facts = gather(&fixtures("linux"), &default_sources(Platform::Illumos)).await;
+        assert_eq!(facts.cpus, None);
+        assert_eq!(facts.memory_mb, None);
+        assert!(facts.links.is_empty());
+        assert_eq!(facts.pools.len(), 2);
+        assert_eq!(facts.os.map(|os| os.id), Some("debian".to_owned()));
+    }
+}
@@ -0,0 +1,251 @@
+use async_trait::async_trait;
+use cloud::{LinkFacts, NodeFacts, OsFacts, PoolFacts, ZoneFacts};
+use serde::Deserialize;
+
+use super::{FactError, FactSource, Platform, Probe};
+
+/// Number of processors the kernel brought online.
+#[derive(Debug)]
+pub struct CpuSource(pub Platform);
+
+#[async_trait]
+impl FactSource for CpuSource {
+    fn name(&self) -> &'static str {
+        "cpus"
+    }
+
+    async fn gather(&self, probe: &dyn Probe, facts: &mut NodeFacts) -> Result<(), FactError> {
+        let cpus = match self.0 {
+            Platform::Linux => probe
+                .read_file("/proc/cpuinfo")
+                .await?
+                .lines()
+                .filter(|line| line.starts_with("processor"))
+                .count(),
+            // One line per processor
+            Platform::Illumos => probe
+                .command("psrinfo", &[])
+                .await?
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .count(),
+        };
+        facts.cpus = Some(cpus as u32);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct MemorySource(pub Platform);
+
+#[async_trait]
+impl FactSource for MemorySource {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    async fn gather(&self, probe: &dyn Probe, facts: &mut NodeFacts) -> Result<(), FactError> {
+        facts.memory_mb = Some(match self.0 {
+            Platform::Linux => parse_meminfo(&probe.read_file("/proc/meminfo").await?)?,
+            Platform::Illumos => {
+                let output = probe.command("prtconf", &["-m"]).await?;
+                output.trim().parse().map_err(|_| FactError::Parse {
+                    source_name: "prtconf",
+                    line: output.trim().to_owned(),
+                })?
+            }
+        });
+        Ok(())
+    }
+}
+
+fn parse_meminfo(meminfo: &str) -> Result<u64, FactError> {
+    // MemTotal:       16318748 kB
+    let line = meminfo
+        .lines()
+        .find(|line| line.starts_with("MemTotal:"))
+        .ok_or_else(|| FactError::Parse {
+            source_name: "meminfo",
+            line: "no MemTotal".to_owned(),
+        })?;
+    line.split_whitespace()
+        .nth(1)
+        .and_then(|kilobytes| kilobytes.parse::<u64>().ok())
+        .map(|kilobytes| kilobytes / 1024)
+        .ok_or_else(|| FactError::Parse {
+            source_name: "meminfo",
+            line: line.to_owned(),
+        })
+}
+
+/// ZFS pools with their size and free space.
+#[derive(Debug)]
+pub struct PoolSource;
+
+#[async_trait]
+impl FactSource for PoolSource {
+    fn name(&self) -> &'static str {
+        "pools"
+    }
+
+    async fn gather(&self, probe: &dyn Probe, facts: &mut NodeFacts) -> Result<(), FactError> {
+        let output = probe
+            .command("zpool", &["list", "-Hp", "-o", "name,size,free"])
+            .await?;
+        facts.pools = output
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                let parse_error = || FactError::Parse {
+                    source_name: "zpool",
+                    line: line.to_owned(),
+                };
+                let mut fields = line.split('\t');
+                let (Some(name), Some(size), Some(free)) =
+                    (fields.next(), fields.next(), fields.next())
+                else {
+                    return Err(parse_error());
+                };
+                Ok(PoolFacts {
+                    name: name.to_owned(),
+                    size_bytes: size.parse().map_err(|_| parse_error())?,
+                    free_bytes: free.parse().map_err(|_| parse_error())?,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(())
+    }
+}
+
+/// The physical datalinks zones can be attached to.
+#[derive(Debug)]
+pub struct LinkSource(pub Platform);
+
+#[derive(Debug, Deserialize)]
+struct IpLink {
+    ifname: String,
+    operstate: String,
+    mtu: Option<u32>,
+    link_type: String,
+}
+
+#[async_trait]
+impl FactSource for LinkSource {
+    fn name(&self) -> &'static str {
+        "links"
+    }
+
+    async fn gather(&self, probe: &dyn Probe, facts: &mut NodeFacts) -> Result<(), FactError> {
+        facts.links = match self.0 {
+            Platform::Linux => {
+                let links: Vec<IpLink> =
+                    serde_json::from_str(&probe.command("ip", &["-j", "link", "show"]).await?)?;
+                let mut facts = vec![];
+                for link in links.into_iter().filter(|link| link.link_type == "ether") {
+                    // Virtual links have no speed
+                    let speed_mbps = probe
+                        .read_file(&format!("/sys/class/net/{}/speed", link.ifname))
+                        .await
+                        .ok()
+                        .and_then(|speed| speed.trim().parse().ok());
+                    facts.push(LinkFacts {
+                        name: link.ifname,
+                        state: link.operstate.to_lowercase(),
+                        speed_mbps,
+                        mtu: link.mtu,
+                    });
+                }
+                facts
+            }
+            Platform::Illumos => probe
+                .command("dladm", &["show-phys", "-p", "-o", "link,state,speed"])
+                .await?
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| {
+                    let mut fields = line.split(':');
+                    match (fields.next(), fields.next(), fields.next()) {
+                        (Some(name), Some(state), speed) => Ok(LinkFacts {
+                            name: name.to_owned(),
+                            state: state.to_owned(),
+                            speed_mbps: speed.and_then(|speed| speed.parse().ok()),
+                            mtu: None,
+                        }),
+                        _ => Err(FactError::Parse {
+                            source_name: "dladm",
+                            line: line.to_owned(),
+                        }),
+                    }
+                })
+                .collect::<Result<_, _>>()?,
+        };
+        Ok(())
+    }
+}
+
+/// Distribution and release from `/etc/os-release`.
+#[derive(Debug)]
+pub struct OsSource;
+
+#[async_trait]
+impl FactSource for OsSource {
+    fn name(&self) -> &'static str {
+        "os"
+    }
+
+    async fn gather(&self, probe: &dyn Probe, facts: &mut NodeFacts) -> Result<(), FactError> {
+        facts.os = Some(parse_os_release(&probe.read_file("/etc/os-release").await?));
+        Ok(())
+    }
+}
+
+fn parse_os_release(os_release: &str) -> OsFacts {
+    let value = |key: &str| {
+        os_release.lines().find_map(|line| {
+            line.strip_prefix(key)
+                .and_then(|rest| rest.strip_prefix('='))
+                .map(|value| value.trim().trim_matches('"').to_owned())
+        })
+    };
+    OsFacts {
+        id: value("ID").unwrap_or_else(|| "unknown".to_owned()),
+        name: value("NAME").unwrap_or_else(|| "unknown".to_owned()),
+        version: value("VERSION_ID").or_else(|| value("VERSION")),
+    }
+}
+
+/// All zones on the host, including the ones not managed by the nodelet.
+#[derive(Debug)]
+pub struct ZoneSource(pub Platform);
+
+#[async_trait]
+impl FactSource for ZoneSource {
+    fn name(&self) -> &'static str {
+        "zones"
+    }
+
+    async fn gather(&self, probe: &dyn Probe, facts: &mut NodeFacts) -> Result<(), FactError> {
+        if self.0 == Platform::Linux {
+            return Ok(());
+        }
+        // id:name:state:path:uuid:brand:ip-type
+        facts.zones = probe
+            .command("zoneadm", &["list", "-cp"])
+            .await?
+            .lines()
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.split(':').collect();
+                match fields.as_slice() {
+                    [_, "global", ..] => None,
+                    [_, name, state, _, _, brand, ..] => Some(ZoneFacts {
+                        name: name.to_string(),
+                        state: state.to_string(),
+                        brand: brand.to_string(),
+                    }),
+                    _ => None,
+                }
+            })
+            .collect();
+        Ok(())
+    }
+}
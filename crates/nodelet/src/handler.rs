@@ -0,0 +1,1176 @@
+use std::sync::Arc;
+
+use bonsaidb::core::schema::SerializedCollection;
+use bonsaidb::local::Database;
+use cloud::{
+    ChangeImpact, DeploymentEvent, DeploymentReport, DeploymentState, ResourceIdentifier,
+    ResourceKind,
+};
+use miette::Diagnostic;
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::bus::{self, NETWORK_TOPIC, SECRET_TOPIC, ZONE_TOPIC};
+use crate::diff::ZoneDiff;
+use crate::driver::{zone_name, DriverError, Operation, ZoneDriver, ZoneState};
+use crate::images::{ImageError, ImageStore};
+use crate::network::{resolve_interfaces, vnic_name, NetworkPlan};
+use crate::progress::{ProgressReporter, ReportKind};
+use crate::secrets::{NodeKey, SealedSecret, SecretError, SecretValue};
+use crate::sysconfig;
+use crate::{
+    Checkpoint, DeploymentStatus, HookFailure, HookStage, HookTarget, Network, NodeEntry,
+    NodeObject, PendingOperation, SignedEvent, Zone,
+};
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum HandlerError {
+    #[error(transparent)]
+    BonsaidbCore(#[from] bonsaidb::core::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Driver(#[from] DriverError),
+
+    #[error("Unsupported routing key received")]
+    UnsupportedRoutingKey,
+
+    #[error("{stage} hook failed")]
+    Hook {
+        stage: HookStage,
+        #[source]
+        source: DriverError,
+    },
+
+    #[error("{stage} hook would run on the node, which its operator does not allow")]
+    GlobalHook { stage: HookStage },
+
+    #[error("secret {0} has not been sent to this node")]
+    MissingSecret(ResourceIdentifier),
+
+    #[error("cannot open secret {secret}")]
+    Secret {
+        secret: ResourceIdentifier,
+        #[source]
+        source: SecretError,
+    },
+
+    #[error(transparent)]
+    Image(#[from] ImageError),
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct HookConfig {
+    /// Run hooks with `run-in "global"` on the node. Zone specs come from the tenants,
+    /// a global hook runs their command as the nodelet's user.
+    #[serde(default)]
+    pub allow_global: bool,
+}
+
+/// Turns deployment events into changes on this node.
+pub struct Handler {
+    nodedb: Database,
+    driver: Arc<dyn ZoneDriver>,
+    node_key: NodeKey,
+    images: ImageStore,
+    reports: UnboundedSender<DeploymentReport>,
+    hooks: HookConfig,
+}
+
+impl Handler {
+    pub fn new(
+        nodedb: Database,
+        driver: Arc<dyn ZoneDriver>,
+        node_key: NodeKey,
+        images: ImageStore,
+        reports: UnboundedSender<DeploymentReport>,
+        hooks: HookConfig,
+    ) -> Self {
+        Self {
+            nodedb,
+            driver,
+            node_key,
+            images,
+            reports,
+            hooks,
+        }
+    }
+
+    #[instrument(skip(self, payload))]
+    pub async fn handle(
+        &self,
+        routing_key: &str,
+        payload: &[u8],
+    ) -> Result<DeploymentReport, HandlerError> {
+        // Published for this node only, as `<topic>.<node>`.
+        match bus::topic(routing_key) {
+            ZONE_TOPIC => {
+                let zone_deployment: DeploymentEvent<Zone> = serde_json::from_slice(payload)?;
+                let report = match zone_deployment {
+                    DeploymentEvent::Ensure { data, identifier } => {
+                        self.ensure_zone(identifier, data).await?
+                    }
+                    DeploymentEvent::Remove { identifier, .. } => {
+                        self.remove_zone(identifier).await?
+                    }
+                    DeploymentEvent::List { tenant, kind, .. } => self.list(tenant, kind)?,
+                };
+                Ok(report)
+            }
+            NETWORK_TOPIC => {
+                let network_deployment: DeploymentEvent<Network> = serde_json::from_slice(payload)?;
+                let report = match network_deployment {
+                    DeploymentEvent::List { tenant, kind, .. } => self.list(tenant, kind)?,
+                    DeploymentEvent::Ensure { data, identifier } => {
+                        self.ensure_network(identifier, data).await?
+                    }
+                    DeploymentEvent::Remove { identifier, .. } => self.archive(identifier)?,
+                };
+                Ok(report)
+            }
+            SECRET_TOPIC => {
+                let secret_deployment: DeploymentEvent<SealedSecret> =
+                    serde_json::from_slice(payload)?;
+                let report = match secret_deployment {
+                    DeploymentEvent::List { tenant, kind, .. } => self.list(tenant, kind)?,
+                    DeploymentEvent::Ensure { data, identifier } => {
+                        self.ensure_secret(identifier, data)?
+                    }
+                    DeploymentEvent::Remove { identifier, .. } => self.archive(identifier)?,
+                };
+                Ok(report)
+            }
+            _ => Err(HandlerError::UnsupportedRoutingKey),
+        }
+    }
+
+    async fn ensure_zone(
+        &self,
+        identifier: ResourceIdentifier,
+        zone: Zone,
+    ) -> Result<DeploymentReport, HandlerError> {
+        let interfaces = self
+            .check_hooks(&zone)
+            .map_err(|e| e.to_string())
+            .and_then(|()| {
+                resolve_interfaces(&self.nodedb, &identifier, &zone).map_err(|e| e.to_string())
+            });
+        let interfaces = match interfaces {
+            Ok(interfaces) => interfaces,
+            Err(e) => {
+                error!(error = %e, %identifier, "refusing zone");
+                // Nothing was stored or set up, the zone stays refused until it is sent
+                // again with networks and hooks this node takes.
+                return Ok(DeploymentReport::Ensure {
+                    identifier,
+                    state: DeploymentState::Failed,
+                    result: Some(Err(format!("refusing zone: {e}"))),
+                    changes: vec![],
+                    progress: None,
+                });
+            }
+        };
+        debug!(?interfaces, "resolved zone interfaces");
+
+        let name = zone_name(&identifier);
+        let mut state = self.driver.state(&name).await?;
+        let deployed = self.deployed_zone(&identifier)?;
+
+        // The reconciler leaves configured entries alone until the zone is started. An
+        // update keeps the deployed spec until it is done, so a retry diffs it again.
+        let diff = match &deployed {
+            Some((deployed_identifier, deployed_zone)) => {
+                self.store(
+                    deployed_identifier,
+                    NodeObject::Zone(Box::new(deployed_zone.clone())),
+                    DeploymentStatus::Configured,
+                )?;
+                match state {
+                    ZoneState::Missing => ZoneDiff::default(),
+                    _ => ZoneDiff::new(deployed_zone, &zone),
+                }
+            }
+            None => {
+                self.store(
+                    &identifier,
+                    NodeObject::Zone(Box::new(zone.clone())),
+                    DeploymentStatus::Configured,
+                )?;
+                ZoneDiff::default()
+            }
+        };
+        let progress = Tracker {
+            nodedb: &self.nodedb,
+            progress: ProgressReporter::new(
+                identifier.clone(),
+                ReportKind::Ensure,
+                self.reports.clone(),
+            ),
+            entry: deployed
+                .as_ref()
+                .map_or_else(|| identifier.clone(), |(deployed, _)| deployed.clone()),
+            operation: PendingOperation::Ensure {
+                identifier: identifier.clone(),
+                zone: Box::new(zone.clone()),
+            },
+        };
+        // Hooks for taking the zone down are those of the spec it was set up with.
+        let current = deployed.as_ref().map_or(&zone, |(_, zone)| zone);
+        for change in &diff.changes {
+            info!(
+                zone = name,
+                field = change.field,
+                impact = ?change.impact,
+                "{}",
+                change.description
+            );
+        }
+
+        // Nothing of an incomplete install is worth keeping. Cleaning it up first lets each
+        // impact go on from a configured zone, so a reconfigure is not skipped for it.
+        if state == ZoneState::Incomplete {
+            info!(zone = name, "cleaning up incomplete install");
+            progress.step(DeploymentState::Uninstalling, "uninstall", Some(5))?;
+            self.driver.uninstall(&name).await?;
+            state = ZoneState::Configured;
+        }
+        match diff.impact() {
+            Some(ChangeImpact::Reinstall) => {
+                if !matches!(state, ZoneState::Configured | ZoneState::Missing) {
+                    self.run_hooks(
+                        &name,
+                        current,
+                        HookStage::PreUninstall,
+                        &progress,
+                        DeploymentState::Stopping,
+                    )
+                    .await?;
+                }
+                if matches!(
+                    state,
+                    ZoneState::Running | ZoneState::Ready | ZoneState::Down
+                ) {
+                    self.run_hooks(
+                        &name,
+                        current,
+                        HookStage::PreHalt,
+                        &progress,
+                        DeploymentState::Stopping,
+                    )
+                    .await?;
+                    progress.step(DeploymentState::Stopping, "halt", Some(0))?;
+                    self.driver.halt(&name).await?;
+                    state = ZoneState::Installed;
+                }
+                if state == ZoneState::Installed {
+                    progress.step(DeploymentState::Uninstalling, "uninstall", Some(5))?;
+                    self.driver.uninstall(&name).await?;
+                    state = ZoneState::Configured;
+                }
+                if state == ZoneState::Configured {
+                    progress.step(DeploymentState::Uninstalling, "delete", Some(10))?;
+                    self.driver.delete(&name).await?;
+                    state = ZoneState::Missing;
+                }
+            }
+            Some(ChangeImpact::Reboot) => {
+                if matches!(
+                    state,
+                    ZoneState::Running | ZoneState::Ready | ZoneState::Down
+                ) {
+                    self.run_hooks(
+                        &name,
+                        current,
+                        HookStage::PreHalt,
+                        &progress,
+                        DeploymentState::Stopping,
+                    )
+                    .await?;
+                    progress.step(DeploymentState::Stopping, "halt", Some(0))?;
+                    self.driver.halt(&name).await?;
+                    state = ZoneState::Installed;
+                }
+                if matches!(state, ZoneState::Installed | ZoneState::Configured) {
+                    progress.step(DeploymentState::Stopped, "reconfigure", Some(10))?;
+                    self.driver.reconfigure(&name, &zone, &interfaces).await?;
+                }
+            }
+            Some(ChangeImpact::Live) | None => {}
+        }
+
+        if state == ZoneState::Missing {
+            progress.step(DeploymentState::Configured, "configure", Some(15))?;
+            self.driver.configure(&name, &zone, &interfaces).await?;
+            state = ZoneState::Configured;
+        }
+        if state == ZoneState::Configured {
+            self.run_hooks(
+                &name,
+                &zone,
+                HookStage::PreInstall,
+                &progress,
+                DeploymentState::Configured,
+            )
+            .await?;
+            let image = match &zone.image {
+                Some(image) => {
+                    progress.step(DeploymentState::Configured, "image", Some(18))?;
+                    Some(self.images.fetch(image, &|line| progress.log(line)).await?)
+                }
+                None => None,
+            };
+            // Package installation takes most of the time, its output goes into the log.
+            progress.step(DeploymentState::Configured, "install", Some(20))?;
+            self.driver
+                .install(&name, &zone, image.as_deref(), &|line| progress.log(line))
+                .await?;
+            state = ZoneState::Installed;
+            if let Some(config) = &zone.system_config {
+                progress.step(DeploymentState::Installed, "system-config", Some(75))?;
+                self.driver
+                    .write_files(&name, &sysconfig::render(&name, config))
+                    .await?;
+            }
+            self.run_hooks(
+                &name,
+                &zone,
+                HookStage::PostInstall,
+                &progress,
+                DeploymentState::Installed,
+            )
+            .await?;
+        }
+        let booted = match state {
+            ZoneState::Installed | ZoneState::Ready => {
+                progress.step(DeploymentState::Starting, "boot", Some(80))?;
+                self.driver.boot(&name).await?;
+                true
+            }
+            ZoneState::Running => false,
+            state => {
+                return Err(DriverError::InvalidState {
+                    zone: name,
+                    operation: Operation::Boot,
+                    state,
+                }
+                .into())
+            }
+        };
+        // Rewritten on every ensure, the secrets may have changed since.
+        self.write_secrets(&name, &zone, &progress).await?;
+        if booted {
+            self.run_hooks(
+                &name,
+                &zone,
+                HookStage::PostBoot,
+                &progress,
+                DeploymentState::Started,
+            )
+            .await?;
+        }
+        // A reinstall already picked up everything else.
+        if diff.impact() != Some(ChangeImpact::Reinstall) && diff.has(ChangeImpact::Live) {
+            progress.step(DeploymentState::Started, "update", Some(90))?;
+            self.driver.update(&name, &zone).await?;
+        }
+        info!(zone = name, "zone running");
+
+        self.store(
+            &identifier,
+            NodeObject::Zone(Box::new(zone)),
+            DeploymentStatus::Started,
+        )?;
+        if let Some((deployed_identifier, _)) = deployed {
+            if deployed_identifier != identifier {
+                self.tombstone(&deployed_identifier)?;
+            }
+        }
+
+        Ok(DeploymentReport::Ensure {
+            identifier,
+            state: DeploymentState::Started,
+            result: Some(Ok(())),
+            changes: diff.changes,
+            progress: None,
+        })
+    }
+
+    /// The zone currently deployed for any version of the resource.
+    fn deployed_zone(
+        &self,
+        identifier: &ResourceIdentifier,
+    ) -> Result<Option<(ResourceIdentifier, Zone)>, HandlerError> {
+        let deployed = NodeEntry::all(&self.nodedb)
+            .query()?
+            .into_iter()
+            .map(|doc| doc.contents)
+            .filter(|entry| {
+                entry.resource_identifier.tenant == identifier.tenant
+                    && entry.resource_identifier.name == identifier.name
+                    && !matches!(entry.state, DeploymentStatus::Archived)
+            })
+            .filter_map(|entry| match entry.object {
+                NodeObject::Zone(zone) => Some((entry.resource_identifier, *zone)),
+                _ => None,
+            })
+            .max_by(|(a, _), (b, _)| a.cmp_version(b));
+        Ok(deployed)
+    }
+
+    /// Tear a zone down step by step, so a retry picks up where a failed removal stopped.
+    async fn remove_zone(
+        &self,
+        identifier: ResourceIdentifier,
+    ) -> Result<DeploymentReport, HandlerError> {
+        // Versions of a resource share the zone, only the deployed one may tear it down.
+        let zone = match self.deployed_zone(&identifier)? {
+            Some((deployed, zone)) if deployed == identifier => Some(zone),
+            Some((deployed, _)) => {
+                debug!(%deployed, "zone belongs to another version");
+                return self.archive(identifier);
+            }
+            None => None,
+        };
+        if let Some(zone) = &zone {
+            self.store(
+                &identifier,
+                NodeObject::Zone(Box::new(zone.clone())),
+                DeploymentStatus::Uninstalling,
+            )?;
+        }
+
+        let progress = Tracker {
+            nodedb: &self.nodedb,
+            progress: ProgressReporter::new(
+                identifier.clone(),
+                ReportKind::Remove,
+                self.reports.clone(),
+            ),
+            entry: identifier.clone(),
+            operation: PendingOperation::Remove,
+        };
+        let name = zone_name(&identifier);
+        let mut state = self.driver.state(&name).await?;
+        if let Some(zone) = &zone {
+            if !matches!(state, ZoneState::Configured | ZoneState::Missing) {
+                self.run_hooks(
+                    &name,
+                    zone,
+                    HookStage::PreUninstall,
+                    &progress,
+                    DeploymentState::Stopping,
+                )
+                .await?;
+            }
+        }
+        if matches!(
+            state,
+            ZoneState::Running | ZoneState::Ready | ZoneState::Down
+        ) {
+            if let Some(zone) = &zone {
+                self.run_hooks(
+                    &name,
+                    zone,
+                    HookStage::PreHalt,
+                    &progress,
+                    DeploymentState::Stopping,
+                )
+                .await?;
+            }
+            progress.step(DeploymentState::Stopping, "halt", Some(0))?;
+            self.driver.halt(&name).await?;
+            state = ZoneState::Installed;
+        }
+        if matches!(state, ZoneState::Installed | ZoneState::Incomplete) {
+            progress.step(DeploymentState::Uninstalling, "uninstall", Some(30))?;
+            self.driver.uninstall(&name).await?;
+            state = ZoneState::Configured;
+        }
+        match state {
+            ZoneState::Configured => {
+                progress.step(DeploymentState::Uninstalling, "delete", Some(70))?;
+                self.driver.delete(&name).await?;
+                info!(zone = name, "zone deleted");
+            }
+            ZoneState::Missing => debug!(zone = name, "zone already gone"),
+            state => {
+                return Err(DriverError::InvalidState {
+                    zone: name,
+                    operation: Operation::Delete,
+                    state,
+                }
+                .into())
+            }
+        }
+
+        // Addresses are bound to the VNICs, releasing those frees them for other zones.
+        let links: Vec<String> = zone
+            .iter()
+            .flat_map(|zone| zone.network.iter().enumerate())
+            .filter(|(_, interface)| interface.network.is_some())
+            .map(|(index, _)| vnic_name(&identifier, index))
+            .collect();
+        progress.step(DeploymentState::Uninstalling, "release", Some(90))?;
+        self.driver.release(&name, &links).await?;
+        debug!(?links, "released zone links");
+
+        self.archive(identifier)
+    }
+
+    /// Run the hooks of `zone` for `stage`, each as a step of its own.
+    /// Hooks of the zone which would run on the node, unless its operator allows that.
+    fn check_hooks(&self, zone: &Zone) -> Result<(), HandlerError> {
+        match zone
+            .hooks
+            .iter()
+            .find(|hook| hook.run_in == HookTarget::Global)
+        {
+            Some(hook) if !self.hooks.allow_global => {
+                Err(HandlerError::GlobalHook { stage: hook.stage })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn run_hooks(
+        &self,
+        name: &str,
+        zone: &Zone,
+        stage: HookStage,
+        progress: &Tracker<'_>,
+        state: DeploymentState,
+    ) -> Result<(), HandlerError> {
+        for hook in zone.hooks.iter().filter(|hook| hook.stage == stage) {
+            // Entries stored before the operator took the permission back still have them.
+            if hook.run_in == HookTarget::Global && !self.hooks.allow_global {
+                return Err(HandlerError::GlobalHook { stage });
+            }
+            progress.step(state.clone(), &format!("{stage} hook"), None)?;
+            let result = self
+                .driver
+                .run_hook(name, hook, &|line| progress.log(line))
+                .await;
+            if let Err(e) = result {
+                match hook.on_failure {
+                    HookFailure::Abort => {
+                        progress.flush();
+                        return Err(HandlerError::Hook { stage, source: e });
+                    }
+                    HookFailure::Ignore => {
+                        warn!(zone = name, %stage, error = %e, "ignoring failed hook");
+                        progress.log(&format!("ignoring failed {stage} hook: {e}"));
+                    }
+                }
+            }
+            // The output of the last hook would otherwise wait for the next step.
+            progress.flush();
+        }
+        Ok(())
+    }
+
+    /// Open the secrets of `zone` and write them into the running zone.
+    async fn write_secrets(
+        &self,
+        name: &str,
+        zone: &Zone,
+        progress: &Tracker<'_>,
+    ) -> Result<(), HandlerError> {
+        if zone.secrets.is_empty() {
+            return Ok(());
+        }
+        progress.step(DeploymentState::Starting, "secrets", Some(85))?;
+        for mount in &zone.secrets {
+            let value = self.secret(&mount.secret)?;
+            self.driver.write_secret(name, mount, &value).await?;
+            debug!(zone = name, secret = %mount.secret, path = %mount.path.display(), "wrote secret");
+        }
+        Ok(())
+    }
+
+    /// The value of a secret cloudadmd sent to this node.
+    fn secret(&self, identifier: &ResourceIdentifier) -> Result<SecretValue, HandlerError> {
+        let missing = || HandlerError::MissingSecret(identifier.clone());
+        let entry = NodeEntry::get(identifier, &self.nodedb)?
+            .ok_or_else(missing)?
+            .contents;
+        let NodeObject::Secret(sealed) = entry.object else {
+            return Err(missing());
+        };
+        if entry.state == DeploymentStatus::Archived {
+            return Err(missing());
+        }
+        self.node_key
+            .open(&sealed)
+            .map_err(|source| HandlerError::Secret {
+                secret: identifier.clone(),
+                source,
+            })
+    }
+
+    /// Pick up the operations a previous run was interrupted in. Ensures carry on towards
+    /// the spec they were applying and removals are finished. The unacknowledged event is
+    /// redelivered afterwards and finds nothing left to do.
+    pub async fn resume(&self) -> Result<(), HandlerError> {
+        let interrupted: Vec<(ResourceIdentifier, Checkpoint)> = NodeEntry::all(&self.nodedb)
+            .query()?
+            .into_iter()
+            .filter_map(|doc| {
+                let entry = doc.contents;
+                entry
+                    .checkpoint
+                    .map(|checkpoint| (entry.resource_identifier, checkpoint))
+            })
+            .collect();
+        for (entry, checkpoint) in interrupted {
+            info!(%entry, step = checkpoint.step, "resuming interrupted operation");
+            let result = match checkpoint.operation {
+                PendingOperation::Ensure { identifier, zone } => {
+                    self.ensure_zone(identifier, *zone).await
+                }
+                PendingOperation::Remove => self.remove_zone(entry.clone()).await,
+            };
+            match result {
+                Ok(report) => {
+                    if self.reports.send(report).is_err() {
+                        warn!("report channel closed");
+                    }
+                }
+                // Left to the redelivered event and the reconciler, resuming it again on
+                // every restart would not help.
+                Err(e) => error!(%entry, error = %e, "failed to resume operation"),
+            }
+            self.clear_checkpoint(&entry)?;
+        }
+        Ok(())
+    }
+
+    fn clear_checkpoint(&self, identifier: &ResourceIdentifier) -> Result<(), HandlerError> {
+        if let Some(mut doc) = NodeEntry::get(identifier, &self.nodedb)? {
+            doc.contents.checkpoint = None;
+            doc.update(&self.nodedb)?;
+        }
+        Ok(())
+    }
+
+    async fn ensure_network(
+        &self,
+        identifier: ResourceIdentifier,
+        network: Network,
+    ) -> Result<DeploymentReport, HandlerError> {
+        let plan = NetworkPlan::new(&network);
+        debug!(?plan, "planned network links");
+        // Before the network is stored, zones must not create VNICs on missing etherstubs.
+        for etherstub in &plan.etherstubs {
+            self.driver.ensure_etherstub(etherstub).await?;
+        }
+        // Zones resolve their interfaces against the networks stored here.
+        self.store(
+            &identifier,
+            NodeObject::Network(network),
+            DeploymentStatus::Configured,
+        )?;
+        Ok(DeploymentReport::Ensure {
+            identifier,
+            state: DeploymentState::Configured,
+            result: Some(Ok(())),
+            changes: vec![],
+            progress: None,
+        })
+    }
+
+    /// Keep a secret sealed as it came, it is only opened to be written into a zone.
+    fn ensure_secret(
+        &self,
+        identifier: ResourceIdentifier,
+        secret: SealedSecret,
+    ) -> Result<DeploymentReport, HandlerError> {
+        // Refused right away, the zones using it would only fail later on.
+        if let Err(e) = self.node_key.open(&secret) {
+            error!(error = %e, %identifier, "refusing secret");
+            return Ok(DeploymentReport::Ensure {
+                identifier,
+                state: DeploymentState::Configured,
+                result: Some(Err(format!("refusing secret: {e}"))),
+                changes: vec![],
+                progress: None,
+            });
+        }
+        self.store(
+            &identifier,
+            NodeObject::Secret(secret),
+            DeploymentStatus::Configured,
+        )?;
+        info!(%identifier, "stored secret");
+        Ok(DeploymentReport::Ensure {
+            identifier,
+            state: DeploymentState::Configured,
+            result: Some(Ok(())),
+            changes: vec![],
+            progress: None,
+        })
+    }
+
+    /// The newest signed event taken for a version of the resource `tenant`/`name`.
+    pub fn last_event(
+        &self,
+        tenant: &Option<String>,
+        name: &str,
+    ) -> Result<Option<SignedEvent>, HandlerError> {
+        Ok(NodeEntry::all(&self.nodedb)
+            .query()?
+            .into_iter()
+            .map(|doc| doc.contents)
+            .filter(|entry| {
+                entry.resource_identifier.tenant == *tenant
+                    && entry.resource_identifier.name == name
+            })
+            .filter_map(|entry| entry.last_event)
+            .max_by_key(|event| event.signed_at))
+    }
+
+    /// Remember `event` as the newest one taken for `identifier`, once it has an entry.
+    pub fn record_event(
+        &self,
+        identifier: &ResourceIdentifier,
+        event: SignedEvent,
+    ) -> Result<(), HandlerError> {
+        if let Some(mut doc) = NodeEntry::get(identifier, &self.nodedb)? {
+            if doc.contents.last_event != Some(event) {
+                doc.contents.last_event = Some(event);
+                doc.update(&self.nodedb)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn store(
+        &self,
+        identifier: &ResourceIdentifier,
+        object: NodeObject,
+        state: DeploymentStatus,
+    ) -> Result<(), HandlerError> {
+        let mut entry = match NodeEntry::get(identifier, &self.nodedb)? {
+            Some(doc) => NodeEntry {
+                object,
+                checkpoint: None,
+                ..doc.contents
+            },
+            None => NodeEntry {
+                resource_identifier: identifier.clone(),
+                object,
+                state: state.clone(),
+                checkpoint: None,
+                history: vec![],
+                last_event: None,
+            },
+        };
+        entry.set_state(state);
+        entry
+            .overwrite_into(identifier, &self.nodedb)
+            .map_err(|e| e.error)?;
+        Ok(())
+    }
+
+    /// Keep a tombstone of removed resources so the node still knows what it managed.
+    fn tombstone(&self, identifier: &ResourceIdentifier) -> Result<(), HandlerError> {
+        match NodeEntry::get(identifier, &self.nodedb)? {
+            Some(mut doc) => {
+                doc.contents.set_state(DeploymentStatus::Archived);
+                doc.contents.checkpoint = None;
+                doc.update(&self.nodedb)?;
+                info!(%identifier, "archived entry");
+            }
+            None => debug!(%identifier, "nothing to remove"),
+        }
+        Ok(())
+    }
+
+    fn archive(&self, identifier: ResourceIdentifier) -> Result<DeploymentReport, HandlerError> {
+        self.tombstone(&identifier)?;
+        Ok(DeploymentReport::Remove {
+            identifier,
+            state: DeploymentState::Archived,
+            result: Some(Ok(())),
+            progress: None,
+        })
+    }
+
+    fn list(
+        &self,
+        tenant: Option<String>,
+        kind: Option<ResourceKind>,
+    ) -> Result<DeploymentReport, HandlerError> {
+        let resources = NodeEntry::all(&self.nodedb)
+            .query()?
+            .into_iter()
+            .map(|doc| doc.contents)
+            .filter(|entry| {
+                tenant.is_none() || entry.resource_identifier.tenant.as_ref() == tenant.as_ref()
+            })
+            .filter(|entry| kind.is_none() || Some(entry.object.kind()) == kind)
+            .map(|entry| {
+                let state = DeploymentState::from(&entry.state);
+                (entry.resource_identifier, state)
+            })
+            .collect();
+        Ok(DeploymentReport::List { resources })
+    }
+}
+
+/// Reports the steps of an operation and checkpoints them in the entry it works on.
+struct Tracker<'a> {
+    nodedb: &'a Database,
+    progress: ProgressReporter,
+    entry: ResourceIdentifier,
+    operation: PendingOperation,
+}
+
+impl Tracker<'_> {
+    fn step(
+        &self,
+        state: DeploymentState,
+        step: &str,
+        percent: Option<u8>,
+    ) -> Result<(), HandlerError> {
+        self.progress.step(state, step, percent);
+        if let Some(mut doc) = NodeEntry::get(&self.entry, self.nodedb)? {
+            doc.contents.checkpoint = Some(Checkpoint {
+                operation: self.operation.clone(),
+                step: step.to_owned(),
+            });
+            doc.update(self.nodedb)?;
+        }
+        Ok(())
+    }
+
+    fn log(&self, line: &str) {
+        self.progress.log(line);
+    }
+
+    fn flush(&self) {
+        self.progress.flush();
+    }
+}
+
+/// The report telling the controller that the node gave up on an event.
+pub fn failure_report(routing_key: &str, payload: &[u8], error: &str) -> Option<DeploymentReport> {
+    // The payload failed to decode for its kind already, so only look at the envelope.
+    let event: DeploymentEvent<serde_json::Value> = serde_json::from_slice(payload).ok()?;
+    let result = Some(Err(format!("giving up on {routing_key} event: {error}")));
+    match event {
+        DeploymentEvent::Ensure { identifier, .. } => Some(DeploymentReport::Ensure {
+            identifier,
+            state: DeploymentState::Failed,
+            result,
+            changes: vec![],
+            progress: None,
+        }),
+        DeploymentEvent::Remove { identifier, .. } => Some(DeploymentReport::Remove {
+            identifier,
+            state: DeploymentState::Failed,
+            result,
+            progress: None,
+        }),
+        DeploymentEvent::List { .. } => None,
+    }
+}
+
+/// The report telling the controller that the node refused an event it could not verify.
+pub fn rejection_report(routing_key: &str, payload: &[u8], reason: &str) -> DeploymentReport {
+    let event: Option<DeploymentEvent<serde_json::Value>> = serde_json::from_slice(payload).ok();
+    let identifier = match event {
+        Some(
+            DeploymentEvent::Ensure { identifier, .. } | DeploymentEvent::Remove { identifier, .. },
+        ) => Some(identifier),
+        _ => None,
+    };
+    DeploymentReport::Rejected {
+        routing_key: routing_key.to_owned(),
+        identifier,
+        reason: reason.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{fixture, identifier, zone};
+
+    fn lan() -> ResourceIdentifier {
+        identifier("res://example.org/lan@1.0.0")
+    }
+
+    fn ensure_lan() -> Vec<u8> {
+        let network: Network = knuffel::parse(
+            "lan.kdl",
+            r#"
+            tenant "example.org"
+            name "lan"
+            (local)vswitch "internal" {
+                allowed-range "10.0.0.0/24"
+                mtu 9000
+            }
+            "#,
+        )
+        .unwrap();
+        serde_json::to_vec(&DeploymentEvent::Ensure {
+            data: network,
+            identifier: lan(),
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn ensuring_a_network_creates_the_etherstubs_of_its_switches() {
+        let fixture = fixture();
+        fixture
+            .handler
+            .handle("networks", &ensure_lan())
+            .await
+            .unwrap();
+
+        let etherstubs = fixture.driver.etherstubs();
+        assert_eq!(etherstubs.len(), 1);
+        assert_eq!(etherstubs[0].switch, "internal");
+        assert_eq!(etherstubs[0].mtu, Some(9000));
+        assert!(NodeEntry::get(&lan(), &fixture.nodedb).unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn networks_without_etherstubs_are_not_stored() {
+        let fixture = fixture();
+        fixture
+            .driver
+            .inject_failure(Operation::EnsureEtherstub, Some(1));
+        assert!(fixture
+            .handler
+            .handle("networks", &ensure_lan())
+            .await
+            .is_err());
+        assert!(NodeEntry::get(&lan(), &fixture.nodedb).unwrap().is_none());
+
+        fixture
+            .handler
+            .handle("networks", &ensure_lan())
+            .await
+            .unwrap();
+        assert_eq!(fixture.driver.etherstubs().len(), 1);
+    }
+
+    fn web(version: &str) -> ResourceIdentifier {
+        identifier(&format!("res://example.org/web@{version}"))
+    }
+
+    fn ensure_web(version: &str) -> Vec<u8> {
+        serde_json::to_vec(&DeploymentEvent::Ensure {
+            data: zone(""),
+            identifier: web(version),
+        })
+        .unwrap()
+    }
+
+    fn entry(nodedb: &Database, identifier: &ResourceIdentifier) -> NodeEntry {
+        NodeEntry::get(identifier, nodedb)
+            .unwrap()
+            .unwrap()
+            .contents
+    }
+
+    #[tokio::test]
+    async fn ensuring_a_zone_boots_it() {
+        let mut fixture = fixture();
+        let report = fixture
+            .handler
+            .handle("zones", &ensure_web("1.0.0"))
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            report,
+            DeploymentReport::Ensure {
+                state: DeploymentState::Started,
+                result: Some(Ok(())),
+                ..
+            }
+        ));
+        let zone = fixture.driver.zone("example.org-web").unwrap();
+        assert_eq!(zone.state, ZoneState::Running);
+        let entry = entry(&fixture.nodedb, &web("1.0.0"));
+        assert!(matches!(entry.state, DeploymentStatus::Started));
+        assert!(entry.checkpoint.is_none());
+
+        let mut steps = vec![];
+        while let Ok(report) = fixture.reports.try_recv() {
+            if let DeploymentReport::Ensure {
+                progress: Some(progress),
+                ..
+            } = report
+            {
+                steps.push(progress.step);
+            }
+        }
+        assert_eq!(steps, ["configure", "install", "boot"]);
+    }
+
+    #[tokio::test]
+    async fn a_failed_step_is_checkpointed_and_retried() {
+        let fixture = fixture();
+        fixture.driver.inject_failure(Operation::Install, Some(1));
+        assert!(fixture
+            .handler
+            .handle("zones", &ensure_web("1.0.0"))
+            .await
+            .is_err());
+        let checkpoint = entry(&fixture.nodedb, &web("1.0.0")).checkpoint.unwrap();
+        assert_eq!(checkpoint.step, "install");
+        assert_eq!(
+            fixture.driver.zone("example.org-web").unwrap().state,
+            ZoneState::Configured
+        );
+
+        fixture
+            .handler
+            .handle("zones", &ensure_web("1.0.0"))
+            .await
+            .unwrap();
+        assert_eq!(
+            fixture.driver.zone("example.org-web").unwrap().state,
+            ZoneState::Running
+        );
+        assert!(entry(&fixture.nodedb, &web("1.0.0")).checkpoint.is_none());
+    }
+
+    #[tokio::test]
+    async fn a_new_version_takes_over_the_zone() {
+        let fixture = fixture();
+        for version in ["1.0.0", "1.1.0"] {
+            fixture
+                .handler
+                .handle("zones", &ensure_web(version))
+                .await
+                .unwrap();
+        }
+
+        assert!(matches!(
+            entry(&fixture.nodedb, &web("1.0.0")).state,
+            DeploymentStatus::Archived
+        ));
+        assert!(matches!(
+            entry(&fixture.nodedb, &web("1.1.0")).state,
+            DeploymentStatus::Started
+        ));
+        assert_eq!(
+            fixture.driver.zone("example.org-web").unwrap().state,
+            ZoneState::Running
+        );
+    }
+
+    #[tokio::test]
+    async fn an_incomplete_zone_is_reconfigured_before_it_is_installed_again() {
+        let fixture = fixture();
+        fixture
+            .handler
+            .handle("zones", &ensure_web("1.0.0"))
+            .await
+            .unwrap();
+        fixture
+            .driver
+            .set_state("example.org-web", ZoneState::Incomplete);
+
+        let limited = serde_json::to_vec(&DeploymentEvent::Ensure {
+            data: zone(r#"rctl "zone.max-lwps" limit=2000"#),
+            identifier: web("1.1.0"),
+        })
+        .unwrap();
+        fixture.handler.handle("zones", &limited).await.unwrap();
+
+        let zone = fixture.driver.zone("example.org-web").unwrap();
+        assert_eq!(zone.state, ZoneState::Running);
+        assert_eq!(zone.zone.rctls.len(), 1);
+        assert_eq!(zone.zone.rctls[0].name, "zone.max-lwps");
+    }
+
+    #[tokio::test]
+    async fn hooks_run_in_the_zone_unless_the_operator_allows_global_ones() {
+        let fixture = fixture();
+        let hooked = |hook: &str| {
+            serde_json::to_vec(&DeploymentEvent::Ensure {
+                data: zone(&format!(
+                    r#"hook "post-install" {{ command "true"; {hook} }}"#
+                )),
+                identifier: web("1.0.0"),
+            })
+            .unwrap()
+        };
+
+        let report = fixture
+            .handler
+            .handle("zones", &hooked(r#"run-in "global";"#))
+            .await
+            .unwrap();
+        assert!(matches!(
+            report,
+            DeploymentReport::Ensure {
+                state: DeploymentState::Failed,
+                ..
+            }
+        ));
+        assert!(fixture.driver.zone("example.org-web").is_none());
+        assert!(NodeEntry::get(&web("1.0.0"), &fixture.nodedb)
+            .unwrap()
+            .is_none());
+
+        assert_eq!(
+            zone(r#"hook "post-install" { command "true"; }"#).hooks[0].run_in,
+            HookTarget::Zone
+        );
+        fixture.handler.handle("zones", &hooked("")).await.unwrap();
+        assert_eq!(
+            fixture.driver.hooks_run(),
+            [("example.org-web".to_owned(), HookStage::PostInstall)]
+        );
+    }
+
+    #[tokio::test]
+    async fn removing_a_zone_deletes_it() {
+        let fixture = fixture();
+        fixture
+            .handler
+            .handle("zones", &ensure_web("1.0.0"))
+            .await
+            .unwrap();
+        let remove = serde_json::to_vec(&DeploymentEvent::Remove {
+            data: zone(""),
+            identifier: web("1.0.0"),
+        })
+        .unwrap();
+        let report = fixture.handler.handle("zones", &remove).await.unwrap();
+
+        assert!(matches!(
+            report,
+            DeploymentReport::Remove {
+                state: DeploymentState::Archived,
+                result: Some(Ok(())),
+                ..
+            }
+        ));
+        assert!(fixture.driver.zone("example.org-web").is_none());
+        assert!(matches!(
+            entry(&fixture.nodedb, &web("1.0.0")).state,
+            DeploymentStatus::Archived
+        ));
+    }
+
+    #[tokio::test]
+    async fn unknown_routing_keys_are_refused() {
+        let fixture = fixture();
+        assert!(matches!(
+            fixture.handler.handle("volumes", b"{}").await,
+            Err(HandlerError::UnsupportedRoutingKey)
+        ));
+    }
+}
@@ -0,0 +1,359 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bonsaidb::core::schema::SerializedCollection;
+use bonsaidb::local::Database;
+use cloud::{DeploymentReport, ResourceIdentifier, ServiceHealth, ServiceState, ZoneHealth};
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::driver::{zone_name, DriverError, ZoneDriver, ZoneState};
+use crate::{DeploymentStatus, NodeEntry, NodeObject, RestartMode, RestartPolicy, Zone};
+
+#[derive(Debug, Error)]
+pub enum HealthError {
+    #[error(transparent)]
+    BonsaidbCore(#[from] bonsaidb::core::Error),
+
+    #[error(transparent)]
+    Driver(#[from] DriverError),
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct HealthConfig {
+    /// Seconds between two looks at the services of every running zone.
+    #[serde(default = "default_interval")]
+    pub interval_secs: u64,
+}
+
+fn default_interval() -> u64 {
+    15
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: default_interval(),
+        }
+    }
+}
+
+/// What was seen and done about the services of one zone.
+#[derive(Debug, Default)]
+struct ZoneTracker {
+    /// As last reported.
+    services: Vec<ServiceHealth>,
+    /// Restarts of each failed service since it was last seen running.
+    attempts: HashMap<String, u32>,
+    reboots: u32,
+    gave_up: bool,
+}
+
+/// Watches the SMF services of the running zones, restarts them according to the
+/// zone's [`RestartPolicy`] and reports changes of their health.
+pub struct HealthMonitor {
+    nodedb: Database,
+    driver: Arc<dyn ZoneDriver>,
+    config: HealthConfig,
+    reports: UnboundedSender<DeploymentReport>,
+    zones: HashMap<ResourceIdentifier, ZoneTracker>,
+}
+
+impl HealthMonitor {
+    pub fn new(
+        nodedb: Database,
+        driver: Arc<dyn ZoneDriver>,
+        config: HealthConfig,
+        reports: UnboundedSender<DeploymentReport>,
+    ) -> Self {
+        Self {
+            nodedb,
+            driver,
+            config,
+            reports,
+            zones: HashMap::new(),
+        }
+    }
+
+    pub async fn run(mut self) {
+        let mut interval = tokio::time::interval(Duration::from_secs(self.config.interval_secs));
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.check().await {
+                error!(error = %e, "health check failed");
+            }
+        }
+    }
+
+    /// One look at every started zone with services.
+    #[instrument(skip(self))]
+    pub async fn check(&mut self) -> Result<(), HealthError> {
+        let mut checked = HashSet::new();
+        for doc in NodeEntry::all(&self.nodedb).query()? {
+            let entry = doc.contents;
+            let NodeObject::Zone(zone) = &entry.object else {
+                continue;
+            };
+            // Zones being worked on or stopped are left to the handler and reconciler.
+            if entry.state != DeploymentStatus::Started
+                || entry.checkpoint.is_some()
+                || zone.services.is_empty()
+            {
+                continue;
+            }
+            checked.insert(entry.resource_identifier.clone());
+            if let Err(e) = self.check_zone(&entry.resource_identifier, zone).await {
+                warn!(identifier = %entry.resource_identifier, error = %e, "failed to check services");
+            }
+        }
+        self.zones
+            .retain(|identifier, _| checked.contains(identifier));
+        Ok(())
+    }
+
+    async fn check_zone(
+        &mut self,
+        identifier: &ResourceIdentifier,
+        zone: &Zone,
+    ) -> Result<(), HealthError> {
+        let name = zone_name(identifier);
+        if self.driver.state(&name).await? != ZoneState::Running {
+            debug!(zone = name, "zone is not running, skipping its services");
+            return Ok(());
+        }
+        let names: Vec<String> = zone
+            .services
+            .iter()
+            .map(|service| service.name.clone())
+            .collect();
+        let services = self.driver.services(&name, &names).await?;
+
+        let policy = zone.restart.clone().unwrap_or(RestartPolicy {
+            mode: RestartMode::Never,
+            max_attempts: 0,
+        });
+        let failed: Vec<&ServiceHealth> = services
+            .iter()
+            .filter(|service| needs_restart(policy.mode, service.state))
+            .collect();
+        let tracker = self.zones.entry(identifier.clone()).or_default();
+        // Services which came back count their restarts from scratch.
+        tracker
+            .attempts
+            .retain(|service, _| failed.iter().any(|failed| &failed.service == service));
+        if failed.is_empty() {
+            tracker.reboots = 0;
+            tracker.gave_up = false;
+        }
+
+        let mut action = None;
+        if !failed.is_empty() && !tracker.gave_up {
+            let exhausted = failed.iter().any(|service| {
+                tracker.attempts.get(&service.service).copied().unwrap_or(0) >= policy.max_attempts
+            });
+            if !exhausted {
+                for service in &failed {
+                    *tracker.attempts.entry(service.service.clone()).or_default() += 1;
+                    info!(zone = name, service = service.service, state = ?service.state, "restarting service");
+                    self.driver
+                        .recover_service(&name, &service.service, service.state)
+                        .await?;
+                }
+                let names: Vec<&str> = failed
+                    .iter()
+                    .map(|service| service.service.as_str())
+                    .collect();
+                action = Some(format!("restarted {}", names.join(", ")));
+            } else if policy.mode == RestartMode::Always || tracker.reboots == 0 {
+                tracker.reboots += 1;
+                tracker.attempts.clear();
+                warn!(zone = name, "services keep failing, rebooting zone");
+                self.driver.reboot(&name).await?;
+                action = Some(format!(
+                    "rebooted the zone after {} restarts of its services",
+                    policy.max_attempts
+                ));
+            } else {
+                tracker.gave_up = true;
+                error!(
+                    zone = name,
+                    "services still failing after a reboot, giving up"
+                );
+                action = Some("gave up, rebooting the zone did not help".to_owned());
+            }
+        }
+
+        if action.is_none() && tracker.services == services {
+            return Ok(());
+        }
+        tracker.services = services.clone();
+        let report = DeploymentReport::Health {
+            identifier: identifier.clone(),
+            health: zone_health(&services),
+            services,
+            action,
+        };
+        if self.reports.send(report).is_err() {
+            warn!("report channel closed");
+        }
+        Ok(())
+    }
+}
+
+/// Services in maintenance failed, disabled ones were stopped on purpose and only come
+/// back with [`RestartMode::Always`].
+fn needs_restart(mode: RestartMode, state: ServiceState) -> bool {
+    match mode {
+        RestartMode::Never => false,
+        RestartMode::OnFailure => state == ServiceState::Maintenance,
+        RestartMode::Always => matches!(state, ServiceState::Maintenance | ServiceState::Disabled),
+    }
+}
+
+fn service_health(state: ServiceState) -> ZoneHealth {
+    match state {
+        ServiceState::Online => ZoneHealth::Healthy,
+        ServiceState::Degraded | ServiceState::Offline => ZoneHealth::Degraded,
+        ServiceState::Maintenance | ServiceState::Disabled | ServiceState::Uninitialized => {
+            ZoneHealth::Unhealthy
+        }
+    }
+}
+
+pub fn zone_health(services: &[ServiceHealth]) -> ZoneHealth {
+    services
+        .iter()
+        .map(|service| service_health(service.state))
+        .max()
+        .unwrap_or(ZoneHealth::Healthy)
+}
+
+/// The state that tells most about a service with several instances.
+pub fn worst_state(states: impl IntoIterator<Item = ServiceState>) -> Option<ServiceState> {
+    states.into_iter().max_by_key(|state| {
+        (
+            service_health(*state),
+            matches!(state, ServiceState::Maintenance),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+    use super::*;
+    use crate::driver::{FakeDriverConfig, FakeZoneDriver};
+    use crate::testing::{identifier, memory_database, store, zone};
+
+    const ZONE: &str = "example.org-web";
+    const SERVICE: &str = "svc:/site/app:default";
+
+    struct Setup {
+        monitor: HealthMonitor,
+        driver: Arc<FakeZoneDriver>,
+        reports: UnboundedReceiver<DeploymentReport>,
+    }
+
+    /// A running zone with one service and `restart` as its policy.
+    async fn setup(restart: &str) -> Setup {
+        let web = identifier("res://example.org/web@1.0.0");
+        let zone = zone(&format!("service \"{SERVICE}\"\n{restart}"));
+        let driver = Arc::new(FakeZoneDriver::new(FakeDriverConfig::default()));
+        driver.configure(ZONE, &zone, &[]).await.unwrap();
+        driver.install(ZONE, &zone, None, &|_| {}).await.unwrap();
+        driver.boot(ZONE).await.unwrap();
+        let nodedb = memory_database();
+        store(&nodedb, &web, zone, DeploymentStatus::Started);
+        let (sender, reports) = mpsc::unbounded_channel();
+        Setup {
+            monitor: HealthMonitor::new(nodedb, driver.clone(), HealthConfig::default(), sender),
+            driver,
+            reports,
+        }
+    }
+
+    impl Setup {
+        /// Let the service fail with `state`, check once and return what was done about
+        /// it. None when nothing was reported.
+        async fn check(&mut self, state: ServiceState) -> Option<Option<String>> {
+            self.driver.set_service_state(ZONE, SERVICE, state);
+            self.monitor.check().await.unwrap();
+            let mut action = None;
+            while let Ok(report) = self.reports.try_recv() {
+                if let DeploymentReport::Health { action: done, .. } = report {
+                    action = Some(done);
+                }
+            }
+            action
+        }
+    }
+
+    fn restarted() -> Option<Option<String>> {
+        Some(Some(format!("restarted {SERVICE}")))
+    }
+
+    fn rebooted() -> Option<Option<String>> {
+        Some(Some(
+            "rebooted the zone after 2 restarts of its services".to_owned(),
+        ))
+    }
+
+    #[tokio::test]
+    async fn never_only_reports_failed_services() {
+        let mut setup = setup("restart \"never\"").await;
+        assert_eq!(setup.check(ServiceState::Maintenance).await, Some(None));
+        // Nothing changed, nothing to report.
+        assert_eq!(setup.check(ServiceState::Maintenance).await, None);
+        assert_eq!(setup.check(ServiceState::Online).await, Some(None));
+    }
+
+    #[tokio::test]
+    async fn on_failure_restarts_then_reboots_once_then_gives_up() {
+        let mut setup = setup("restart \"on-failure\" max-attempts=2").await;
+        let failed = ServiceState::Maintenance;
+        assert_eq!(setup.check(failed).await, restarted());
+        assert_eq!(setup.check(failed).await, restarted());
+        assert_eq!(setup.check(failed).await, rebooted());
+        assert_eq!(setup.check(failed).await, restarted());
+        assert_eq!(setup.check(failed).await, restarted());
+        assert_eq!(
+            setup.check(failed).await,
+            Some(Some("gave up, rebooting the zone did not help".to_owned()))
+        );
+        assert_eq!(setup.check(failed).await, None);
+        assert_eq!(
+            setup.driver.zone(ZONE).unwrap().services[SERVICE],
+            ServiceState::Maintenance
+        );
+
+        // Once the service is back the policy starts over.
+        assert_eq!(setup.check(ServiceState::Online).await, Some(None));
+        assert_eq!(setup.check(failed).await, restarted());
+        assert_eq!(setup.check(failed).await, restarted());
+        assert_eq!(setup.check(failed).await, rebooted());
+    }
+
+    #[tokio::test]
+    async fn on_failure_leaves_disabled_services_alone() {
+        let mut setup = setup("restart \"on-failure\" max-attempts=2").await;
+        assert_eq!(setup.check(ServiceState::Disabled).await, Some(None));
+        assert_eq!(
+            setup.driver.zone(ZONE).unwrap().services[SERVICE],
+            ServiceState::Disabled
+        );
+    }
+
+    #[tokio::test]
+    async fn always_keeps_rebooting() {
+        let mut setup = setup("restart \"always\" max-attempts=2").await;
+        for _ in 0..3 {
+            assert_eq!(setup.check(ServiceState::Disabled).await, restarted());
+            assert_eq!(setup.check(ServiceState::Disabled).await, restarted());
+            assert_eq!(setup.check(ServiceState::Disabled).await, rebooted());
+        }
+    }
+}
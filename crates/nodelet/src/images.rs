@@ -0,0 +1,184 @@
+//! The local store of the images zones are installed from. Images are kept by their
+//! digest and only enter the store once the digest of the fetched file matched, so a
+//! stored image is never fetched or checked again.
+
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::process::Command;
+use tokio::sync::Mutex;
+use tracing::{debug, info, instrument};
+
+use crate::driver::LogSink;
+use crate::ZoneImage;
+
+const SHA256_PREFIX: &str = "sha256:";
+
+#[derive(Debug, Error)]
+pub enum ImageError {
+    #[error("invalid image digest {0}, use `sha256:` followed by 64 hex digits")]
+    InvalidDigest(String),
+
+    #[error("cannot fetch image {source_url}: {reason}")]
+    Fetch { source_url: String, reason: String },
+
+    #[error("image {source_url} has digest {actual}, expected {expected}")]
+    DigestMismatch {
+        source_url: String,
+        expected: String,
+        actual: String,
+    },
+
+    #[error(transparent)]
+    IO(#[from] std::io::Error),
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ImageConfig {
+    /// Where fetched images are kept.
+    #[serde(default = "default_dir")]
+    pub dir: PathBuf,
+}
+
+fn default_dir() -> PathBuf {
+    PathBuf::from("./target/images")
+}
+
+impl Default for ImageConfig {
+    fn default() -> Self {
+        Self { dir: default_dir() }
+    }
+}
+
+/// Fetches images into the store and hands out their path.
+#[derive(Debug)]
+pub struct ImageStore {
+    dir: PathBuf,
+    /// Fetches run one at a time, zones sharing an image wait for the first to fetch it.
+    fetching: Mutex<()>,
+}
+
+impl ImageStore {
+    pub fn new(config: ImageConfig) -> Self {
+        Self {
+            dir: config.dir,
+            fetching: Mutex::new(()),
+        }
+    }
+
+    /// Where the image with the hex encoded sha256 `digest` is kept.
+    pub fn path(&self, digest: &str) -> PathBuf {
+        self.dir.join("sha256").join(digest)
+    }
+
+    /// The absolute path of `image` in the store, zoneadm takes no other, fetching and
+    /// verifying it first if it is not there yet.
+    #[instrument(skip(self, image, log), fields(source = %image.source))]
+    pub async fn fetch(&self, image: &ZoneImage, log: &LogSink<'_>) -> Result<PathBuf, ImageError> {
+        let digest = parse_digest(&image.digest)?;
+        let path = self.path(digest);
+        let _fetching = self.fetching.lock().await;
+        if tokio::fs::try_exists(&path).await? {
+            debug!(path = %path.display(), "image is in the store");
+            return Ok(tokio::fs::canonicalize(&path).await?);
+        }
+
+        let dir = path.parent().expect("images are kept in a directory");
+        tokio::fs::create_dir_all(dir).await?;
+        let partial = dir.join(format!("{digest}.partial"));
+        log(&format!("fetching image {}", image.source));
+        let result = async {
+            copy_source(&image.source, &partial).await?;
+            let actual = sha256_file(partial.clone()).await?;
+            if actual != digest {
+                return Err(ImageError::DigestMismatch {
+                    source_url: image.source.clone(),
+                    expected: image.digest.clone(),
+                    actual: format!("{SHA256_PREFIX}{actual}"),
+                });
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(e) = result {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(e);
+        }
+        tokio::fs::rename(&partial, &path).await?;
+        log(&format!("verified image {}", image.digest));
+        info!(digest = %image.digest, "stored image");
+        Ok(tokio::fs::canonicalize(&path).await?)
+    }
+}
+
+/// The hex digits of a `sha256:` digest, in lower case as `sha256sum` prints them.
+pub fn parse_digest(digest: &str) -> Result<&str, ImageError> {
+    match digest.strip_prefix(SHA256_PREFIX) {
+        Some(hex)
+            if hex.len() == 64
+                && hex
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || matches!(c, 'a'..='f')) =>
+        {
+            Ok(hex)
+        }
+        _ => Err(ImageError::InvalidDigest(digest.to_owned())),
+    }
+}
+
+/// Copy a local image or download a remote one with curl.
+async fn copy_source(source: &str, to: &Path) -> Result<(), ImageError> {
+    if source.starts_with("http://") || source.starts_with("https://") {
+        let output = Command::new("curl")
+            .args([
+                "--fail",
+                "--silent",
+                "--show-error",
+                "--location",
+                "--output",
+            ])
+            .arg(to)
+            .arg(source)
+            .stdin(Stdio::null())
+            .output()
+            .await?;
+        if !output.status.success() {
+            return Err(ImageError::Fetch {
+                source_url: source.to_owned(),
+                reason: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+            });
+        }
+        return Ok(());
+    }
+    let path = source.strip_prefix("file://").unwrap_or(source);
+    tokio::fs::copy(path, to)
+        .await
+        .map_err(|e| ImageError::Fetch {
+            source_url: source.to_owned(),
+            reason: e.to_string(),
+        })?;
+    Ok(())
+}
+
+async fn sha256_file(path: PathBuf) -> Result<String, ImageError> {
+    let digest = tokio::task::spawn_blocking(move || -> std::io::Result<String> {
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; 1 << 20];
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+        Ok(format!("{:x}", hasher.finalize()))
+    })
+    .await
+    .map_err(std::io::Error::other)??;
+    Ok(digest)
+}
@@ -0,0 +1,64 @@
+//! 32 byte secret keys kept base64 encoded in a file of their own, like the signing key
+//! of cloudadmd or the encryption key of a node.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rand_core::{OsRng, RngCore};
+use thiserror::Error;
+
+pub const KEY_LENGTH: usize = 32;
+
+#[derive(Debug, Error)]
+pub enum KeyFileError {
+    #[error("cannot read key {path}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("cannot write key {path}")]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("{0} does not hold a base64 encoded key of {KEY_LENGTH} bytes")]
+    Invalid(PathBuf),
+}
+
+/// Load the key stored at `path`. A new random key is stored there, readable by the
+/// owner only, if there is none yet.
+pub fn load_or_generate(path: &Path) -> Result<[u8; KEY_LENGTH], KeyFileError> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => {
+            let invalid = || KeyFileError::Invalid(path.to_owned());
+            let bytes = BASE64.decode(text.trim()).map_err(|_| invalid())?;
+            <[u8; KEY_LENGTH]>::try_from(bytes).map_err(|_| invalid())
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let mut key = [0; KEY_LENGTH];
+            OsRng.fill_bytes(&mut key);
+            let write_err = |source| KeyFileError::Write {
+                path: path.to_owned(),
+                source,
+            };
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(path)
+                .map_err(write_err)?;
+            writeln!(file, "{}", BASE64.encode(key)).map_err(write_err)?;
+            Ok(key)
+        }
+        Err(source) => Err(KeyFileError::Read {
+            path: path.to_owned(),
+            source,
+        }),
+    }
+}
@@ -1,20 +1,64 @@
 use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::path::PathBuf;
 use std::str::FromStr;
 
 use bonsaidb::core::schema::Collection;
-use cloud::ResourceIdentifier;
+use chrono::{NaiveDateTime, Utc};
+use cloud::{DeploymentState, ResourceIdentifier, ResourceIdentifierParseError, ResourceKind};
+use ipnet::IpNet;
 use knuffel::{Decode, DecodeScalar};
-use serde::{Deserialize, Serialize};
+use serde::{de::Visitor, Deserialize, Deserializer, Serialize};
 use thiserror::Error;
 
+use crate::secrets::SealedSecret;
+
+pub mod admin;
+pub mod bus;
+pub mod diff;
+pub mod driver;
+pub mod facts;
+pub mod handler;
+pub mod health;
+pub mod images;
+pub mod keyfile;
+pub mod manifest;
+pub mod metrics;
+pub mod network;
+pub mod progress;
+pub mod reconcile;
+pub mod registration;
+pub mod retry;
+pub mod secrets;
+pub mod signing;
+pub mod sysconfig;
+pub mod workers;
+
+#[cfg(test)]
+mod testing;
+
 #[derive(Debug, Error)]
 pub enum NodeletDataError {
     #[error("invalid vswitch type {0}, Must be one of `local`, `distributed`, `external`")]
     InvalidVSwitchKind(String),
+
+    #[error("network reference {0} has no switch, append one like `#internal`")]
+    NoSwitchInNetworkReference(String),
+
+    #[error(transparent)]
+    InvalidNetworkReference(#[from] ResourceIdentifierParseError),
+
+    #[error(
+        "invalid mac address {0}, use `random`, `derived` or six hex octets like 02:08:20:aa:bb:cc"
+    )]
+    InvalidMacAddress(String),
+
+    #[error("invalid bandwidth {0}, use a number with an optional K, M or G suffix like 100M")]
+    InvalidBandwidth(String),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
 pub enum DeploymentStatus {
     Configured,
     Installed,
@@ -26,6 +70,22 @@ pub enum DeploymentStatus {
     Archived,
 }
 
+impl From<&DeploymentStatus> for DeploymentState {
+    fn from(value: &DeploymentStatus) -> Self {
+        match value {
+            DeploymentStatus::Configured => DeploymentState::Configured,
+            DeploymentStatus::Installed => DeploymentState::Installed,
+            DeploymentStatus::Starting => DeploymentState::Starting,
+            DeploymentStatus::Started => DeploymentState::Started,
+            DeploymentStatus::Stopping => DeploymentState::Stopping,
+            DeploymentStatus::Uninstalling => DeploymentState::Uninstalling,
+            DeploymentStatus::Stopped => DeploymentState::Stopped,
+            DeploymentStatus::Archived => DeploymentState::Archived,
+        }
+    }
+}
+
+/// What the nodelet manages on this node. Removed resources stay as `Archived` entries.
 #[derive(Debug, Serialize, Deserialize, Collection)]
 #[collection(name = "node-entries", primary_key = ResourceIdentifier)]
 pub struct NodeEntry {
@@ -33,12 +93,87 @@ pub struct NodeEntry {
     pub resource_identifier: ResourceIdentifier,
     pub object: NodeObject,
     pub state: DeploymentStatus,
+    /// Set while an operation on the entry is in flight, so a restart can pick it up again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checkpoint: Option<Checkpoint>,
+    /// The states the entry went through, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<StatusChange>,
+    /// The newest signed event taken for the entry. Older events and replays of it are
+    /// refused, also after a restart.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_event: Option<SignedEvent>,
+}
+
+/// State changes kept per entry, older ones are dropped.
+const HISTORY_LEN: usize = 20;
+
+impl NodeEntry {
+    /// Move the entry to `state`, remembering when it did.
+    pub fn set_state(&mut self, state: DeploymentStatus) {
+        if self.history.last().map(|change| &change.state) != Some(&state) {
+            if self.history.len() == HISTORY_LEN {
+                self.history.remove(0);
+            }
+            self.history.push(StatusChange {
+                state: state.clone(),
+                at: Utc::now().naive_utc(),
+            });
+        }
+        self.state = state;
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StatusChange {
+    pub state: DeploymentStatus,
+    pub at: NaiveDateTime,
+}
+
+/// A signed event taken for a [`NodeEntry`].
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct SignedEvent {
+    /// Milliseconds since the unix epoch.
+    pub signed_at: u64,
+    /// The retry of the event the nodelet published, taken once it comes back.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<u32>,
+}
+
+/// The last step an operation on a [`NodeEntry`] reached.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Checkpoint {
+    pub operation: PendingOperation,
+    pub step: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum PendingOperation {
+    /// Bringing the zone to `zone`. For an update the checkpoint sits on the deployed
+    /// entry, while `identifier` is the version being applied.
+    Ensure {
+        identifier: ResourceIdentifier,
+        zone: Box<Zone>,
+    },
+    Remove,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum NodeObject {
-    Zone(Zone),
+    Zone(Box<Zone>),
     Network(Network),
+    /// Sent by cloudadmd to the nodes running zones which use the secret.
+    Secret(SealedSecret),
+}
+
+impl NodeObject {
+    pub fn kind(&self) -> ResourceKind {
+        match self {
+            NodeObject::Zone(_) => ResourceKind::Zone,
+            NodeObject::Network(_) => ResourceKind::Network,
+            NodeObject::Secret(_) => ResourceKind::Secret,
+        }
+    }
 }
 
 impl Display for NodeObject {
@@ -46,12 +181,17 @@ impl Display for NodeObject {
         match self {
             NodeObject::Zone(_) => write!(f, "Zone"),
             NodeObject::Network(_) => write!(f, "Network"),
+            NodeObject::Secret(_) => write!(f, "Secret"),
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Decode, Clone)]
+#[derive(Debug, Serialize, Deserialize, Decode, Clone, PartialEq)]
 pub struct Zone {
+    /// Tells the zones of one deployment apart, defaults to the name of the deployment.
+    #[knuffel(child, unwrap(argument))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
     #[knuffel(child, unwrap(argument))]
     pub brand: ZoneBrand,
     #[knuffel(child, unwrap(argument))]
@@ -59,65 +199,735 @@ pub struct Zone {
     #[knuffel(child, unwrap(argument))]
     pub ip_type: ZoneIpType,
     #[knuffel(children(name = "net"))]
-    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub network: Vec<NetworkInterface>,
-    #[knuffel(children(name = "nameserver"), unwrap(argument))]
-    #[serde(skip_serializing_if = "Vec::is_empty")]
-    pub nameservers: Vec<String>,
+    #[knuffel(children(name = "nameserver"), unwrap(argument, str))]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub nameservers: Vec<IpAddr>,
     #[knuffel(children(name = "dns-search"), unwrap(argument))]
-    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub dns_search: Vec<String>,
+    /// Installed instead of everything coming from pkg(5), the packages are added on top.
+    #[knuffel(child)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<ZoneImage>,
     #[knuffel(children(name = "package"), unwrap(argument))]
-    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub packages: Vec<String>,
     #[knuffel(children(name = "service"))]
-    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub services: Vec<SMFService>,
+    #[knuffel(children(name = "rctl"))]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rctls: Vec<ResourceControl>,
+    /// Without one the health of the services is only reported.
+    #[knuffel(child)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restart: Option<RestartPolicy>,
+    #[knuffel(children(name = "hook"))]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hooks: Vec<Hook>,
+    #[knuffel(children(name = "secret"))]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub secrets: Vec<SecretMount>,
+    #[knuffel(child)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_config: Option<SystemConfig>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Decode, Clone)]
+/// The identity of a zone, written into its root after the install and applied by
+/// sysding on the first boot. Changing it later only affects reinstalls.
+///
+/// ```kdl
+/// system-config {
+///     hostname "db1"
+///     timezone "Europe/Zurich"
+///     locale "en_US.UTF-8"
+///     root-password "$6$salt$hash"
+///     authorized-key "ssh-ed25519 AAAA... admin@example.org"
+///     user "deploy" uid=1001 {
+///         group "staff"
+///         authorized-key "ssh-ed25519 AAAA... deploy@example.org"
+///     }
+/// }
+/// ```
+#[derive(Debug, Serialize, Deserialize, Decode, Clone, PartialEq, Eq, Default)]
+pub struct SystemConfig {
+    /// Defaults to the name of the zone.
+    #[knuffel(child, unwrap(argument))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[knuffel(child, unwrap(argument))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    #[knuffel(child, unwrap(argument))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+    /// A crypt(3) hash as in /etc/shadow, never the password itself.
+    #[knuffel(child, unwrap(argument))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root_password: Option<String>,
+    /// Keys allowed to log in as root over SSH.
+    #[knuffel(children(name = "authorized-key"), unwrap(argument))]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub authorized_keys: Vec<String>,
+    #[knuffel(children(name = "user"))]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<SystemUser>,
+}
+
+/// A user created on the first boot, logging in with SSH keys only.
+#[derive(Debug, Serialize, Deserialize, Decode, Clone, PartialEq, Eq)]
+pub struct SystemUser {
+    #[knuffel(argument)]
+    pub name: String,
+    #[knuffel(property)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[knuffel(property, default = "/bin/bash".into())]
+    #[serde(default = "default_user_shell")]
+    pub shell: String,
+    /// Defaults to `/export/home/<name>`.
+    #[knuffel(property)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub home: Option<String>,
+    #[knuffel(children(name = "group"), unwrap(argument))]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+    #[knuffel(children(name = "authorized-key"), unwrap(argument))]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub authorized_keys: Vec<String>,
+}
+
+fn default_user_shell() -> String {
+    "/bin/bash".to_owned()
+}
+
+impl SystemUser {
+    pub fn home(&self) -> String {
+        self.home
+            .clone()
+            .unwrap_or_else(|| format!("/export/home/{}", self.name))
+    }
+}
+
+/// The image a zone is installed from, like
+/// `image "https://images.example.org/pg16.zfs" format="zfs-send" digest="sha256:9f86..."`.
+///
+/// The nodelet keeps the images it fetched in its image store by their digest, so zones
+/// sharing an image fetch it once.
+#[derive(Debug, Serialize, Deserialize, Decode, Clone, PartialEq, Eq)]
+pub struct ZoneImage {
+    /// A path on the node, or a `file://`, `http://` or `https://` URL.
+    #[knuffel(argument)]
+    pub source: String,
+    #[knuffel(property)]
+    pub format: ImageFormat,
+    /// `sha256:` and the hex encoded digest of the image file.
+    #[knuffel(property)]
+    pub digest: String,
+}
+
+impl Display for ZoneImage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.format, self.digest)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, DecodeScalar, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// A `zfs send` stream of a zone root dataset.
+    ZfsSend,
+    /// A tar archive of a zone root, optionally compressed.
+    Tarball,
+}
+
+impl Display for ImageFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageFormat::ZfsSend => write!(f, "zfs-send"),
+            ImageFormat::Tarball => write!(f, "tarball"),
+        }
+    }
+}
+
+/// A secret of the zone's tenant written into a file of the zone, like
+/// `secret "res://acme/db-password@1.0.0" path="/etc/postgres/password" owner="postgres"`.
+///
+/// The file is written whenever the zone is ensured, after it booted and before the
+/// `post-boot` hooks run.
+#[derive(Debug, Serialize, Deserialize, Decode, Clone, PartialEq, Eq)]
+pub struct SecretMount {
+    #[knuffel(argument, str)]
+    pub secret: ResourceIdentifier,
+    /// Absolute path inside the zone.
+    #[knuffel(property, str)]
+    pub path: PathBuf,
+    #[knuffel(property, default = "root".into())]
+    #[serde(default = "default_secret_owner")]
+    pub owner: String,
+    #[knuffel(property, default = "root".into())]
+    #[serde(default = "default_secret_owner")]
+    pub group: String,
+    #[knuffel(property, default = default_secret_mode())]
+    #[serde(default = "default_secret_mode")]
+    pub mode: u32,
+}
+
+fn default_secret_owner() -> String {
+    "root".to_owned()
+}
+
+/// Readable by the owner only.
+fn default_secret_mode() -> u32 {
+    0o400
+}
+
+impl Display for SecretMount {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} at {} ({}:{} {:o})",
+            self.secret,
+            self.path.display(),
+            self.owner,
+            self.group,
+            self.mode
+        )
+    }
+}
+
+/// A site specific command run by the nodelet at a point of the zone's lifecycle.
+///
+/// ```kdl
+/// hook "pre-uninstall" {
+///     command "pg_dumpall" "-f" "/var/backup/all.sql"
+///     run-in "zone"
+///     timeout 600
+///     env "PGUSER" "postgres"
+/// }
+/// ```
+///
+/// Its output goes into the progress reports of the operation. The command gets the zone
+/// name in `NODELET_ZONE` and the stage in `NODELET_HOOK`.
+#[derive(Debug, Serialize, Deserialize, Decode, Clone, PartialEq, Eq)]
+pub struct Hook {
+    #[knuffel(argument)]
+    pub stage: HookStage,
+    /// The program and its arguments, not run through a shell.
+    #[knuffel(child, unwrap(arguments))]
+    pub command: Vec<String>,
+    #[knuffel(child, unwrap(argument), default)]
+    #[serde(default)]
+    pub run_in: HookTarget,
+    /// Seconds before the command is killed and counts as failed.
+    #[knuffel(child, unwrap(argument), default = default_hook_timeout())]
+    #[serde(default = "default_hook_timeout")]
+    pub timeout: u64,
+    #[knuffel(children(name = "env"))]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub env: Vec<HookEnv>,
+    #[knuffel(child, unwrap(argument), default)]
+    #[serde(default)]
+    pub on_failure: HookFailure,
+}
+
+fn default_hook_timeout() -> u64 {
+    300
+}
+
+#[derive(Debug, Serialize, Deserialize, DecodeScalar, Clone, Copy, PartialEq, Eq)]
+pub enum HookStage {
+    PreInstall,
+    PostInstall,
+    PostBoot,
+    /// Before the zone is halted, while it still runs.
+    PreHalt,
+    /// Before a zone is torn down for good or reinstalled. It runs ahead of the
+    /// `pre-halt` hooks, so the zone still runs if it did before.
+    PreUninstall,
+}
+
+impl Display for HookStage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HookStage::PreInstall => write!(f, "pre-install"),
+            HookStage::PostInstall => write!(f, "post-install"),
+            HookStage::PostBoot => write!(f, "post-boot"),
+            HookStage::PreHalt => write!(f, "pre-halt"),
+            HookStage::PreUninstall => write!(f, "pre-uninstall"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, DecodeScalar, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HookTarget {
+    /// On the node, next to the nodelet and as its user. Only taken when the operator of
+    /// the node allows it, see [`handler::HookConfig`].
+    Global,
+    /// Inside the zone through `zlogin`.
+    #[default]
+    Zone,
+}
+
+#[derive(Debug, Serialize, Deserialize, Decode, Clone, PartialEq, Eq)]
+pub struct HookEnv {
+    #[knuffel(argument)]
+    pub name: String,
+    #[knuffel(argument)]
+    pub value: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, DecodeScalar, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HookFailure {
+    /// Fail the operation, a retry of the event runs the hook again.
+    #[default]
+    Abort,
+    /// Note the failure in the report and carry on.
+    Ignore,
+}
+
+/// What the nodelet does about services of a zone which stopped running, like
+/// `restart "on-failure" max-attempts=3`.
+#[derive(Debug, Serialize, Deserialize, Decode, Clone, PartialEq, Eq)]
+pub struct RestartPolicy {
+    #[knuffel(argument)]
+    pub mode: RestartMode,
+    /// Restarts of a service in a row before the zone is rebooted instead.
+    #[knuffel(property, default = default_max_attempts())]
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+}
+
+fn default_max_attempts() -> u32 {
+    3
+}
+
+impl Display for RestartPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.mode {
+            RestartMode::Never => write!(f, "never"),
+            RestartMode::OnFailure => write!(f, "on-failure max-attempts={}", self.max_attempts),
+            RestartMode::Always => write!(f, "always max-attempts={}", self.max_attempts),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, DecodeScalar, Clone, Copy, PartialEq, Eq)]
+pub enum RestartMode {
+    Never,
+    /// Clear services in maintenance. Once rebooting the zone did not help either, the
+    /// nodelet gives up until the services recover.
+    OnFailure,
+    /// Also enable disabled services, and keep rebooting the zone for as long as they
+    /// do not come up.
+    Always,
+}
+
+/// A zone wide resource control like `zone.max-lwps`.
+#[derive(Debug, Serialize, Deserialize, Decode, Clone, PartialEq, Eq)]
+pub struct ResourceControl {
+    #[knuffel(argument)]
+    pub name: String,
+    #[knuffel(property)]
+    pub limit: u64,
+    #[knuffel(property(name = "priv"), default = "privileged".into())]
+    #[serde(default = "default_rctl_privilege")]
+    pub privilege: String,
+    #[knuffel(property, default = "deny".into())]
+    #[serde(default = "default_rctl_action")]
+    pub action: String,
+}
+
+fn default_rctl_privilege() -> String {
+    "privileged".into()
+}
+
+fn default_rctl_action() -> String {
+    "deny".into()
+}
+
+#[derive(Debug, Serialize, Deserialize, Decode, Clone, PartialEq)]
 pub struct SMFService {
     #[knuffel(argument)]
     pub name: String,
     #[knuffel(children)]
-    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub property_groups: Vec<SMFServicePropertyGroup>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Decode, Clone)]
+#[derive(Debug, Serialize, Deserialize, Decode, Clone, PartialEq)]
 pub struct SMFServicePropertyGroup {
+    #[knuffel(node_name)]
+    #[serde(default)]
+    pub name: String,
     #[knuffel(properties)]
     pub properties: HashMap<String, String>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Decode, Clone)]
+#[derive(Debug, Serialize, Deserialize, Decode, Clone, PartialEq)]
 pub struct NetworkInterface {
     #[knuffel(argument)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
+    /// The switch of a [`Network`] this interface is attached to. The nodelet resolves it
+    /// to a link on the node, so tenants do not need to know the host's link names.
+    #[knuffel(child, unwrap(argument, str))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub network: Option<NetworkReference>,
+    /// Host specific link name. Only needed when the interface is not attached to a `network`.
     #[knuffel(child, unwrap(argument))]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub physical: Option<String>,
+    #[knuffel(children(name = "allowed-address"), unwrap(argument, str))]
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        deserialize_with = "one_or_many"
+    )]
+    pub allowed_address: Vec<IpNet>,
+    #[knuffel(children(name = "defrouter"), unwrap(argument, str))]
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        deserialize_with = "one_or_many"
+    )]
+    pub defrouter: Vec<IpAddr>,
+    /// How the interface gets its IPv6 addresses. Without it the interface is IPv4 only.
+    #[knuffel(child, unwrap(argument))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipv6: Option<Ipv6Mode>,
+    /// Overrides the VLAN of the switch.
     #[knuffel(child, unwrap(argument))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub allowed_address: Option<String>,
+    pub vlan: Option<u16>,
+    /// Overrides the MTU of the switch.
     #[knuffel(child, unwrap(argument))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub defrouter: Option<String>,
+    pub mtu: Option<u32>,
+    #[knuffel(child, unwrap(argument, str))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mac: Option<MacAddressPolicy>,
+    #[knuffel(child, unwrap(argument, str))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maxbw: Option<Bandwidth>,
+    #[knuffel(child, unwrap(arguments), default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub protection: Vec<LinkProtection>,
 }
 
-#[derive(Debug, Serialize, Deserialize, DecodeScalar, Clone)]
+#[derive(Debug, Serialize, Deserialize, DecodeScalar, Clone, Copy, Eq, PartialEq)]
+pub enum Ipv6Mode {
+    /// Stateless address autoconfiguration from the router advertisements on the link.
+    Slaac,
+    /// Use the IPv6 addresses listed in `allowed-address`.
+    Static,
+}
+
+/// Points at a switch of a [`Network`] in the form `res://tenant/name@version#switch`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct NetworkReference {
+    pub network: ResourceIdentifier,
+    pub switch: String,
+}
+
+impl FromStr for NetworkReference {
+    type Err = NodeletDataError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, switch) = s
+            .split_once('#')
+            .ok_or_else(|| NodeletDataError::NoSwitchInNetworkReference(s.to_owned()))?;
+        if switch.is_empty() {
+            return Err(NodeletDataError::NoSwitchInNetworkReference(s.to_owned()));
+        }
+
+        Ok(Self {
+            network: ResourceIdentifier::from_str(network)?,
+            switch: switch.to_owned(),
+        })
+    }
+}
+
+impl Display for NetworkReference {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}#{}", self.network, self.switch)
+    }
+}
+
+impl Serialize for NetworkReference {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(self.to_string().as_str())
+    }
+}
+
+struct NetworkReferenceVisitor;
+
+impl<'de> Visitor<'de> for NetworkReferenceVisitor {
+    type Value = NetworkReference;
+
+    fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+        formatter.write_str("a network reference like res://tenant/name@1.0.0#switch")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        NetworkReference::from_str(v).map_err(serde::de::Error::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for NetworkReference {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_str(NetworkReferenceVisitor)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, DecodeScalar, Clone, Copy, Eq, PartialEq)]
+pub enum LinkProtection {
+    MacNospoof,
+    IpNospoof,
+    DhcpNospoof,
+    Restricted,
+}
+
+impl Display for LinkProtection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkProtection::MacNospoof => write!(f, "mac-nospoof"),
+            LinkProtection::IpNospoof => write!(f, "ip-nospoof"),
+            LinkProtection::DhcpNospoof => write!(f, "dhcp-nospoof"),
+            LinkProtection::Restricted => write!(f, "restricted"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl Display for MacAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+impl FromStr for MacAddress {
+    type Err = NodeletDataError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || NodeletDataError::InvalidMacAddress(s.to_owned());
+        let mut octets = [0u8; 6];
+        let mut parts = s.split(':');
+        for octet in octets.iter_mut() {
+            let part = parts.next().ok_or_else(invalid)?;
+            *octet = u8::from_str_radix(part, 16).map_err(|_| invalid())?;
+        }
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(Self(octets))
+    }
+}
+
+/// How the MAC address of an interface is chosen.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MacAddressPolicy {
+    Fixed(MacAddress),
+    Random,
+    /// Locally administered address derived from a hash of the zone and interface, so it
+    /// stays the same when the zone is recreated on another node.
+    Derived,
+}
+
+impl FromStr for MacAddressPolicy {
+    type Err = NodeletDataError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(Self::Random),
+            "derived" => Ok(Self::Derived),
+            s => Ok(Self::Fixed(MacAddress::from_str(s)?)),
+        }
+    }
+}
+
+impl Display for MacAddressPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MacAddressPolicy::Fixed(address) => write!(f, "{address}"),
+            MacAddressPolicy::Random => write!(f, "random"),
+            MacAddressPolicy::Derived => write!(f, "derived"),
+        }
+    }
+}
+
+impl Serialize for MacAddressPolicy {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(self.to_string().as_str())
+    }
+}
+
+struct MacAddressPolicyVisitor;
+
+impl<'de> Visitor<'de> for MacAddressPolicyVisitor {
+    type Value = MacAddressPolicy;
+
+    fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+        formatter.write_str("`random`, `derived` or a mac address")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        MacAddressPolicy::from_str(v).map_err(serde::de::Error::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for MacAddressPolicy {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_str(MacAddressPolicyVisitor)
+    }
+}
+
+/// Bandwidth limit in bits per second, written like dladm's `maxbw` property.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Bandwidth(pub u64);
+
+impl FromStr for Bandwidth {
+    type Err = NodeletDataError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || NodeletDataError::InvalidBandwidth(s.to_owned());
+        // Like dladm a number without a suffix is in Mbps.
+        let (number, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+            Some('K') => (&s[..s.len() - 1], 1_000),
+            Some('M') => (&s[..s.len() - 1], 1_000_000),
+            Some('G') => (&s[..s.len() - 1], 1_000_000_000),
+            _ => (s, 1_000_000),
+        };
+        // Fractions go down to single bits, like 0.5K.
+        let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+        let whole: u64 = whole.parse().map_err(|_| invalid())?;
+        let mut bits = whole.checked_mul(multiplier).ok_or_else(invalid)?;
+        if number.contains('.') {
+            let mut unit = multiplier;
+            for digit in fraction.chars() {
+                unit /= 10;
+                let digit = digit
+                    .to_digit(10)
+                    .filter(|_| unit > 0)
+                    .ok_or_else(invalid)?;
+                bits = bits
+                    .checked_add(u64::from(digit) * unit)
+                    .ok_or_else(invalid)?;
+            }
+            if fraction.is_empty() {
+                return Err(invalid());
+            }
+        }
+        Ok(Self(bits))
+    }
+}
+
+impl Display for Bandwidth {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // The largest unit the value is a whole number of, or the exact fraction of a K.
+        match self.0 {
+            bits if bits >= 1_000_000_000 && bits % 1_000_000_000 == 0 => {
+                write!(f, "{}G", bits / 1_000_000_000)
+            }
+            bits if bits >= 1_000_000 && bits % 1_000_000 == 0 => {
+                write!(f, "{}M", bits / 1_000_000)
+            }
+            bits if bits % 1_000 == 0 => write!(f, "{}K", bits / 1_000),
+            bits => {
+                let fraction = format!("{:03}", bits % 1_000);
+                write!(f, "{}.{}K", bits / 1_000, fraction.trim_end_matches('0'))
+            }
+        }
+    }
+}
+
+impl Serialize for Bandwidth {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(self.to_string().as_str())
+    }
+}
+
+struct BandwidthVisitor;
+
+impl<'de> Visitor<'de> for BandwidthVisitor {
+    type Value = Bandwidth;
+
+    fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+        formatter.write_str("a bandwidth like 100M")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Bandwidth::from_str(v).map_err(serde::de::Error::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for Bandwidth {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_str(BandwidthVisitor)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, DecodeScalar, Clone, Copy, PartialEq, Eq)]
 pub enum ZoneIpType {
     Exclusive,
     Shared,
 }
 
-#[derive(Debug, Serialize, Deserialize, DecodeScalar, Clone)]
+impl Display for ZoneIpType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ZoneIpType::Exclusive => write!(f, "exclusive"),
+            ZoneIpType::Shared => write!(f, "shared"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, DecodeScalar, Clone, Copy, PartialEq, Eq)]
 pub enum ZoneBrand {
     LinkedPkg,
     UnlinkedPkg,
 }
 
+impl Display for ZoneBrand {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ZoneBrand::LinkedPkg => write!(f, "linked-pkg"),
+            ZoneBrand::UnlinkedPkg => write!(f, "unlinked-pkg"),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Decode, Clone)]
 pub struct Network {
     #[knuffel(child, unwrap(argument))]
@@ -134,10 +944,32 @@ pub struct VSwitch {
     pub kind: VSwitchKind,
     #[knuffel(argument)]
     pub name: String,
+    #[knuffel(children(name = "allowed-range"), unwrap(argument, str))]
+    #[serde(
+        default,
+        alias = "allowed_range",
+        skip_serializing_if = "Vec::is_empty",
+        deserialize_with = "one_or_many"
+    )]
+    pub allowed_ranges: Vec<IpNet>,
+    #[knuffel(children(name = "router"), unwrap(argument, str))]
+    #[serde(
+        default,
+        alias = "router",
+        skip_serializing_if = "Vec::is_empty",
+        deserialize_with = "one_or_many"
+    )]
+    pub routers: Vec<IpAddr>,
     #[knuffel(child, unwrap(argument))]
-    pub allowed_range: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vlan: Option<u16>,
     #[knuffel(child, unwrap(argument))]
-    pub router: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<u32>,
+    /// Default bandwidth limit for every interface on the switch.
+    #[knuffel(child, unwrap(argument, str))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maxbw: Option<Bandwidth>,
     #[knuffel(children(name = "public-ips"))]
     pub public_ips: Vec<PublicIp>,
 }
@@ -164,10 +996,89 @@ impl FromStr for VSwitchKind {
 
 #[derive(Debug, Serialize, Deserialize, Decode, Clone)]
 pub struct PublicIp {
-    #[knuffel(child, unwrap(argument))]
-    pub range: String,
-    #[knuffel(child, unwrap(argument))]
-    pub reserved: String,
-    #[knuffel(child, unwrap(argument))]
-    pub floating_ip: String,
+    #[knuffel(child, unwrap(argument, str))]
+    pub range: IpNet,
+    #[knuffel(child, unwrap(argument, str))]
+    pub reserved: IpAddr,
+    #[knuffel(child, unwrap(argument, str))]
+    pub floating_ip: IpAddr,
+}
+
+/// Accepts a single value, a list or null. Older documents used single strings for
+/// fields which are lists now that IPv4 and IPv6 can be configured side by side.
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    // The node database always holds lists. Its binary format cannot go through an
+    // untagged enum, which buffers values as if they were human readable.
+    if !deserializer.is_human_readable() {
+        return Vec::deserialize(deserializer);
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    Ok(match Option::<OneOrMany<T>>::deserialize(deserializer)? {
+        None => vec![],
+        Some(OneOrMany::One(value)) => vec![value],
+        Some(OneOrMany::Many(values)) => values,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bandwidths_are_rendered_exactly() {
+        let cases = [
+            (0, "0K"),
+            (1, "0.001K"),
+            (500, "0.5K"),
+            (999, "0.999K"),
+            (1_000, "1K"),
+            (1_500, "1.5K"),
+            (1_000_001, "1000.001K"),
+            (1_500_000, "1500K"),
+            (100_000_000, "100M"),
+            (1_500_000_000, "1500M"),
+            (2_000_000_000, "2G"),
+        ];
+        for (bits, text) in cases {
+            assert_eq!(Bandwidth(bits).to_string(), text);
+            assert_eq!(
+                Bandwidth::from_str(text).unwrap(),
+                Bandwidth(bits),
+                "{text}"
+            );
+        }
+    }
+
+    #[test]
+    fn bandwidths_are_parsed_like_dladm() {
+        assert_eq!(Bandwidth::from_str("100").unwrap(), Bandwidth(100_000_000));
+        assert_eq!(
+            Bandwidth::from_str("1.5G").unwrap(),
+            Bandwidth(1_500_000_000)
+        );
+        assert_eq!(Bandwidth::from_str("0.25m").unwrap(), Bandwidth(250_000));
+        for invalid in [
+            "",
+            "M",
+            "1.",
+            ".5K",
+            "0.0001K",
+            "1.5xK",
+            "-1M",
+            "99999999999999999G",
+        ] {
+            assert!(Bandwidth::from_str(invalid).is_err(), "{invalid}");
+        }
+    }
 }
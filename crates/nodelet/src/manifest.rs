@@ -0,0 +1,502 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use bonsaidb::core::schema::SerializedCollection;
+use bonsaidb::local::Database;
+use cloud::{
+    DeploymentEvent, DeploymentReport, ResourceIdentifier, ResourceIdentifierParseError,
+    ResourceKind,
+};
+use miette::Diagnostic;
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{debug, error, info, warn};
+
+use crate::handler::Handler;
+use crate::workers::{LaneKey, LaneLocks};
+use crate::{DeploymentStatus, NodeEntry, NodeObject};
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum ManifestError {
+    #[error("cannot read {path}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("{0} is neither a .kdl nor a .yaml file")]
+    UnknownFormat(PathBuf),
+
+    #[error("cannot derive a resource name from {0}")]
+    InvalidName(PathBuf),
+
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Kdl(#[from] knuffel::Error),
+
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Identifier(#[from] ResourceIdentifierParseError),
+
+    #[error(transparent)]
+    BonsaidbCore(#[from] bonsaidb::core::Error),
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ManifestConfig {
+    /// Holds `zones/` and `networks/` with one `.kdl` or `.yaml` file per resource.
+    pub dir: PathBuf,
+    /// Seconds between two looks at the directory.
+    #[serde(default = "default_interval")]
+    pub interval_secs: u64,
+}
+
+fn default_interval() -> u64 {
+    5
+}
+
+/// Networks first, zones resolve their interfaces against them.
+const KINDS: [(&str, ResourceKind); 2] = [
+    ("networks", ResourceKind::Network),
+    ("zones", ResourceKind::Zone),
+];
+
+/// A zone or network file of the manifest directory.
+///
+/// The file is named after the resource, `<name>@<version>.kdl`, the version defaults to
+/// `0.0.0`. Networks name their tenant and may name themselves in the file, so zones can
+/// reference them like networks handed out by cloudadmd.
+#[derive(Debug, Clone)]
+pub struct Manifest {
+    pub identifier: ResourceIdentifier,
+    pub object: NodeObject,
+}
+
+impl Manifest {
+    pub fn read(path: &Path, kind: ResourceKind) -> Result<Self, ManifestError> {
+        let text = std::fs::read_to_string(path).map_err(|source| ManifestError::Read {
+            path: path.to_owned(),
+            source,
+        })?;
+        let name = path.to_string_lossy();
+        let object = match (path.extension().and_then(|ext| ext.to_str()), kind) {
+            (Some("kdl"), ResourceKind::Zone) => NodeObject::Zone(knuffel::parse(&name, &text)?),
+            (Some("kdl"), ResourceKind::Network) => {
+                NodeObject::Network(knuffel::parse(&name, &text)?)
+            }
+            (Some("yaml" | "yml"), ResourceKind::Zone) => {
+                NodeObject::Zone(serde_yaml::from_str(&text)?)
+            }
+            (Some("yaml" | "yml"), ResourceKind::Network) => {
+                NodeObject::Network(serde_yaml::from_str(&text)?)
+            }
+            _ => return Err(ManifestError::UnknownFormat(path.to_owned())),
+        };
+
+        let stem = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_else(|| ManifestError::InvalidName(path.to_owned()))?;
+        let (name, version) = stem.split_once('@').unwrap_or((stem, "0.0.0"));
+        let (tenant, name) = match &object {
+            NodeObject::Network(network) => (
+                network.tenant.as_deref(),
+                network.name.as_deref().unwrap_or(name),
+            ),
+            NodeObject::Zone(_) | NodeObject::Secret(_) => (None, name),
+        };
+        let identifier = match tenant {
+            Some(tenant) => format!("res://{tenant}/{name}@{version}"),
+            None => format!("res:/{name}@{version}"),
+        };
+        Ok(Self {
+            identifier: ResourceIdentifier::from_str(&identifier)?,
+            object,
+        })
+    }
+
+    fn routing_key(&self) -> &'static str {
+        match self.object {
+            NodeObject::Zone(_) => "zones",
+            NodeObject::Network(_) => "networks",
+            // Never read from the directory, secrets only come from cloudadmd.
+            NodeObject::Secret(_) => "secrets",
+        }
+    }
+
+    /// The payload a message from cloudadmd would carry.
+    fn payload(&self, ensure: bool) -> Result<Vec<u8>, serde_json::Error> {
+        let identifier = self.identifier.clone();
+        match &self.object {
+            NodeObject::Zone(zone) => serde_json::to_vec(&event(ensure, zone.clone(), identifier)),
+            NodeObject::Network(network) => {
+                serde_json::to_vec(&event(ensure, network.clone(), identifier))
+            }
+            NodeObject::Secret(secret) => {
+                serde_json::to_vec(&event(ensure, secret.clone(), identifier))
+            }
+        }
+    }
+}
+
+fn event<T>(ensure: bool, data: T, identifier: ResourceIdentifier) -> DeploymentEvent<T> {
+    if ensure {
+        DeploymentEvent::Ensure { data, identifier }
+    } else {
+        DeploymentEvent::Remove { data, identifier }
+    }
+}
+
+#[derive(Debug)]
+struct Applied {
+    manifest: Manifest,
+    /// What was last handed to the handler, a change of the file shows up here.
+    payload: Vec<u8>,
+    ok: bool,
+}
+
+/// Turns the files of the manifest directory into the events cloudadmd would send: every
+/// file is ensured, every deleted one removed. Events that failed are sent again on the
+/// next sync.
+pub struct ManifestWatcher {
+    dir: PathBuf,
+    nodedb: Database,
+    applied: BTreeMap<PathBuf, Applied>,
+    removals: BTreeMap<ResourceIdentifier, Manifest>,
+    first_sync: bool,
+    locks: LaneLocks,
+}
+
+impl ManifestWatcher {
+    pub fn new(dir: PathBuf, nodedb: Database, locks: LaneLocks) -> Self {
+        Self {
+            dir,
+            nodedb,
+            applied: BTreeMap::new(),
+            removals: BTreeMap::new(),
+            first_sync: true,
+            locks,
+        }
+    }
+
+    pub async fn sync(
+        &mut self,
+        handler: &Handler,
+        reports: &UnboundedSender<DeploymentReport>,
+    ) -> Result<(), ManifestError> {
+        let mut present = BTreeSet::new();
+        let mut ensures = vec![];
+        let mut wanted = BTreeSet::new();
+        let mut invalid = false;
+        for (subdir, kind) in KINDS {
+            for path in self.files(subdir)? {
+                present.insert(path.clone());
+                // A file that fails to parse keeps its last good version.
+                let manifest = match Manifest::read(&path, kind) {
+                    Ok(manifest) => manifest,
+                    Err(e) => {
+                        warn!(path = %path.display(), error = %e, "skipping invalid manifest");
+                        invalid = true;
+                        continue;
+                    }
+                };
+                wanted.insert(manifest.identifier.clone());
+                let payload = manifest.payload(true)?;
+                match self.applied.get(&path) {
+                    Some(applied) if applied.ok && applied.payload == payload => {}
+                    _ => ensures.push((path, manifest, payload)),
+                }
+            }
+        }
+
+        let deleted: Vec<PathBuf> = self
+            .applied
+            .keys()
+            .filter(|path| !present.contains(*path))
+            .cloned()
+            .collect();
+        for path in deleted {
+            if let Some(applied) = self.applied.remove(&path) {
+                info!(path = %path.display(), "manifest deleted");
+                self.removals
+                    .insert(applied.manifest.identifier.clone(), applied.manifest);
+            }
+        }
+        // Whose entry an invalid file is for is unknown, so wait until all of them parse.
+        if self.first_sync && !invalid {
+            self.first_sync = false;
+            self.orphans(&wanted)?;
+        }
+
+        for (path, manifest, payload) in ensures {
+            // A renamed file ensures the same resource again instead of removing it.
+            self.removals.remove(&manifest.identifier);
+            let ok = self.handle(handler, reports, &manifest, &payload).await;
+            self.applied.insert(
+                path,
+                Applied {
+                    manifest,
+                    payload,
+                    ok,
+                },
+            );
+        }
+
+        // Zones first, so their interfaces are gone before the networks.
+        let mut removals: Vec<Manifest> = self.removals.values().cloned().collect();
+        removals.sort_by_key(|manifest| matches!(manifest.object, NodeObject::Network(_)));
+        for manifest in removals {
+            let payload = manifest.payload(false)?;
+            if self.handle(handler, reports, &manifest, &payload).await {
+                self.removals.remove(&manifest.identifier);
+            }
+        }
+        Ok(())
+    }
+
+    /// Entries without a file were deleted while the nodelet was not running.
+    fn orphans(&mut self, wanted: &BTreeSet<ResourceIdentifier>) -> Result<(), ManifestError> {
+        for doc in NodeEntry::all(&self.nodedb).query()? {
+            let entry = doc.contents;
+            if entry.state == DeploymentStatus::Archived
+                || wanted.contains(&entry.resource_identifier)
+            {
+                continue;
+            }
+            info!(identifier = %entry.resource_identifier, "entry has no manifest");
+            self.removals.insert(
+                entry.resource_identifier.clone(),
+                Manifest {
+                    identifier: entry.resource_identifier,
+                    object: entry.object,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn files(&self, subdir: &str) -> Result<Vec<PathBuf>, ManifestError> {
+        let dir = self.dir.join(subdir);
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(source) => return Err(ManifestError::Read { path: dir, source }),
+        };
+        let mut files = vec![];
+        for entry in entries {
+            let path = entry
+                .map_err(|source| ManifestError::Read {
+                    path: dir.clone(),
+                    source,
+                })?
+                .path();
+            if matches!(
+                path.extension().and_then(|ext| ext.to_str()),
+                Some("kdl" | "yaml" | "yml")
+            ) {
+                files.push(path);
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// Through the handler and in the lane of the resource, exactly like a message from
+    /// the broker.
+    async fn handle(
+        &self,
+        handler: &Handler,
+        reports: &UnboundedSender<DeploymentReport>,
+        manifest: &Manifest,
+        payload: &[u8],
+    ) -> bool {
+        debug!(identifier = %manifest.identifier, "applying manifest");
+        let _lane = self.locks.enter(&LaneKey::from(&manifest.identifier)).await;
+        match handler.handle(manifest.routing_key(), payload).await {
+            Ok(report) => {
+                if reports.send(report).is_err() {
+                    error!("report channel closed");
+                }
+                true
+            }
+            Err(e) => {
+                error!(identifier = %manifest.identifier, error = %e, "failed to apply manifest");
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::driver::ZoneState;
+    use crate::testing::{fixture, identifier, Fixture};
+
+    const WEB: &str = r#"
+        brand "linked-pkg"
+        autoboot true
+        ip-type "exclusive"
+    "#;
+
+    const LAN: &str = r#"
+        tenant "example.org"
+        name "lan"
+        (local)vswitch "internal"
+    "#;
+
+    fn write(dir: &Path, path: &str, text: &str) -> PathBuf {
+        let path = dir.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    async fn sync(watcher: &mut ManifestWatcher, fixture: &Fixture) {
+        let (reports, _receiver) = mpsc::unbounded_channel();
+        watcher.sync(&fixture.handler, &reports).await.unwrap();
+    }
+
+    fn watcher(fixture: &Fixture) -> ManifestWatcher {
+        ManifestWatcher::new(
+            fixture.dir.path().join("manifests"),
+            fixture.nodedb.clone(),
+            LaneLocks::default(),
+        )
+    }
+
+    fn entry(fixture: &Fixture, identifier: &ResourceIdentifier) -> Option<NodeEntry> {
+        NodeEntry::get(identifier, &fixture.nodedb)
+            .unwrap()
+            .map(|doc| doc.contents)
+    }
+
+    #[test]
+    fn files_are_named_after_their_resource() {
+        let dir = tempfile::tempdir().unwrap();
+        let zone = Manifest::read(
+            &write(dir.path(), "zones/web@1.2.0.kdl", WEB),
+            ResourceKind::Zone,
+        )
+        .unwrap();
+        assert_eq!(zone.identifier, identifier("res:/web@1.2.0"));
+        assert!(matches!(zone.object, NodeObject::Zone(_)));
+
+        let zone =
+            Manifest::read(&write(dir.path(), "zones/db.kdl", WEB), ResourceKind::Zone).unwrap();
+        assert_eq!(zone.identifier, identifier("res:/db@0.0.0"));
+
+        // Networks name their tenant, and may name themselves.
+        let network = Manifest::read(
+            &write(dir.path(), "networks/internal.kdl", LAN),
+            ResourceKind::Network,
+        )
+        .unwrap();
+        assert_eq!(
+            network.identifier,
+            identifier("res://example.org/lan@0.0.0")
+        );
+    }
+
+    #[test]
+    fn yaml_files_are_read_and_other_files_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let yaml = "brand: LinkedPkg\nautoboot: true\nip_type: Exclusive\n";
+        let zone = Manifest::read(
+            &write(dir.path(), "zones/web.yaml", yaml),
+            ResourceKind::Zone,
+        )
+        .unwrap();
+        assert_eq!(zone.identifier, identifier("res:/web@0.0.0"));
+
+        assert!(matches!(
+            Manifest::read(
+                &write(dir.path(), "zones/web.json", "{}"),
+                ResourceKind::Zone
+            ),
+            Err(ManifestError::UnknownFormat(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn files_are_ensured_and_changes_applied_again() {
+        let fixture = fixture();
+        let manifests = fixture.dir.path().join("manifests");
+        write(&manifests, "networks/lan.kdl", LAN);
+        let zone =
+            format!("{WEB}\nnet \"eth0\" {{ network \"res://example.org/lan@0.0.0#internal\"; }}");
+        let path = write(&manifests, "zones/web.kdl", &zone);
+        let mut watcher = watcher(&fixture);
+
+        sync(&mut watcher, &fixture).await;
+        let web = fixture.driver.zone("web").unwrap();
+        assert_eq!(web.state, ZoneState::Running);
+        assert_eq!(web.interfaces.len(), 1);
+        assert!(entry(&fixture, &identifier("res://example.org/lan@0.0.0")).is_some());
+
+        write(
+            &manifests,
+            "zones/web.kdl",
+            &format!("{zone}\npackage \"pkg:/web\""),
+        );
+        sync(&mut watcher, &fixture).await;
+        assert_eq!(
+            fixture.driver.zone("web").unwrap().zone.packages,
+            ["pkg:/web"]
+        );
+
+        // A file which does not parse keeps its last good version.
+        std::fs::write(&path, "brand").unwrap();
+        sync(&mut watcher, &fixture).await;
+        assert_eq!(
+            fixture.driver.zone("web").unwrap().zone.packages,
+            ["pkg:/web"]
+        );
+    }
+
+    #[tokio::test]
+    async fn deleted_files_are_removed() {
+        let fixture = fixture();
+        let manifests = fixture.dir.path().join("manifests");
+        write(&manifests, "networks/lan.kdl", LAN);
+        let zone = write(&manifests, "zones/web.kdl", WEB);
+        let mut watcher = watcher(&fixture);
+        sync(&mut watcher, &fixture).await;
+        assert!(fixture.driver.zone("web").is_some());
+
+        std::fs::remove_file(zone).unwrap();
+        sync(&mut watcher, &fixture).await;
+        assert!(fixture.driver.zone("web").is_none());
+        assert!(matches!(
+            entry(&fixture, &identifier("res:/web@0.0.0")),
+            None | Some(NodeEntry {
+                state: DeploymentStatus::Archived,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn files_deleted_while_the_nodelet_was_stopped_are_removed() {
+        let fixture = fixture();
+        let manifests = fixture.dir.path().join("manifests");
+        let zone = write(&manifests, "zones/web.kdl", WEB);
+        write(&manifests, "zones/db.kdl", WEB);
+        sync(&mut watcher(&fixture), &fixture).await;
+        assert!(fixture.driver.zone("web").is_some());
+
+        std::fs::remove_file(zone).unwrap();
+        sync(&mut watcher(&fixture), &fixture).await;
+        assert!(fixture.driver.zone("web").is_none());
+        assert!(fixture.driver.zone("db").is_some());
+    }
+}
@@ -0,0 +1,204 @@
+//! Prometheus metrics of the nodelet. Without the `metrics` feature recording them does
+//! nothing and no endpoint is served.
+
+use std::net::SocketAddr;
+
+use serde::Deserialize;
+
+#[cfg(not(feature = "metrics"))]
+pub use self::disabled::*;
+#[cfg(feature = "metrics")]
+pub use self::enabled::*;
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct MetricsConfig {
+    /// Address of the HTTP endpoint serving `/metrics`. Not served without one.
+    #[serde(default)]
+    pub listen: Option<SocketAddr>,
+}
+
+#[cfg(feature = "metrics")]
+mod enabled {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::sync::OnceLock;
+    use std::time::Duration;
+
+    use bonsaidb::core::schema::SerializedCollection;
+    use bonsaidb::local::Database;
+    use prometheus::{
+        Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry,
+        TextEncoder,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tracing::{debug, info, warn};
+
+    use crate::NodeEntry;
+
+    struct Metrics {
+        registry: Registry,
+        messages: IntCounterVec,
+        latency: HistogramVec,
+        retries: IntCounterVec,
+        dead_letters: IntCounterVec,
+        entries: IntGaugeVec,
+    }
+
+    impl Metrics {
+        fn new() -> prometheus::Result<Self> {
+            let registry = Registry::new_custom(Some("nodelet".into()), None)?;
+            let messages = IntCounterVec::new(
+                Opts::new("messages_total", "Deployment events handled"),
+                &["routing_key", "outcome"],
+            )?;
+            // Zone installs take minutes, network changes milliseconds.
+            let latency = HistogramVec::new(
+                HistogramOpts::new("handler_duration_seconds", "Time to handle an event")
+                    .buckets(prometheus::exponential_buckets(0.01, 4.0, 9)?),
+                &["routing_key"],
+            )?;
+            let retries = IntCounterVec::new(
+                Opts::new("retries_total", "Events retried after a failure"),
+                &["routing_key"],
+            )?;
+            let dead_letters = IntCounterVec::new(
+                Opts::new("dead_letters_total", "Events given up on"),
+                &["routing_key"],
+            )?;
+            let entries = IntGaugeVec::new(
+                Opts::new("entries", "Managed entries by status"),
+                &["status"],
+            )?;
+            registry.register(Box::new(messages.clone()))?;
+            registry.register(Box::new(latency.clone()))?;
+            registry.register(Box::new(retries.clone()))?;
+            registry.register(Box::new(dead_letters.clone()))?;
+            registry.register(Box::new(entries.clone()))?;
+            Ok(Self {
+                registry,
+                messages,
+                latency,
+                retries,
+                dead_letters,
+                entries,
+            })
+        }
+    }
+
+    fn metrics() -> &'static Metrics {
+        static METRICS: OnceLock<Metrics> = OnceLock::new();
+        METRICS.get_or_init(|| Metrics::new().expect("metric definitions are valid"))
+    }
+
+    pub fn message_handled(routing_key: &str, elapsed: Duration, ok: bool) {
+        let outcome = if ok { "ok" } else { "error" };
+        metrics()
+            .messages
+            .with_label_values(&[routing_key, outcome])
+            .inc();
+        metrics()
+            .latency
+            .with_label_values(&[routing_key])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn message_rejected(routing_key: &str) {
+        metrics()
+            .messages
+            .with_label_values(&[routing_key, "rejected"])
+            .inc();
+    }
+
+    pub fn message_retried(routing_key: &str) {
+        metrics().retries.with_label_values(&[routing_key]).inc();
+    }
+
+    pub fn message_dead_lettered(routing_key: &str) {
+        metrics()
+            .dead_letters
+            .with_label_values(&[routing_key])
+            .inc();
+    }
+
+    pub async fn serve(addr: SocketAddr, nodedb: Database) -> io::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        info!(%addr, "serving metrics");
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let nodedb = nodedb.clone();
+            tokio::spawn(async move {
+                if let Err(e) = scrape(stream, &nodedb).await {
+                    debug!(error = %e, "metrics scrape failed");
+                }
+            });
+        }
+    }
+
+    /// Answer one request, only `GET /metrics` is known.
+    async fn scrape(mut stream: TcpStream, nodedb: &Database) -> io::Result<()> {
+        let mut request = [0; 1024];
+        let read = stream.read(&mut request).await?;
+        let response = if request[..read].starts_with(b"GET /metrics ") {
+            count_entries(nodedb);
+            let encoder = TextEncoder::new();
+            let mut body = vec![];
+            encoder
+                .encode(&metrics().registry.gather(), &mut body)
+                .map_err(io::Error::other)?;
+            let mut response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                encoder.format_type(),
+                body.len()
+            )
+            .into_bytes();
+            response.append(&mut body);
+            response
+        } else {
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec()
+        };
+        stream.write_all(&response).await?;
+        stream.shutdown().await
+    }
+
+    /// Entries are counted on every scrape, they change in too many places to track.
+    fn count_entries(nodedb: &Database) {
+        let entries = match NodeEntry::all(nodedb).query() {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!(error = %e, "failed to count entries");
+                return;
+            }
+        };
+        metrics().entries.reset();
+        for doc in entries {
+            metrics()
+                .entries
+                .with_label_values(&[&format!("{:?}", doc.contents.state)])
+                .inc();
+        }
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+mod disabled {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use bonsaidb::local::Database;
+    use tracing::warn;
+
+    pub fn message_handled(_routing_key: &str, _elapsed: Duration, _ok: bool) {}
+
+    pub fn message_rejected(_routing_key: &str) {}
+
+    pub fn message_retried(_routing_key: &str) {}
+
+    pub fn message_dead_lettered(_routing_key: &str) {}
+
+    pub async fn serve(addr: SocketAddr, _nodedb: Database) -> io::Result<()> {
+        warn!(%addr, "built without the metrics feature, not serving metrics");
+        Ok(())
+    }
+}
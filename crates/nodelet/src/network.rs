@@ -0,0 +1,547 @@
+use bonsaidb::core::schema::SerializedCollection;
+use bonsaidb::local::Database;
+use std::net::IpAddr;
+
+use cloud::ResourceIdentifier;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    Bandwidth, DeploymentStatus, Ipv6Mode, LinkProtection, MacAddress, MacAddressPolicy, Network,
+    NetworkInterface, NetworkReference, NodeEntry, NodeObject, VSwitch, VSwitchKind, Zone,
+};
+
+#[derive(Debug, Error)]
+pub enum NetworkError {
+    #[error("network {0} is not present on this node")]
+    NetworkNotFound(ResourceIdentifier),
+
+    #[error("network {network} has no switch named {switch}")]
+    SwitchNotFound {
+        network: ResourceIdentifier,
+        switch: String,
+    },
+
+    #[error("{0} is not a network")]
+    NotANetwork(ResourceIdentifier),
+
+    #[error("external switch of {network} points at public network {public} which is not present on this node")]
+    PublicNetworkNotFound {
+        network: ResourceIdentifier,
+        public: String,
+    },
+
+    #[error("public network {0} has no switch zones can attach to")]
+    NoAttachableSwitch(String),
+
+    #[error("interface {0} needs either a network or a physical link")]
+    UnattachedInterface(String),
+
+    #[error("interface {0} uses static IPv6 but has no IPv6 allowed-address")]
+    NoStaticIpv6Address(String),
+
+    #[error(transparent)]
+    BonsaidbCore(#[from] bonsaidb::core::Error),
+}
+
+/// Source of the networks known to this node.
+pub trait NetworkLookup {
+    fn network(&self, identifier: &ResourceIdentifier) -> Result<Option<Network>, NetworkError>;
+
+    /// Find a network by `tenant/name` as used by external switches.
+    fn network_by_name(
+        &self,
+        tenant: Option<&str>,
+        name: &str,
+    ) -> Result<Option<(ResourceIdentifier, Network)>, NetworkError>;
+}
+
+impl NetworkLookup for Database {
+    fn network(&self, identifier: &ResourceIdentifier) -> Result<Option<Network>, NetworkError> {
+        match NodeEntry::get(identifier, self)? {
+            None => Ok(None),
+            // Removed networks are kept as tombstones, zones must not attach to them.
+            Some(doc) if doc.contents.state == DeploymentStatus::Archived => Ok(None),
+            Some(doc) => match doc.contents.object {
+                NodeObject::Network(network) => Ok(Some(network)),
+                _ => Err(NetworkError::NotANetwork(identifier.clone())),
+            },
+        }
+    }
+
+    fn network_by_name(
+        &self,
+        tenant: Option<&str>,
+        name: &str,
+    ) -> Result<Option<(ResourceIdentifier, Network)>, NetworkError> {
+        let found = NodeEntry::all(self)
+            .query()?
+            .into_iter()
+            .map(|doc| doc.contents)
+            .filter(|entry| entry.state != DeploymentStatus::Archived)
+            .filter_map(|entry| match entry.object {
+                NodeObject::Network(network) => Some((entry.resource_identifier, network)),
+                _ => None,
+            })
+            .filter(|(_, network)| {
+                network.tenant.as_deref() == tenant && network.name.as_deref() == Some(name)
+            })
+            .max_by(|(a, _), (b, _)| a.cmp_version(b));
+        Ok(found)
+    }
+}
+
+/// The datalinks the nodelet maintains on this node for a [`Network`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkPlan {
+    pub etherstubs: Vec<EtherstubPlan>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EtherstubPlan {
+    pub switch: String,
+    pub link: String,
+    pub mtu: Option<u32>,
+}
+
+impl NetworkPlan {
+    pub fn new(network: &Network) -> Self {
+        let etherstubs = network
+            .switches
+            .iter()
+            .filter(|switch| !matches!(switch.kind, VSwitchKind::External))
+            .map(|switch| EtherstubPlan {
+                switch: switch.name.clone(),
+                link: etherstub_name(network, switch),
+                mtu: switch.mtu,
+            })
+            .collect();
+        Self { etherstubs }
+    }
+}
+
+/// A zone interface after its network reference has been resolved against this node.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InterfacePlan {
+    pub name: Option<String>,
+    /// The link handed to zonecfg as `physical`.
+    pub physical: String,
+    /// The etherstub the VNIC for `physical` is created on. None for host provided links.
+    pub lower_link: Option<String>,
+    pub allowed_address: Vec<IpNet>,
+    pub defrouter: Vec<IpAddr>,
+    pub ipv6: Option<Ipv6Mode>,
+    pub vlan: Option<u16>,
+    pub mtu: Option<u32>,
+    /// Either [`MacAddressPolicy::Fixed`] or [`MacAddressPolicy::Random`], derived
+    /// addresses are computed while planning.
+    pub mac: Option<MacAddressPolicy>,
+    pub maxbw: Option<Bandwidth>,
+    pub protection: Vec<LinkProtection>,
+}
+
+/// Resolve the interfaces of a zone to links on this node.
+pub fn resolve_interfaces<L: NetworkLookup>(
+    lookup: &L,
+    identifier: &ResourceIdentifier,
+    zone: &Zone,
+) -> Result<Vec<InterfacePlan>, NetworkError> {
+    zone.network
+        .iter()
+        .enumerate()
+        .map(|(index, interface)| resolve_interface(lookup, identifier, index, interface))
+        .collect()
+}
+
+fn resolve_interface<L: NetworkLookup>(
+    lookup: &L,
+    identifier: &ResourceIdentifier,
+    index: usize,
+    interface: &NetworkInterface,
+) -> Result<InterfacePlan, NetworkError> {
+    let display_name = || {
+        interface
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("#{index}"))
+    };
+    if interface.ipv6 == Some(Ipv6Mode::Static)
+        && !interface
+            .allowed_address
+            .iter()
+            .any(|address| matches!(address, IpNet::V6(_)))
+    {
+        return Err(NetworkError::NoStaticIpv6Address(display_name()));
+    }
+
+    let (physical, lower_link, switch) = match (&interface.network, &interface.physical) {
+        (Some(reference), _) => {
+            let (link, switch) = resolve_switch(lookup, reference)?;
+            (vnic_name(identifier, index), Some(link), Some(switch))
+        }
+        (None, Some(physical)) => (physical.clone(), None, None),
+        (None, None) => return Err(NetworkError::UnattachedInterface(display_name())),
+    };
+
+    let mac = interface.mac.map(|policy| match policy {
+        MacAddressPolicy::Derived => MacAddressPolicy::Fixed(derived_mac(identifier, index)),
+        policy => policy,
+    });
+
+    Ok(InterfacePlan {
+        name: interface.name.clone(),
+        physical,
+        lower_link,
+        allowed_address: interface.allowed_address.clone(),
+        defrouter: interface.defrouter.clone(),
+        ipv6: interface.ipv6,
+        vlan: interface
+            .vlan
+            .or_else(|| switch.as_ref().and_then(|switch| switch.vlan)),
+        mtu: interface
+            .mtu
+            .or_else(|| switch.as_ref().and_then(|switch| switch.mtu)),
+        mac,
+        maxbw: interface
+            .maxbw
+            .or_else(|| switch.as_ref().and_then(|switch| switch.maxbw)),
+        protection: interface.protection.clone(),
+    })
+}
+
+/// Find the switch a reference points at and the etherstub backing it. For external
+/// switches this is the switch of the public network.
+fn resolve_switch<L: NetworkLookup>(
+    lookup: &L,
+    reference: &NetworkReference,
+) -> Result<(String, VSwitch), NetworkError> {
+    let network = lookup
+        .network(&reference.network)?
+        .ok_or_else(|| NetworkError::NetworkNotFound(reference.network.clone()))?;
+    let switch = network
+        .switches
+        .iter()
+        .find(|switch| switch.name == reference.switch)
+        .ok_or_else(|| NetworkError::SwitchNotFound {
+            network: reference.network.clone(),
+            switch: reference.switch.clone(),
+        })?;
+
+    if !matches!(switch.kind, VSwitchKind::External) {
+        return Ok((etherstub_name(&network, switch), switch.clone()));
+    }
+
+    // External switches are named after the public network they connect to.
+    let (tenant, name) = match switch.name.split_once('/') {
+        Some((tenant, name)) => (Some(tenant), name),
+        None => (None, switch.name.as_str()),
+    };
+    let (_, public) = lookup.network_by_name(tenant, name)?.ok_or_else(|| {
+        NetworkError::PublicNetworkNotFound {
+            network: reference.network.clone(),
+            public: switch.name.clone(),
+        }
+    })?;
+    let public_switch = public
+        .switches
+        .iter()
+        .find(|switch| !matches!(switch.kind, VSwitchKind::External))
+        .ok_or_else(|| NetworkError::NoAttachableSwitch(switch.name.clone()))?;
+    Ok((
+        etherstub_name(&public, public_switch),
+        public_switch.clone(),
+    ))
+}
+
+/// Name of the etherstub backing a switch. Derived from the tenant, network and switch
+/// name so it is the same on every node and stable across versions of the network.
+pub fn etherstub_name(network: &Network, switch: &VSwitch) -> String {
+    let seed = format!(
+        "{}/{}#{}",
+        network.tenant.as_deref().unwrap_or_default(),
+        network.name.as_deref().unwrap_or_default(),
+        switch.name
+    );
+    // illumos link names must end in a digit
+    format!("cstub{:08x}0", fnv1a(&seed))
+}
+
+/// Name of the VNIC created for the interface at `index` of a zone.
+pub fn vnic_name(identifier: &ResourceIdentifier, index: usize) -> String {
+    let seed = format!(
+        "{}/{}",
+        identifier.tenant.as_deref().unwrap_or_default(),
+        identifier.name
+    );
+    format!("cvnic{:08x}{index}", fnv1a(&seed))
+}
+
+/// Locally administered unicast address for the interface at `index` of a zone.
+pub fn derived_mac(identifier: &ResourceIdentifier, index: usize) -> MacAddress {
+    let seed = format!(
+        "{}/{}#{index}",
+        identifier.tenant.as_deref().unwrap_or_default(),
+        identifier.name
+    );
+    let [a, b, c, d] = fnv1a(&seed).to_be_bytes();
+    let [e, ..] = fnv1a(&format!("{seed}#mac")).to_be_bytes();
+    MacAddress([0x02, a, b, c, d, e])
+}
+
+fn fnv1a(input: &str) -> u32 {
+    input.bytes().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{identifier, zone};
+
+    /// Longest link name dladm takes, MAXLINKNAMELEN less the terminating NUL.
+    const MAX_LINK_NAME: usize = 31;
+
+    /// The networks of a node, without a database.
+    struct Networks(Vec<(ResourceIdentifier, Network)>);
+
+    impl NetworkLookup for Networks {
+        fn network(
+            &self,
+            identifier: &ResourceIdentifier,
+        ) -> Result<Option<Network>, NetworkError> {
+            Ok(self
+                .0
+                .iter()
+                .find(|(known, _)| known == identifier)
+                .map(|(_, network)| network.clone()))
+        }
+
+        fn network_by_name(
+            &self,
+            tenant: Option<&str>,
+            name: &str,
+        ) -> Result<Option<(ResourceIdentifier, Network)>, NetworkError> {
+            Ok(self
+                .0
+                .iter()
+                .find(|(_, network)| {
+                    network.tenant.as_deref() == tenant && network.name.as_deref() == Some(name)
+                })
+                .cloned())
+        }
+    }
+
+    fn network(kdl: &str) -> Network {
+        knuffel::parse("network.kdl", kdl).unwrap()
+    }
+
+    fn networks() -> Networks {
+        let lan = network(
+            r#"
+            tenant "example.org"
+            name "lan"
+            (distributed)vswitch "internal" {
+                vlan 100
+                mtu 9000
+                maxbw "1G"
+            }
+            (external)vswitch "provider.org/public"
+            "#,
+        );
+        let public = network(
+            r#"
+            tenant "provider.org"
+            name "public"
+            (local)vswitch "public"
+            "#,
+        );
+        Networks(vec![
+            (identifier("res://example.org/lan@1.0.0"), lan),
+            (identifier("res://provider.org/public@1.0.0"), public),
+        ])
+    }
+
+    fn web() -> ResourceIdentifier {
+        identifier("res://example.org/web@1.0.0")
+    }
+
+    fn resolve(interfaces: &str) -> Result<Vec<InterfacePlan>, NetworkError> {
+        resolve_interfaces(&networks(), &web(), &zone(interfaces))
+    }
+
+    fn is_link_name(name: &str) -> bool {
+        name.len() <= MAX_LINK_NAME
+            && name.starts_with(|c: char| c.is_ascii_lowercase())
+            && name.ends_with(|c: char| c.is_ascii_digit())
+            && name.chars().all(|c| c.is_ascii_alphanumeric())
+    }
+
+    #[test]
+    fn interfaces_get_a_vnic_on_the_etherstub_of_their_switch() {
+        let plans =
+            resolve(r#"net "eth0" { network "res://example.org/lan@1.0.0#internal"; }"#).unwrap();
+        let lan = &networks().0[0].1;
+        assert_eq!(plans.len(), 1);
+        assert_eq!(plans[0].physical, vnic_name(&web(), 0));
+        assert_eq!(
+            plans[0].lower_link,
+            Some(etherstub_name(lan, &lan.switches[0]))
+        );
+    }
+
+    #[test]
+    fn interfaces_take_vlan_mtu_and_bandwidth_from_their_switch_unless_they_set_them() {
+        let plans = resolve(
+            r#"
+            net "eth0" { network "res://example.org/lan@1.0.0#internal"; }
+            net "eth1" {
+                network "res://example.org/lan@1.0.0#internal"
+                vlan 200
+                mtu 1500
+                maxbw "100M"
+            }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(plans[0].vlan, Some(100));
+        assert_eq!(plans[0].mtu, Some(9000));
+        assert_eq!(plans[0].maxbw, Some(Bandwidth(1_000_000_000)));
+        assert_eq!(plans[1].vlan, Some(200));
+        assert_eq!(plans[1].mtu, Some(1500));
+        assert_eq!(plans[1].maxbw, Some(Bandwidth(100_000_000)));
+    }
+
+    #[test]
+    fn external_switches_attach_to_the_public_network() {
+        let plans =
+            resolve(r#"net "eth0" { network "res://example.org/lan@1.0.0#provider.org/public"; }"#)
+                .unwrap();
+        let public = &networks().0[1].1;
+        assert_eq!(
+            plans[0].lower_link,
+            Some(etherstub_name(public, &public.switches[0]))
+        );
+        // The switch of the public network has no VLAN of the tenant's switch.
+        assert_eq!(plans[0].vlan, None);
+    }
+
+    #[test]
+    fn physical_links_are_used_as_they_are() {
+        let plans = resolve(r#"net "eth0" { physical "igb0"; }"#).unwrap();
+        assert_eq!(plans[0].physical, "igb0");
+        assert_eq!(plans[0].lower_link, None);
+    }
+
+    #[test]
+    fn unknown_networks_and_switches_are_refused() {
+        assert!(matches!(
+            resolve(r#"net "eth0" { network "res://example.org/wan@1.0.0#internal"; }"#),
+            Err(NetworkError::NetworkNotFound(_))
+        ));
+        assert!(matches!(
+            resolve(r#"net "eth0" { network "res://example.org/lan@1.0.0#dmz"; }"#),
+            Err(NetworkError::SwitchNotFound { .. })
+        ));
+        assert!(matches!(
+            resolve(r#"net "eth0""#),
+            Err(NetworkError::UnattachedInterface(name)) if name == "eth0"
+        ));
+    }
+
+    #[test]
+    fn static_ipv6_needs_an_ipv6_address() {
+        assert!(matches!(
+            resolve(
+                r#"net "eth0" {
+                    physical "igb0"
+                    allowed-address "192.168.100.20/24"
+                    ipv6 "static"
+                }"#
+            ),
+            Err(NetworkError::NoStaticIpv6Address(_))
+        ));
+
+        let plans = resolve(
+            r#"net "eth0" {
+                physical "igb0"
+                allowed-address "192.168.100.20/24"
+                allowed-address "fd00:100::20/64"
+                defrouter "fd00:100::1"
+                ipv6 "static"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(plans[0].ipv6, Some(Ipv6Mode::Static));
+        assert_eq!(plans[0].allowed_address.len(), 2);
+        assert_eq!(
+            plans[0].defrouter,
+            ["fd00:100::1".parse::<IpAddr>().unwrap()]
+        );
+
+        let plans = resolve(r#"net "eth0" { physical "igb0"; ipv6 "slaac"; }"#).unwrap();
+        assert_eq!(plans[0].ipv6, Some(Ipv6Mode::Slaac));
+    }
+
+    #[test]
+    fn derived_macs_are_planned_as_fixed_addresses() {
+        let plans = resolve(r#"net "eth0" { physical "igb0"; mac "derived"; }"#).unwrap();
+        assert_eq!(
+            plans[0].mac,
+            Some(MacAddressPolicy::Fixed(derived_mac(&web(), 0)))
+        );
+    }
+
+    #[test]
+    fn derived_macs_are_stable_unicast_and_locally_administered() {
+        let mac = derived_mac(&web(), 0);
+        assert_eq!(
+            mac,
+            derived_mac(&identifier("res://example.org/web@2.0.0"), 0)
+        );
+        assert_ne!(mac, derived_mac(&web(), 1));
+        assert_ne!(
+            mac,
+            derived_mac(&identifier("res://example.com/web@1.0.0"), 0)
+        );
+        for index in 0..16 {
+            let [first, ..] = derived_mac(&web(), index).0;
+            assert_eq!(first & 0x02, 0x02, "locally administered");
+            assert_eq!(first & 0x01, 0x00, "unicast");
+        }
+    }
+
+    #[test]
+    fn link_names_fit_dladm() {
+        let lan = &networks().0[0].1;
+        let long = identifier(&format!(
+            "res://{}/{}@1.0.0",
+            "t".repeat(64),
+            "n".repeat(64)
+        ));
+        for name in [
+            etherstub_name(lan, &lan.switches[0]),
+            vnic_name(&web(), 0),
+            vnic_name(&long, 0),
+            vnic_name(&long, 9999),
+        ] {
+            assert!(is_link_name(&name), "{name}");
+        }
+    }
+
+    #[test]
+    fn link_names_are_stable_across_versions() {
+        let web2 = identifier("res://example.org/web@2.0.0");
+        assert_eq!(vnic_name(&web(), 0), vnic_name(&web2, 0));
+        assert_ne!(vnic_name(&web(), 0), vnic_name(&web(), 1));
+
+        let lan = &networks().0[0].1;
+        let mut other = lan.clone();
+        other.name = Some("wan".to_owned());
+        assert_ne!(
+            etherstub_name(lan, &lan.switches[0]),
+            etherstub_name(&other, &other.switches[0])
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use cloud::{DeploymentReport, DeploymentState, Progress, ResourceIdentifier};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::warn;
+
+/// Lines of output kept for the next report, older ones are dropped.
+const LOG_EXCERPT_LINES: usize = 20;
+/// Output is batched, so a chatty package install does not flood the report exchange.
+const LOG_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ReportKind {
+    Ensure,
+    Remove,
+}
+
+/// Sends status reports, reports without a result, while an event is handled.
+pub struct ProgressReporter {
+    identifier: ResourceIdentifier,
+    kind: ReportKind,
+    reports: UnboundedSender<DeploymentReport>,
+    current: Mutex<Current>,
+}
+
+struct Current {
+    state: DeploymentState,
+    step: String,
+    percent: Option<u8>,
+    log: VecDeque<String>,
+    sent_at: Instant,
+}
+
+impl ProgressReporter {
+    pub fn new(
+        identifier: ResourceIdentifier,
+        kind: ReportKind,
+        reports: UnboundedSender<DeploymentReport>,
+    ) -> Self {
+        Self {
+            identifier,
+            kind,
+            reports,
+            current: Mutex::new(Current {
+                state: DeploymentState::Configured,
+                step: String::new(),
+                percent: None,
+                log: VecDeque::new(),
+                sent_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Start the next step and report it right away.
+    pub fn step(&self, state: DeploymentState, step: &str, percent: Option<u8>) {
+        let mut current = self.current.lock().unwrap();
+        if !current.log.is_empty() {
+            self.send(&mut current);
+        }
+        current.state = state;
+        current.step = step.to_owned();
+        current.percent = percent;
+        self.send(&mut current);
+    }
+
+    /// Record a line of output of the current step.
+    pub fn log(&self, line: &str) {
+        let mut current = self.current.lock().unwrap();
+        if current.log.len() == LOG_EXCERPT_LINES {
+            current.log.pop_front();
+        }
+        current.log.push_back(line.to_owned());
+        if current.sent_at.elapsed() >= LOG_INTERVAL {
+            self.send(&mut current);
+        }
+    }
+
+    /// Report the output recorded since the last report right away.
+    pub fn flush(&self) {
+        let mut current = self.current.lock().unwrap();
+        if !current.log.is_empty() {
+            self.send(&mut current);
+        }
+    }
+
+    fn send(&self, current: &mut Current) {
+        let progress = Some(Progress {
+            step: current.step.clone(),
+            percent: current.percent,
+            log: current.log.drain(..).collect(),
+        });
+        let identifier = self.identifier.clone();
+        let state = current.state.clone();
+        let report = match self.kind {
+            ReportKind::Ensure => DeploymentReport::Ensure {
+                identifier,
+                state,
+                result: None,
+                changes: vec![],
+                progress,
+            },
+            ReportKind::Remove => DeploymentReport::Remove {
+                identifier,
+                state,
+                result: None,
+                progress,
+            },
+        };
+        current.sent_at = Instant::now();
+        if self.reports.send(report).is_err() {
+            warn!("report channel closed");
+        }
+    }
+}
@@ -0,0 +1,418 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bonsaidb::core::schema::SerializedCollection;
+use bonsaidb::local::Database;
+use cloud::{DeploymentReport, DeploymentState, ResourceIdentifier};
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::driver::{zone_name, DriverError, ZoneDriver, ZoneState};
+use crate::workers::{LaneKey, LaneLocks};
+use crate::{DeploymentStatus, NodeEntry, NodeObject};
+
+#[derive(Debug, Error)]
+pub enum ReconcileError {
+    #[error(transparent)]
+    BonsaidbCore(#[from] bonsaidb::core::Error),
+
+    #[error(transparent)]
+    Driver(#[from] DriverError),
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ReconcileConfig {
+    /// Seconds between two passes over all entries.
+    #[serde(default = "default_interval")]
+    pub interval_secs: u64,
+    /// Upper bound for the delay between repair attempts of a failing entry.
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff_secs: u64,
+    /// Fix drift instead of only reporting it.
+    #[serde(default = "default_repair")]
+    pub repair: bool,
+}
+
+fn default_interval() -> u64 {
+    30
+}
+
+fn default_max_backoff() -> u64 {
+    600
+}
+
+fn default_repair() -> bool {
+    true
+}
+
+impl Default for ReconcileConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: default_interval(),
+            max_backoff_secs: default_max_backoff(),
+            repair: default_repair(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Backoff {
+    failures: u32,
+    next_attempt: Instant,
+}
+
+/// Compares the stored [`NodeEntry`]s with what the driver observes and repairs or
+/// reports the difference.
+///
+/// Zones an event is working on are left to it, the reconciler takes the lane of a zone
+/// before it looks at it.
+pub struct Reconciler {
+    nodedb: Database,
+    driver: Arc<dyn ZoneDriver>,
+    config: ReconcileConfig,
+    reports: UnboundedSender<DeploymentReport>,
+    locks: LaneLocks,
+    backoff: HashMap<ResourceIdentifier, Backoff>,
+    reported: HashMap<ResourceIdentifier, DeploymentState>,
+    trigger: Arc<Notify>,
+}
+
+impl Reconciler {
+    pub fn new(
+        nodedb: Database,
+        driver: Arc<dyn ZoneDriver>,
+        config: ReconcileConfig,
+        reports: UnboundedSender<DeploymentReport>,
+        locks: LaneLocks,
+    ) -> Self {
+        Self {
+            nodedb,
+            driver,
+            config,
+            reports,
+            locks,
+            backoff: HashMap::new(),
+            reported: HashMap::new(),
+            trigger: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Notify it to start a pass right away instead of waiting for the interval.
+    pub fn trigger(&self) -> Arc<Notify> {
+        self.trigger.clone()
+    }
+
+    pub async fn run(mut self) {
+        let mut interval = tokio::time::interval(Duration::from_secs(self.config.interval_secs));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = self.trigger.notified() => debug!("reconcile triggered"),
+            }
+            if let Err(e) = self.reconcile().await {
+                error!(error = %e, "reconcile pass failed");
+            }
+        }
+    }
+
+    /// One pass over every stored zone.
+    #[instrument(skip(self))]
+    pub async fn reconcile(&mut self) -> Result<(), ReconcileError> {
+        for doc in NodeEntry::all(&self.nodedb).query()? {
+            let identifier = doc.contents.resource_identifier;
+            if !matches!(doc.contents.object, NodeObject::Zone(_)) {
+                continue;
+            }
+            let Some(_lane) = self.locks.try_enter(&LaneKey::from(&identifier)) else {
+                debug!(%identifier, "zone is being worked on, leaving it to its event");
+                continue;
+            };
+            // The event which held the lane may have changed or removed the entry.
+            let Some(entry) = NodeEntry::get(&identifier, &self.nodedb)? else {
+                continue;
+            };
+            let Some(desired) = desired_state(&entry.contents.state) else {
+                continue;
+            };
+            // One zone the driver cannot query must not hold up the others.
+            if let Err(e) = self.reconcile_zone(&identifier, desired).await {
+                let delay = self.record_failure(&identifier);
+                warn!(%identifier, error = %e, ?delay, "cannot reconcile zone");
+            }
+        }
+        Ok(())
+    }
+
+    async fn reconcile_zone(
+        &mut self,
+        identifier: &ResourceIdentifier,
+        desired: ZoneState,
+    ) -> Result<(), ReconcileError> {
+        if let Some(backoff) = self.backoff.get(identifier) {
+            if backoff.next_attempt > Instant::now() {
+                return Ok(());
+            }
+        }
+        let name = zone_name(identifier);
+        let observed = self.driver.state(&name).await?;
+        if observed == desired {
+            self.backoff.remove(identifier);
+            self.report(identifier, observed, None);
+            return Ok(());
+        }
+        debug!(zone = name, %observed, %desired, "zone drifted");
+
+        if observed == ZoneState::Missing || !self.config.repair {
+            self.report(identifier, observed, None);
+            return Ok(());
+        }
+
+        let repair = match (observed, desired) {
+            (ZoneState::Installed | ZoneState::Ready, ZoneState::Running) => {
+                self.driver.boot(&name).await
+            }
+            (ZoneState::Down, ZoneState::Running) => match self.driver.halt(&name).await {
+                Ok(()) => self.driver.boot(&name).await,
+                Err(e) => Err(e),
+            },
+            (ZoneState::Running | ZoneState::Ready, ZoneState::Installed) => {
+                self.driver.halt(&name).await
+            }
+            _ => {
+                // Needs a reinstall, which only a new Ensure may trigger.
+                self.report(identifier, observed, None);
+                return Ok(());
+            }
+        };
+
+        match repair {
+            Ok(()) => {
+                info!(zone = name, %desired, "repaired drift");
+                self.backoff.remove(identifier);
+                self.report(identifier, desired, Some(Ok(())));
+            }
+            Err(e) => {
+                let delay = self.record_failure(identifier);
+                warn!(zone = name, error = %e, ?delay, "failed to repair drift");
+                self.reported.remove(identifier);
+                self.report(
+                    identifier,
+                    observed,
+                    Some(Err(format!("failed to repair drift: {e}"))),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Put off the next attempt on an entry, longer with every failure in a row.
+    fn record_failure(&mut self, identifier: &ResourceIdentifier) -> Duration {
+        let failures = self
+            .backoff
+            .get(identifier)
+            .map(|backoff| backoff.failures + 1)
+            .unwrap_or(1);
+        let delay = self.backoff_delay(failures);
+        self.backoff.insert(
+            identifier.clone(),
+            Backoff {
+                failures,
+                next_attempt: Instant::now() + delay,
+            },
+        );
+        delay
+    }
+
+    fn backoff_delay(&self, failures: u32) -> Duration {
+        let delay = self
+            .config
+            .interval_secs
+            .saturating_mul(2u64.saturating_pow(failures));
+        Duration::from_secs(delay.min(self.config.max_backoff_secs))
+    }
+
+    /// Send a report when the observed state changed since the last one.
+    fn report(
+        &mut self,
+        identifier: &ResourceIdentifier,
+        observed: ZoneState,
+        result: Option<Result<(), String>>,
+    ) {
+        let state = observed_state(observed);
+        if result.is_none() && self.reported.get(identifier) == Some(&state) {
+            return;
+        }
+        self.reported.insert(identifier.clone(), state.clone());
+        let report = DeploymentReport::Ensure {
+            identifier: identifier.clone(),
+            state,
+            result,
+            changes: vec![],
+            progress: None,
+        };
+        if self.reports.send(report).is_err() {
+            warn!("report channel closed");
+        }
+    }
+}
+
+fn desired_state(status: &DeploymentStatus) -> Option<ZoneState> {
+    match status {
+        DeploymentStatus::Starting | DeploymentStatus::Started => Some(ZoneState::Running),
+        DeploymentStatus::Installed | DeploymentStatus::Stopping | DeploymentStatus::Stopped => {
+            Some(ZoneState::Installed)
+        }
+        DeploymentStatus::Configured
+        | DeploymentStatus::Uninstalling
+        | DeploymentStatus::Archived => None,
+    }
+}
+
+/// How a zone state is reported to the controller. A zone which is gone while the node
+/// still has an entry for it is orphaned.
+pub fn observed_state(state: ZoneState) -> DeploymentState {
+    match state {
+        ZoneState::Missing => DeploymentState::Orphaned,
+        ZoneState::Configured | ZoneState::Incomplete => DeploymentState::Configured,
+        ZoneState::Installed => DeploymentState::Installed,
+        ZoneState::Ready => DeploymentState::Starting,
+        ZoneState::Running => DeploymentState::Started,
+        ZoneState::ShuttingDown => DeploymentState::Stopping,
+        ZoneState::Down => DeploymentState::Stopped,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+    use super::*;
+    use crate::driver::{FakeDriverConfig, FakeZoneDriver, Operation};
+    use crate::testing::{identifier, memory_database, store, zone};
+
+    /// An installed zone whose entry wants it running.
+    async fn installed(
+        driver: &FakeZoneDriver,
+        nodedb: &Database,
+        identifier: &ResourceIdentifier,
+    ) {
+        let name = zone_name(identifier);
+        driver.configure(&name, &zone(""), &[]).await.unwrap();
+        driver
+            .install(&name, &zone(""), None, &|_| {})
+            .await
+            .unwrap();
+        store(nodedb, identifier, zone(""), DeploymentStatus::Started);
+    }
+
+    fn reconciler(
+        nodedb: &Database,
+        driver: &Arc<FakeZoneDriver>,
+        locks: &LaneLocks,
+    ) -> (Reconciler, UnboundedReceiver<DeploymentReport>) {
+        let (reports, receiver) = mpsc::unbounded_channel();
+        let reconciler = Reconciler::new(
+            nodedb.clone(),
+            driver.clone(),
+            ReconcileConfig::default(),
+            reports,
+            locks.clone(),
+        );
+        (reconciler, receiver)
+    }
+
+    fn states(reports: &mut UnboundedReceiver<DeploymentReport>) -> Vec<DeploymentState> {
+        let mut states = vec![];
+        while let Ok(DeploymentReport::Ensure { state, .. }) = reports.try_recv() {
+            states.push(state);
+        }
+        states
+    }
+
+    #[tokio::test]
+    async fn a_stopped_zone_is_booted_again() {
+        let nodedb = memory_database();
+        let driver = Arc::new(FakeZoneDriver::new(FakeDriverConfig::default()));
+        let web = identifier("res://example.org/web@1.0.0");
+        installed(&driver, &nodedb, &web).await;
+        let (mut reconciler, mut reports) = reconciler(&nodedb, &driver, &LaneLocks::default());
+
+        reconciler.reconcile().await.unwrap();
+
+        assert_eq!(
+            driver.zone(&zone_name(&web)).unwrap().state,
+            ZoneState::Running
+        );
+        assert_eq!(states(&mut reports), [DeploymentState::Started]);
+    }
+
+    #[tokio::test]
+    async fn a_missing_zone_is_reported_and_not_recreated() {
+        let nodedb = memory_database();
+        let driver = Arc::new(FakeZoneDriver::new(FakeDriverConfig::default()));
+        let web = identifier("res://example.org/web@1.0.0");
+        store(&nodedb, &web, zone(""), DeploymentStatus::Started);
+        let (mut reconciler, mut reports) = reconciler(&nodedb, &driver, &LaneLocks::default());
+
+        reconciler.reconcile().await.unwrap();
+        reconciler.reconcile().await.unwrap();
+
+        assert!(driver.zone(&zone_name(&web)).is_none());
+        // Once, until the state changes.
+        assert_eq!(states(&mut reports), [DeploymentState::Orphaned]);
+    }
+
+    #[tokio::test]
+    async fn a_zone_being_removed_is_left_to_its_event() {
+        let nodedb = memory_database();
+        let driver = Arc::new(FakeZoneDriver::new(FakeDriverConfig::default()));
+        let web = identifier("res://example.org/web@1.0.0");
+        installed(&driver, &nodedb, &web).await;
+        let locks = LaneLocks::default();
+        let (mut reconciler, mut reports) = reconciler(&nodedb, &driver, &locks);
+
+        // The Remove holds the lane while it works, and takes the entry down with it.
+        let remove = locks.enter(&LaneKey::from(&web)).await;
+        reconciler.reconcile().await.unwrap();
+        store(&nodedb, &web, zone(""), DeploymentStatus::Uninstalling);
+        drop(remove);
+        reconciler.reconcile().await.unwrap();
+
+        assert_eq!(
+            driver.zone(&zone_name(&web)).unwrap().state,
+            ZoneState::Installed
+        );
+        assert!(states(&mut reports).is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_zone_which_cannot_be_queried_does_not_stop_the_pass() {
+        let nodedb = memory_database();
+        let driver = Arc::new(FakeZoneDriver::new(FakeDriverConfig::default()));
+        let zones = [
+            identifier("res://example.org/a@1.0.0"),
+            identifier("res://example.org/b@1.0.0"),
+        ];
+        for identifier in &zones {
+            installed(&driver, &nodedb, identifier).await;
+        }
+        let (mut reconciler, _reports) = reconciler(&nodedb, &driver, &LaneLocks::default());
+
+        driver.inject_failure(Operation::State, Some(1));
+        reconciler.reconcile().await.unwrap();
+
+        let state = |identifier| driver.zone(&zone_name(identifier)).unwrap().state;
+        assert_eq!(state(&zones[0]), ZoneState::Installed);
+        assert_eq!(state(&zones[1]), ZoneState::Running);
+        assert!(reconciler.backoff.contains_key(&zones[0]));
+
+        // Backing off, the zone is left alone on the next pass.
+        reconciler.reconcile().await.unwrap();
+        assert_eq!(state(&zones[0]), ZoneState::Installed);
+    }
+}
@@ -0,0 +1,95 @@
+use std::path::PathBuf;
+
+use cloud::{Capacity, NodeFacts, NodeRegistration};
+use serde::Deserialize;
+
+use crate::secrets::{NodeKey, SecretError};
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct NodeConfig {
+    /// Name the node registers with, defaults to the hostname.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// `key=value` pairs deployments select nodes by.
+    #[serde(default)]
+    pub labels: Vec<String>,
+    /// Announced on top of the ones the nodelet detects itself.
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// Overrides the detected capacity.
+    #[serde(default)]
+    pub capacity: Option<Capacity>,
+    #[serde(default = "default_heartbeat")]
+    pub heartbeat_secs: u64,
+    /// The key secrets are sealed for this node with, generated on the first start. Keep
+    /// it readable by the nodelet only.
+    #[serde(default = "default_key_file")]
+    pub key_file: PathBuf,
+}
+
+fn default_heartbeat() -> u64 {
+    15
+}
+
+fn default_key_file() -> PathBuf {
+    PathBuf::from("./target/nodelet.key")
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        Self {
+            name: None,
+            labels: vec![],
+            capabilities: vec![],
+            capacity: None,
+            heartbeat_secs: default_heartbeat(),
+            key_file: default_key_file(),
+        }
+    }
+}
+
+impl NodeConfig {
+    pub fn name(&self) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| gethostname::gethostname().to_string_lossy().to_string())
+    }
+
+    pub fn key(&self) -> Result<NodeKey, SecretError> {
+        NodeKey::load_or_generate(&self.key_file)
+    }
+
+    pub fn registration(&self, driver: &str, facts: NodeFacts, key: &NodeKey) -> NodeRegistration {
+        let mut capabilities = vec![
+            format!("driver={driver}"),
+            "brand=linked-pkg".to_owned(),
+            "brand=unlinked-pkg".to_owned(),
+            "vswitch=local".to_owned(),
+            "vswitch=distributed".to_owned(),
+            "vswitch=external".to_owned(),
+            // Interfaces and switches may set a VLAN id on their VNICs.
+            "vlan".to_owned(),
+        ];
+        capabilities.extend(self.capabilities.iter().cloned());
+
+        let capacity = self.capacity.clone().unwrap_or_else(|| Capacity {
+            cpus: facts.cpus.unwrap_or_else(|| {
+                std::thread::available_parallelism()
+                    .map(|cpus| cpus.get() as u32)
+                    .unwrap_or(1)
+            }),
+            memory_mb: facts.memory_mb.unwrap_or_default(),
+            max_zones: None,
+        });
+
+        NodeRegistration {
+            name: self.name(),
+            labels: self.labels.clone(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            capabilities,
+            capacity,
+            facts,
+            encryption_key: Some(key.public_key()),
+        }
+    }
+}
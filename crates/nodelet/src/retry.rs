@@ -0,0 +1,144 @@
+use std::time::Duration;
+
+use deadpool_lapin::lapin::types::{AMQPValue, FieldTable, LongString, ShortString};
+use serde::Deserialize;
+
+/// Failed deliveries which ran out of attempts end up here.
+pub const DEAD_LETTER_EXCHANGE: &str = "deployment.nodelet.dead";
+
+/// How often a message was retried already.
+pub const RETRY_COUNT_HEADER: &str = "x-retry-count";
+/// Dead-lettered messages are replayed onto the node's queue directly, this keeps the
+/// routing key the handler needs.
+pub const ROUTING_KEY_HEADER: &str = "x-original-routing-key";
+pub const ERROR_HEADER: &str = "x-last-error";
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RetryConfig {
+    /// Deliveries of a message before it is dead-lettered, the first one included.
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for every further one.
+    #[serde(default = "default_base_delay")]
+    pub base_delay_ms: u64,
+    #[serde(default = "default_max_delay")]
+    pub max_delay_ms: u64,
+}
+
+fn default_max_attempts() -> u32 {
+    5
+}
+
+fn default_base_delay() -> u64 {
+    1000
+}
+
+fn default_max_delay() -> u64 {
+    60_000
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_max_attempts(),
+            base_delay_ms: default_base_delay(),
+            max_delay_ms: default_max_delay(),
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Delay before retry number `retry`, counting from 1.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = 2u64.saturating_pow(retry.saturating_sub(1));
+        Duration::from_millis(
+            self.base_delay_ms
+                .saturating_mul(factor)
+                .min(self.max_delay_ms),
+        )
+    }
+
+    /// Whether a delivery that failed after `retries` retries gets another one.
+    pub fn should_retry(&self, retries: u32) -> bool {
+        retries + 1 < self.max_attempts
+    }
+}
+
+/// The retry bookkeeping carried in the headers of a delivery.
+#[derive(Debug, Clone, Default)]
+pub struct RetryState {
+    pub retries: u32,
+    pub routing_key: Option<String>,
+}
+
+impl RetryState {
+    pub fn from_headers(headers: Option<&FieldTable>) -> Self {
+        let Some(headers) = headers else {
+            return Self::default();
+        };
+        let retries = match headers.inner().get(&ShortString::from(RETRY_COUNT_HEADER)) {
+            Some(AMQPValue::LongUInt(retries)) => *retries,
+            Some(AMQPValue::LongInt(retries)) => u32::try_from(*retries).unwrap_or_default(),
+            Some(AMQPValue::LongLongInt(retries)) => u32::try_from(*retries).unwrap_or_default(),
+            _ => 0,
+        };
+        let routing_key = match headers.inner().get(&ShortString::from(ROUTING_KEY_HEADER)) {
+            Some(AMQPValue::LongString(key)) => {
+                Some(String::from_utf8_lossy(key.as_bytes()).into_owned())
+            }
+            _ => None,
+        };
+        Self {
+            retries,
+            routing_key,
+        }
+    }
+
+    /// Headers for the next delivery of a message that failed with `error`.
+    pub fn next_headers(&self, routing_key: &str, error: &str) -> FieldTable {
+        let mut headers = FieldTable::default();
+        headers.insert(
+            ShortString::from(RETRY_COUNT_HEADER),
+            AMQPValue::LongUInt(self.retries + 1),
+        );
+        headers.insert(
+            ShortString::from(ROUTING_KEY_HEADER),
+            AMQPValue::LongString(LongString::from(routing_key)),
+        );
+        headers.insert(
+            ShortString::from(ERROR_HEADER),
+            AMQPValue::LongString(LongString::from(error)),
+        );
+        headers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_retry_count_is_carried_from_one_delivery_to_the_next() {
+        let first = RetryState::default();
+        let second = RetryState::from_headers(Some(&first.next_headers("zones.node1", "failed")));
+        assert_eq!(second.retries, 1);
+        assert_eq!(second.routing_key.as_deref(), Some("zones.node1"));
+        let third = RetryState::from_headers(Some(&second.next_headers("zones.node1", "failed")));
+        assert_eq!(third.retries, 2);
+    }
+
+    #[test]
+    fn delays_double_up_to_the_maximum() {
+        let config = RetryConfig {
+            max_attempts: 5,
+            base_delay_ms: 1000,
+            max_delay_ms: 3000,
+        };
+        let delays: Vec<_> = (1..=3)
+            .map(|retry| config.delay(retry).as_millis())
+            .collect();
+        assert_eq!(delays, [1000, 2000, 3000]);
+        assert!(config.should_retry(3));
+        assert!(!config.should_retry(4));
+    }
+}
@@ -0,0 +1,121 @@
+//! Secrets travel from cloudadmd to the nodes in sealed boxes, X25519 and
+//! XSalsa20-Poly1305 with a throwaway sender key, so only the node they are meant for can
+//! open them. Nodes keep them sealed and only open them to write them into a zone.
+
+use std::fmt::{Debug, Formatter};
+use std::path::Path;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use crypto_box::{PublicKey, SecretKey};
+use rand_core::OsRng;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::bus;
+use crate::keyfile::{self, KeyFileError, KEY_LENGTH};
+
+#[derive(Debug, Error)]
+pub enum SecretError {
+    #[error(transparent)]
+    KeyFile(#[from] KeyFileError),
+
+    #[error("{0} is not a base64 encoded X25519 key")]
+    InvalidKey(String),
+
+    #[error("secret is sealed for the node key {0}, not this node's")]
+    WrongRecipient(String),
+
+    #[error("secret cannot be opened, it is corrupt or was sealed for another key")]
+    Unseal,
+
+    #[error("cannot seal secret")]
+    Seal,
+}
+
+/// The routing key of the secrets published for the node `name`.
+pub fn routing_key(node: &str) -> String {
+    bus::routing_key(bus::SECRET_TOPIC, node)
+}
+
+/// A secret as it travels to and is stored on a node.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct SealedSecret {
+    /// Base64 encoded public key of the node it was sealed for.
+    pub recipient: String,
+    /// Base64 encoded sealed box.
+    pub sealed: String,
+}
+
+/// The value of an opened secret. It is kept out of `Debug` output, so it does not end up
+/// in a log by accident.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SecretValue(Vec<u8>);
+
+impl SecretValue {
+    pub fn new(value: Vec<u8>) -> Self {
+        Self(value)
+    }
+
+    pub fn expose(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Debug for SecretValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SecretValue(<redacted>)")
+    }
+}
+
+fn decode_public_key(key: &str) -> Result<PublicKey, SecretError> {
+    let invalid = || SecretError::InvalidKey(key.to_owned());
+    let bytes = BASE64.decode(key.trim()).map_err(|_| invalid())?;
+    let bytes = <[u8; KEY_LENGTH]>::try_from(bytes).map_err(|_| invalid())?;
+    Ok(PublicKey::from(bytes))
+}
+
+/// Seal `value` for the node which registered the base64 encoded `recipient` key.
+pub fn seal(recipient: &str, value: &SecretValue) -> Result<SealedSecret, SecretError> {
+    let sealed = decode_public_key(recipient)?
+        .seal(&mut OsRng, value.expose())
+        .map_err(|_| SecretError::Seal)?;
+    Ok(SealedSecret {
+        recipient: recipient.to_owned(),
+        sealed: BASE64.encode(sealed),
+    })
+}
+
+/// The key secrets are sealed for this node with.
+pub struct NodeKey {
+    key: SecretKey,
+}
+
+impl NodeKey {
+    /// Load the key stored at `path`, a new one is generated if there is none yet.
+    /// Replacing it makes the secrets the node holds unreadable until cloudadmd sealed them
+    /// again for the new key, which happens on the next apply of their deployments.
+    pub fn load_or_generate(path: &Path) -> Result<Self, SecretError> {
+        Ok(Self {
+            key: SecretKey::from(keyfile::load_or_generate(path)?),
+        })
+    }
+
+    /// What the node registers with, so cloudadmd can seal secrets for it.
+    pub fn public_key(&self) -> String {
+        BASE64.encode(self.key.public_key().as_bytes())
+    }
+
+    pub fn open(&self, secret: &SealedSecret) -> Result<SecretValue, SecretError> {
+        if decode_public_key(&secret.recipient)? != self.key.public_key() {
+            return Err(SecretError::WrongRecipient(secret.recipient.clone()));
+        }
+        let sealed = BASE64
+            .decode(&secret.sealed)
+            .map_err(|_| SecretError::Unseal)?;
+        self.key
+            .unseal(&sealed)
+            .map(SecretValue)
+            .map_err(|_| SecretError::Unseal)
+    }
+}
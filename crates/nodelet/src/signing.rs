@@ -0,0 +1,333 @@
+//! Ed25519 signatures of the deployment events cloudadmd publishes, so a node only acts
+//! on events of a controller it trusts.
+//!
+//! The signature covers the routing key, the time of signing and the payload and travels
+//! in the headers of the message together with the public key that made it. Nodes trust
+//! a list of keys, rotating the key of cloudadmd is done by trusting the new key next to
+//! the old one until cloudadmd switched over.
+//!
+//! A recorded event cannot be sent again later on: nodes reject events signed longer ago
+//! than `max_age_secs`, and the workers reject events not newer than the last one they
+//! took for the same resource, which its node entry keeps across restarts.
+
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use deadpool_lapin::lapin::types::{AMQPValue, FieldTable, LongString, ShortString};
+use ed25519_dalek::{Signer as _, SigningKey, VerifyingKey};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::keyfile::{self, KeyFileError};
+
+/// Base64 encoded public key the event was signed with.
+pub const KEY_HEADER: &str = "x-signing-key";
+/// Base64 encoded signature of the routing key, the time of signing and payload.
+pub const SIGNATURE_HEADER: &str = "x-signature";
+/// Milliseconds since the unix epoch when the event was signed.
+pub const SIGNED_AT_HEADER: &str = "x-signed-at";
+
+/// Clocks of controller and node may differ this much.
+const CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Error)]
+pub enum SigningError {
+    #[error(transparent)]
+    KeyFile(#[from] KeyFileError),
+
+    #[error("{0} is not a base64 encoded Ed25519 key")]
+    InvalidKey(String),
+
+    #[error("event is not signed")]
+    Unsigned,
+
+    #[error("event is signed with the untrusted key {0}")]
+    UntrustedKey(String),
+
+    #[error("signature does not match the event")]
+    BadSignature,
+
+    #[error("event was signed {age_secs}s ago, events older than {max_age_secs}s are stale")]
+    Stale { age_secs: u64, max_age_secs: u64 },
+
+    #[error("event was signed {ahead_secs}s in the future")]
+    FromTheFuture { ahead_secs: u64 },
+
+    #[error("event is not newer than the last one taken for its resource")]
+    OutOfOrder,
+
+    #[error("cannot tell whether the event is newer than the last one of its resource: {0}")]
+    OrderUnknown(String),
+}
+
+/// A signature and the public key that made it, both base64 encoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub key: String,
+    pub signature: String,
+    /// Milliseconds since the unix epoch, signed along with the event.
+    pub signed_at: u64,
+}
+
+impl Signature {
+    pub fn from_headers(headers: Option<&FieldTable>) -> Option<Self> {
+        let headers = headers?.inner();
+        let header = |name: &str| match headers.get(&ShortString::from(name)) {
+            Some(AMQPValue::LongString(value)) => {
+                Some(String::from_utf8_lossy(value.as_bytes()).into_owned())
+            }
+            _ => None,
+        };
+        Some(Self {
+            key: header(KEY_HEADER)?,
+            signature: header(SIGNATURE_HEADER)?,
+            // A signature without its time cannot be verified, the event counts as unsigned.
+            signed_at: header(SIGNED_AT_HEADER)?.parse().ok()?,
+        })
+    }
+
+    pub fn insert_into(&self, headers: &mut FieldTable) {
+        headers.insert(
+            ShortString::from(KEY_HEADER),
+            AMQPValue::LongString(LongString::from(self.key.as_str())),
+        );
+        headers.insert(
+            ShortString::from(SIGNATURE_HEADER),
+            AMQPValue::LongString(LongString::from(self.signature.as_str())),
+        );
+        headers.insert(
+            ShortString::from(SIGNED_AT_HEADER),
+            AMQPValue::LongString(LongString::from(self.signed_at.to_string())),
+        );
+    }
+}
+
+/// The routing key is signed as well, a zone event must not pass as a network event.
+fn signed_bytes(routing_key: &str, signed_at: u64, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(routing_key.len() + 9 + payload.len());
+    bytes.extend_from_slice(routing_key.as_bytes());
+    bytes.push(0);
+    bytes.extend_from_slice(&signed_at.to_be_bytes());
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
+
+fn decode_public_key(key: &str) -> Result<VerifyingKey, SigningError> {
+    let invalid = || SigningError::InvalidKey(key.to_owned());
+    let bytes = BASE64.decode(key.trim()).map_err(|_| invalid())?;
+    let bytes = <[u8; 32]>::try_from(bytes).map_err(|_| invalid())?;
+    VerifyingKey::from_bytes(&bytes).map_err(|_| invalid())
+}
+
+/// Signs the events of cloudadmd.
+pub struct Signer {
+    key: SigningKey,
+}
+
+impl Signer {
+    /// Load the secret key stored base64 encoded at `path`. A new key is generated and
+    /// stored there, readable by the owner only, if there is none yet.
+    pub fn load_or_generate(path: &Path) -> Result<Self, SigningError> {
+        Ok(Self {
+            key: SigningKey::from_bytes(&keyfile::load_or_generate(path)?),
+        })
+    }
+
+    /// What nodes put into their trusted keys.
+    pub fn public_key(&self) -> String {
+        BASE64.encode(self.key.verifying_key().as_bytes())
+    }
+
+    pub fn sign(&self, routing_key: &str, payload: &[u8]) -> Signature {
+        self.sign_at(routing_key, payload, now_millis())
+    }
+
+    /// Sign as of `signed_at`, in milliseconds since the unix epoch.
+    pub fn sign_at(&self, routing_key: &str, payload: &[u8], signed_at: u64) -> Signature {
+        let signature = self
+            .key
+            .sign(&signed_bytes(routing_key, signed_at, payload));
+        Signature {
+            key: self.public_key(),
+            signature: BASE64.encode(signature.to_bytes()),
+            signed_at,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SigningConfig {
+    /// Base64 encoded public keys of the controllers whose events are accepted.
+    #[serde(default)]
+    pub trusted_keys: Vec<String>,
+    /// Accept events without a signature. Signed events are verified nonetheless, meant
+    /// for development setups only.
+    #[serde(default)]
+    pub accept_unsigned: bool,
+    /// Events signed longer ago are rejected. Events queued while the node is down for
+    /// longer are rejected as well and have to be applied again.
+    #[serde(default = "default_max_age")]
+    pub max_age_secs: u64,
+}
+
+fn default_max_age() -> u64 {
+    3600
+}
+
+impl Default for SigningConfig {
+    fn default() -> Self {
+        Self {
+            trusted_keys: vec![],
+            accept_unsigned: false,
+            max_age_secs: default_max_age(),
+        }
+    }
+}
+
+/// Checks the events a node receives against its trusted keys.
+#[derive(Debug, Clone)]
+pub struct Verifier {
+    trusted: Vec<VerifyingKey>,
+    accept_unsigned: bool,
+    max_age: Duration,
+}
+
+impl Verifier {
+    pub fn new(config: &SigningConfig) -> Result<Self, SigningError> {
+        Ok(Self {
+            trusted: config
+                .trusted_keys
+                .iter()
+                .map(|key| decode_public_key(key))
+                .collect::<Result<_, _>>()?,
+            accept_unsigned: config.accept_unsigned,
+            max_age: Duration::from_secs(config.max_age_secs),
+        })
+    }
+
+    pub fn verify(
+        &self,
+        routing_key: &str,
+        payload: &[u8],
+        signature: Option<&Signature>,
+    ) -> Result<(), SigningError> {
+        let Some(signature) = signature else {
+            return if self.accept_unsigned {
+                Ok(())
+            } else {
+                Err(SigningError::Unsigned)
+            };
+        };
+        let key = decode_public_key(&signature.key)?;
+        if !self.trusted.contains(&key) {
+            return Err(SigningError::UntrustedKey(signature.key.clone()));
+        }
+        let bytes = BASE64
+            .decode(&signature.signature)
+            .map_err(|_| SigningError::BadSignature)?;
+        let ed25519 =
+            ed25519_dalek::Signature::from_slice(&bytes).map_err(|_| SigningError::BadSignature)?;
+        key.verify_strict(
+            &signed_bytes(routing_key, signature.signed_at, payload),
+            &ed25519,
+        )
+        .map_err(|_| SigningError::BadSignature)?;
+        self.check_age(signature.signed_at, now_millis())
+    }
+
+    /// Checked once the signature matched, so the time can be trusted.
+    fn check_age(&self, signed_at: u64, now: u64) -> Result<(), SigningError> {
+        let ahead = Duration::from_millis(signed_at.saturating_sub(now));
+        if ahead > CLOCK_SKEW {
+            return Err(SigningError::FromTheFuture {
+                ahead_secs: ahead.as_secs(),
+            });
+        }
+        let age = Duration::from_millis(now.saturating_sub(signed_at));
+        if age > self.max_age {
+            return Err(SigningError::Stale {
+                age_secs: age.as_secs(),
+                max_age_secs: self.max_age.as_secs(),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signer_and_verifier() -> (Signer, Verifier, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let signer = Signer::load_or_generate(&dir.path().join("signing.key")).unwrap();
+        let verifier = Verifier::new(&SigningConfig {
+            trusted_keys: vec![signer.public_key()],
+            ..Default::default()
+        })
+        .unwrap();
+        (signer, verifier, dir)
+    }
+
+    #[test]
+    fn fresh_events_are_accepted() {
+        let (signer, verifier, _dir) = signer_and_verifier();
+        let signature = signer.sign("zones", b"{}");
+        verifier.verify("zones", b"{}", Some(&signature)).unwrap();
+    }
+
+    #[test]
+    fn the_time_of_signing_cannot_be_changed() {
+        let (signer, verifier, _dir) = signer_and_verifier();
+        let mut signature = signer.sign("zones", b"{}");
+        signature.signed_at += 1;
+        assert!(matches!(
+            verifier.verify("zones", b"{}", Some(&signature)),
+            Err(SigningError::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn stale_events_are_rejected() {
+        let (signer, verifier, _dir) = signer_and_verifier();
+        let two_hours_ago = now_millis() - 2 * 3600 * 1000;
+        let signature = signer.sign_at("zones", b"{}", two_hours_ago);
+        assert!(matches!(
+            verifier.verify("zones", b"{}", Some(&signature)),
+            Err(SigningError::Stale {
+                max_age_secs: 3600,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn events_from_the_future_are_rejected() {
+        let (signer, verifier, _dir) = signer_and_verifier();
+        let signature = signer.sign_at("zones", b"{}", now_millis() + 3600 * 1000);
+        assert!(matches!(
+            verifier.verify("zones", b"{}", Some(&signature)),
+            Err(SigningError::FromTheFuture { .. })
+        ));
+        // Within the clock skew
+        let signature = signer.sign_at("zones", b"{}", now_millis() + 10 * 1000);
+        verifier.verify("zones", b"{}", Some(&signature)).unwrap();
+    }
+
+    #[test]
+    fn the_time_of_signing_travels_in_the_headers() {
+        let (signer, _verifier, _dir) = signer_and_verifier();
+        let signature = signer.sign("zones", b"{}");
+        let mut headers = FieldTable::default();
+        signature.insert_into(&mut headers);
+        assert_eq!(Signature::from_headers(Some(&headers)), Some(signature));
+    }
+}
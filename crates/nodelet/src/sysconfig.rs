@@ -0,0 +1,245 @@
+//! Turns the [`SystemConfig`] of a zone into the files written into its root before the
+//! first boot. sysding runs `/etc/sysding.conf` once on that boot, the site profile makes
+//! sure it is enabled.
+
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use crate::{SystemConfig, SystemUser};
+
+/// A file for the root of a zone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZoneFile {
+    /// Relative to the zone's root.
+    pub path: PathBuf,
+    pub contents: String,
+    pub mode: u32,
+}
+
+/// Ends the here-documents of the script, the keys are written as they are. Extended
+/// until no line of the document equals it, see [`heredoc_end`].
+const HEREDOC_END: &str = "NODELET_EOF";
+
+/// The files setting up a zone named `name` on its first boot.
+pub fn render(name: &str, config: &SystemConfig) -> Vec<ZoneFile> {
+    let mut files = vec![
+        ZoneFile {
+            path: PathBuf::from("etc/sysding.conf"),
+            contents: sysding_conf(name, config),
+            // Holds the root password hash.
+            mode: 0o600,
+        },
+        ZoneFile {
+            path: PathBuf::from("etc/svc/profile/site.xml"),
+            contents: site_profile(),
+            mode: 0o644,
+        },
+    ];
+    if !config.authorized_keys.is_empty() {
+        files.push(ZoneFile {
+            path: PathBuf::from("root/.ssh/authorized_keys"),
+            contents: authorized_keys(&config.authorized_keys),
+            mode: 0o600,
+        });
+    }
+    files
+}
+
+/// The script sysding runs on the first boot. Users are created there, their home
+/// directories do not exist before.
+pub fn sysding_conf(name: &str, config: &SystemConfig) -> String {
+    let mut script = String::new();
+    writeln!(
+        script,
+        "# Written by the nodelet, run once by sysding on the first boot."
+    )
+    .unwrap();
+    if let Some(timezone) = &config.timezone {
+        writeln!(script, "setup_timezone {}", shell_quote(timezone)).unwrap();
+    }
+    if let Some(locale) = &config.locale {
+        writeln!(script, "setup_locale {}", shell_quote(locale)).unwrap();
+    }
+    let hostname = config.hostname.as_deref().unwrap_or(name);
+    writeln!(script, "setup_hostname {}", shell_quote(hostname)).unwrap();
+    if let Some(hash) = &config.root_password {
+        writeln!(script, "setup_root_password {}", shell_quote(hash)).unwrap();
+    }
+    for user in &config.users {
+        write_user(&mut script, user);
+    }
+    script
+}
+
+fn write_user(script: &mut String, user: &SystemUser) {
+    let home = user.home();
+    let mut useradd = vec![
+        "useradd".to_owned(),
+        "-m".to_owned(),
+        "-d".to_owned(),
+        shell_quote(&home),
+        "-s".to_owned(),
+        shell_quote(&user.shell),
+    ];
+    if let Some(uid) = user.uid {
+        useradd.extend(["-u".to_owned(), uid.to_string()]);
+    }
+    if !user.groups.is_empty() {
+        useradd.extend(["-G".to_owned(), shell_quote(&user.groups.join(","))]);
+    }
+    useradd.push(shell_quote(&user.name));
+    writeln!(script, "{}", useradd.join(" ")).unwrap();
+
+    if user.authorized_keys.is_empty() {
+        return;
+    }
+    let ssh = shell_quote(&format!("{home}/.ssh"));
+    let keys = shell_quote(&format!("{home}/.ssh/authorized_keys"));
+    writeln!(script, "mkdir -m 700 -p {ssh}").unwrap();
+    let document = authorized_keys(&user.authorized_keys);
+    let end = heredoc_end(&document);
+    writeln!(script, "cat > {keys} <<'{end}'").unwrap();
+    write!(script, "{document}").unwrap();
+    writeln!(script, "{end}").unwrap();
+    writeln!(script, "chmod 600 {keys}").unwrap();
+    writeln!(script, "chown -R {} {ssh}", shell_quote(&user.name)).unwrap();
+}
+
+/// A delimiter no line of `document` equals, a key must not end the here-document and
+/// have the lines after it run as root.
+fn heredoc_end(document: &str) -> String {
+    let mut end = HEREDOC_END.to_owned();
+    while document.lines().any(|line| line == end) {
+        end.push('_');
+    }
+    end
+}
+
+/// Enables sysding, whose `config/finished` property keeps it from running again.
+pub fn site_profile() -> String {
+    r#"<?xml version="1.0"?>
+<!DOCTYPE service_bundle SYSTEM "/usr/share/lib/xml/dtd/service_bundle.dtd.1">
+<service_bundle type="profile" name="nodelet">
+  <service name="system/sysding" version="1" type="service">
+    <instance name="system" enabled="true"/>
+  </service>
+</service_bundle>
+"#
+    .to_owned()
+}
+
+/// One key per line, keys spanning lines are cut at the line break.
+pub fn authorized_keys(keys: &[String]) -> String {
+    keys.iter()
+        .filter_map(|key| key.lines().next())
+        .map(|key| format!("{}\n", key.trim()))
+        .collect()
+}
+
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(files: &[ZoneFile]) -> Vec<&str> {
+        files
+            .iter()
+            .map(|file| file.path.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn the_hostname_defaults_to_the_zone_name() {
+        let files = render("web", &SystemConfig::default());
+        assert_eq!(
+            paths(&files),
+            ["etc/sysding.conf", "etc/svc/profile/site.xml"]
+        );
+        assert_eq!(files[0].mode, 0o600);
+        assert_eq!(
+            files[0].contents,
+            "# Written by the nodelet, run once by sysding on the first boot.\n\
+             setup_hostname 'web'\n"
+        );
+    }
+
+    #[test]
+    fn everything_configured_is_rendered() {
+        let config = SystemConfig {
+            hostname: Some("www".to_owned()),
+            timezone: Some("Europe/Zurich".to_owned()),
+            locale: Some("en_US.UTF-8".to_owned()),
+            root_password: Some("$5$salt$hash".to_owned()),
+            authorized_keys: vec!["ssh-ed25519 AAAA root@admin\nssh-rsa BBBB".to_owned()],
+            users: vec![SystemUser {
+                name: "deploy".to_owned(),
+                uid: Some(1001),
+                shell: "/bin/bash".to_owned(),
+                home: None,
+                groups: vec!["staff".to_owned(), "www".to_owned()],
+                authorized_keys: vec!["ssh-ed25519 CCCC deploy@admin".to_owned()],
+            }],
+        };
+        let files = render("web", &config);
+        assert_eq!(
+            paths(&files),
+            [
+                "etc/sysding.conf",
+                "etc/svc/profile/site.xml",
+                "root/.ssh/authorized_keys"
+            ]
+        );
+        assert_eq!(files[2].contents, "ssh-ed25519 AAAA root@admin\n");
+        assert_eq!(files[2].mode, 0o600);
+        assert_eq!(
+            files[0].contents,
+            r#"# Written by the nodelet, run once by sysding on the first boot.
+setup_timezone 'Europe/Zurich'
+setup_locale 'en_US.UTF-8'
+setup_hostname 'www'
+setup_root_password '$5$salt$hash'
+useradd -m -d '/export/home/deploy' -s '/bin/bash' -u 1001 -G 'staff,www' 'deploy'
+mkdir -m 700 -p '/export/home/deploy/.ssh'
+cat > '/export/home/deploy/.ssh/authorized_keys' <<'NODELET_EOF'
+ssh-ed25519 CCCC deploy@admin
+NODELET_EOF
+chmod 600 '/export/home/deploy/.ssh/authorized_keys'
+chown -R 'deploy' '/export/home/deploy/.ssh'
+"#
+        );
+    }
+
+    #[test]
+    fn keys_cannot_end_the_here_document() {
+        let config = SystemConfig {
+            users: vec![SystemUser {
+                name: "deploy".to_owned(),
+                uid: None,
+                shell: "/bin/bash".to_owned(),
+                home: None,
+                groups: vec![],
+                authorized_keys: vec![
+                    "NODELET_EOF".to_owned(),
+                    "rm -rf /".to_owned(),
+                    "NODELET_EOF_".to_owned(),
+                ],
+            }],
+            ..Default::default()
+        };
+        let script = sysding_conf("web", &config);
+        assert!(script
+            .contains("<<'NODELET_EOF__'\nNODELET_EOF\nrm -rf /\nNODELET_EOF_\nNODELET_EOF__\n"));
+    }
+
+    #[test]
+    fn values_are_quoted_for_the_shell() {
+        let config = SystemConfig {
+            hostname: Some("it's; reboot".to_owned()),
+            ..Default::default()
+        };
+        assert!(sysding_conf("web", &config).contains(r"setup_hostname 'it'\''s; reboot'"));
+    }
+}
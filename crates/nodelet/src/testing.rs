@@ -0,0 +1,116 @@
+//! Shared setup of the unit tests.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use bonsaidb::core::connection::StorageConnection;
+use bonsaidb::core::schema::SerializedCollection;
+use bonsaidb::local::config::{Builder, StorageConfiguration};
+use bonsaidb::local::{Database, Storage};
+use cloud::{DeploymentReport, ResourceIdentifier};
+use tempfile::TempDir;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+use crate::driver::{FakeDriverConfig, FakeZoneDriver};
+use crate::handler::{Handler, HookConfig};
+use crate::images::{ImageConfig, ImageStore};
+use crate::secrets::NodeKey;
+use crate::{DeploymentStatus, NodeEntry, NodeObject, Zone};
+
+/// A node database which lives as long as the returned handle.
+pub fn memory_database() -> Database {
+    let storage = Storage::open(
+        StorageConfiguration::default()
+            .memory_only()
+            .with_schema::<NodeEntry>()
+            .unwrap(),
+    )
+    .unwrap();
+    storage
+        .create_database::<NodeEntry>("node-entries", true)
+        .unwrap()
+}
+
+pub fn identifier(text: &str) -> ResourceIdentifier {
+    text.parse().unwrap()
+}
+
+/// A zone without interfaces, `extra` is appended to its KDL.
+pub fn zone(extra: &str) -> Zone {
+    let kdl = format!(
+        r#"
+        brand "linked-pkg"
+        autoboot true
+        ip-type "exclusive"
+        {extra}
+        "#
+    );
+    knuffel::parse("zone.kdl", &kdl).unwrap()
+}
+
+pub fn store(
+    nodedb: &Database,
+    identifier: &ResourceIdentifier,
+    zone: Zone,
+    state: DeploymentStatus,
+) {
+    NodeEntry {
+        resource_identifier: identifier.clone(),
+        object: NodeObject::Zone(Box::new(zone)),
+        state,
+        checkpoint: None,
+        history: vec![],
+        last_event: None,
+    }
+    .overwrite_into(identifier, nodedb)
+    .map_err(|e| e.error)
+    .unwrap();
+}
+
+/// A handler on a fake driver and an in-memory node database.
+pub struct Fixture {
+    pub handler: Handler,
+    pub nodedb: Database,
+    pub driver: Arc<FakeZoneDriver>,
+    /// What the handler reports while it works.
+    pub reports: UnboundedReceiver<DeploymentReport>,
+    /// Holds the node key and the image store.
+    pub dir: TempDir,
+}
+
+pub fn fixture() -> Fixture {
+    let dir = tempfile::tempdir().unwrap();
+    let nodedb = memory_database();
+    let driver = Arc::new(FakeZoneDriver::new(FakeDriverConfig::default()));
+    let (handler, reports) = handler(&nodedb, &driver, dir.path());
+    Fixture {
+        handler,
+        nodedb,
+        driver,
+        reports,
+        dir,
+    }
+}
+
+/// A handler on `nodedb` and `driver`, like after a restart of the nodelet. Its node key
+/// and images are kept in `dir`.
+pub fn handler(
+    nodedb: &Database,
+    driver: &Arc<FakeZoneDriver>,
+    dir: &Path,
+) -> (Handler, UnboundedReceiver<DeploymentReport>) {
+    let node_key = NodeKey::load_or_generate(&dir.join("node.key")).unwrap();
+    let images = ImageStore::new(ImageConfig {
+        dir: dir.join("images"),
+    });
+    let (sender, reports) = mpsc::unbounded_channel();
+    let handler = Handler::new(
+        nodedb.clone(),
+        driver.clone(),
+        node_key,
+        images,
+        sender,
+        HookConfig::default(),
+    );
+    (handler, reports)
+}
@@ -0,0 +1,742 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Instant;
+
+use cloud::{DeploymentEvent, DeploymentReport, ResourceIdentifier};
+use serde::Deserialize;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::{watch, OwnedMutexGuard};
+use tokio::task::JoinSet;
+use tracing::{debug, error, warn};
+
+use crate::bus::{Message, MessageBus};
+use crate::handler::{failure_report, rejection_report, Handler};
+use crate::metrics;
+use crate::retry::RetryConfig;
+use crate::signing::{SigningError, Verifier};
+use crate::SignedEvent;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct WorkerConfig {
+    /// Deliveries the broker hands out before the first of them is acknowledged. Bounds
+    /// the events handled at the same time, as long as they are for different resources.
+    #[serde(default = "default_prefetch")]
+    pub prefetch: u16,
+}
+
+fn default_prefetch() -> u16 {
+    16
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        Self {
+            prefetch: default_prefetch(),
+        }
+    }
+}
+
+/// Versions of a resource share one zone, so they share a lane as well.
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub struct LaneKey {
+    tenant: Option<String>,
+    name: String,
+}
+
+impl LaneKey {
+    fn of(message: &Message) -> Option<Self> {
+        identifier(message).as_ref().map(Self::from)
+    }
+}
+
+impl From<&ResourceIdentifier> for LaneKey {
+    fn from(identifier: &ResourceIdentifier) -> Self {
+        Self {
+            tenant: identifier.tenant.clone(),
+            name: identifier.name.clone(),
+        }
+    }
+}
+
+/// Held while something works on a resource, by the lanes for each event they handle
+/// and by the reconciler for each repair. Shared between them, so the reconciler never
+/// boots or halts a zone an event is changing.
+#[derive(Debug, Clone, Default)]
+pub struct LaneLocks {
+    locks: Arc<Mutex<HashMap<LaneKey, Arc<tokio::sync::Mutex<()>>>>>,
+}
+
+impl LaneLocks {
+    /// Wait until nothing else works on the resource.
+    pub async fn enter(&self, key: &LaneKey) -> OwnedMutexGuard<()> {
+        self.lock(key).lock_owned().await
+    }
+
+    /// None while something else works on the resource.
+    pub fn try_enter(&self, key: &LaneKey) -> Option<OwnedMutexGuard<()>> {
+        self.lock(key).try_lock_owned().ok()
+    }
+
+    fn lock(&self, key: &LaneKey) -> Arc<tokio::sync::Mutex<()>> {
+        let mut locks = self.locks.lock().unwrap();
+        // Only the map holds the locks nobody works under.
+        locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+        locks.entry(key.clone()).or_default().clone()
+    }
+}
+
+/// `List` events and payloads without an identifier do not touch a resource.
+fn identifier(message: &Message) -> Option<ResourceIdentifier> {
+    let event: DeploymentEvent<serde_json::Value> =
+        serde_json::from_slice(&message.payload).ok()?;
+    match event {
+        DeploymentEvent::Ensure { identifier, .. } | DeploymentEvent::Remove { identifier, .. } => {
+            Some(identifier)
+        }
+        DeploymentEvent::List { .. } => None,
+    }
+}
+
+struct Shared {
+    handler: Handler,
+    bus: Arc<dyn MessageBus>,
+    verifier: Verifier,
+    retry: RetryConfig,
+    reports: UnboundedSender<DeploymentReport>,
+    stopping: watch::Receiver<bool>,
+    locks: LaneLocks,
+    /// Messages waiting behind the one their lane is working on.
+    lanes: Mutex<HashMap<LaneKey, VecDeque<Message>>>,
+    /// The newest event taken for a resource since the start, the node entries keep it
+    /// across restarts. Lanes take the events of a resource in order, so one that is not
+    /// newer is a replay.
+    events: Mutex<HashMap<LaneKey, SignedEvent>>,
+}
+
+/// Removes the lane when its task panics or is aborted, otherwise the later events of
+/// the resource would queue behind it forever.
+struct LaneGuard<'a> {
+    lanes: &'a Mutex<HashMap<LaneKey, VecDeque<Message>>>,
+    key: &'a LaneKey,
+    /// Set once the lane removed itself.
+    closed: bool,
+}
+
+impl Drop for LaneGuard<'_> {
+    fn drop(&mut self) {
+        if !self.closed {
+            warn!(key = ?self.key, "lane ended early, its queued events wait for a restart");
+            self.lanes
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .remove(self.key);
+        }
+    }
+}
+
+/// Handles events for different resources in parallel and the events of one resource
+/// one after the other, in the order they were handed out.
+///
+/// A failed event goes back to the bus, which hands it out again after the retry delay
+/// with the retry counted in its headers. Later events of the resource may overtake it
+/// meanwhile, the retry is refused as older than them then.
+pub struct Workers {
+    shared: Arc<Shared>,
+    tasks: JoinSet<()>,
+}
+
+impl Workers {
+    pub fn new(
+        handler: Handler,
+        bus: Arc<dyn MessageBus>,
+        verifier: Verifier,
+        retry: RetryConfig,
+        reports: UnboundedSender<DeploymentReport>,
+        stopping: watch::Receiver<bool>,
+        locks: LaneLocks,
+    ) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                handler,
+                bus,
+                verifier,
+                retry,
+                reports,
+                stopping,
+                locks,
+                lanes: Mutex::new(HashMap::new()),
+                events: Mutex::new(HashMap::new()),
+            }),
+            tasks: JoinSet::new(),
+        }
+    }
+
+    pub fn dispatch(&mut self, message: Message) {
+        let shared = self.shared.clone();
+        let Some(key) = LaneKey::of(&message) else {
+            self.tasks
+                .spawn(async move { shared.process(None, message).await });
+            return;
+        };
+        {
+            let mut lanes = shared.lanes.lock().unwrap();
+            if let Some(waiting) = lanes.get_mut(&key) {
+                debug!(?key, waiting = waiting.len(), "queued behind running event");
+                waiting.push_back(message);
+                return;
+            }
+            lanes.insert(key.clone(), VecDeque::new());
+        }
+        self.tasks
+            .spawn(async move { shared.run_lane(key, message).await });
+    }
+
+    /// Wait for the next lane to finish. Never returns while all workers are idle.
+    pub async fn join_next(&mut self) {
+        match self.tasks.join_next().await {
+            Some(Err(e)) if e.is_panic() => error!(error = %e, "worker panicked"),
+            Some(_) => {}
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Wait for the events in flight. Lanes stop taking further events once the nodelet
+    /// is stopping.
+    pub async fn drain(&mut self) {
+        while self.tasks.join_next().await.is_some() {}
+    }
+
+    /// Cancel the events in flight. Their entries keep the checkpoint they reached.
+    pub async fn abort(&mut self) {
+        self.tasks.shutdown().await;
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.tasks.len()
+    }
+}
+
+impl Shared {
+    async fn run_lane(&self, key: LaneKey, mut message: Message) {
+        let mut lane = LaneGuard {
+            lanes: &self.lanes,
+            key: &key,
+            closed: false,
+        };
+        loop {
+            let held = self.locks.enter(&key).await;
+            self.process(Some(&key), message).await;
+            drop(held);
+            let mut lanes = self.lanes.lock().unwrap();
+            // The remaining messages stay unacknowledged and come back after a restart.
+            let next = if *self.stopping.borrow() {
+                None
+            } else {
+                lanes.get_mut(&key).and_then(VecDeque::pop_front)
+            };
+            match next {
+                Some(next) => message = next,
+                None => {
+                    // Under the same lock as the last pop, so nothing is queued in between.
+                    lanes.remove(&key);
+                    lane.closed = true;
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn process(&self, key: Option<&LaneKey>, message: Message) {
+        let routing_key = message.routing_key.clone();
+        let verified = self
+            .verifier
+            .verify(&routing_key, &message.payload, message.signature.as_ref())
+            .and_then(|()| self.check_order(key, &message));
+        if let Err(e) = verified {
+            self.reject(&message, &e.to_string()).await;
+            return;
+        }
+        self.record_event(&message, None);
+
+        let started = Instant::now();
+        let handled = self.handler.handle(&routing_key, &message.payload).await;
+        metrics::message_handled(&routing_key, started.elapsed(), handled.is_ok());
+        let error = match handled {
+            Ok(report) => {
+                debug!("handled message");
+                // An entry created by the event remembers it from now on.
+                self.record_event(&message, None);
+                if let Err(e) = self.bus.ack(&message).await {
+                    error!(error = ?e, "failed to acknowledge message");
+                }
+                // Queued behind the progress reports of the same event.
+                self.send(report);
+                return;
+            }
+            Err(e) => e.to_string(),
+        };
+        error!(error, retries = message.retries, "failed to handle message");
+        if !self.retry.should_retry(message.retries) {
+            self.record_event(&message, None);
+            self.dead_letter(&message, &error).await;
+            return;
+        }
+        let retries = message.retries + 1;
+        let delay = self.retry.delay(retries);
+        debug!(?delay, "retrying message");
+        // Before the retry is out, so it is taken when it comes back.
+        if let Some(key) = key {
+            self.expect_retry(key, &message, retries);
+        }
+        self.record_event(&message, Some(retries));
+        if let Err(e) = self.bus.retry(&message, &error, delay).await {
+            // Unsettled, so the bus hands it out again after a restart.
+            error!(error = ?e, "failed to retry message");
+            return;
+        }
+        metrics::message_retried(&routing_key);
+    }
+
+    /// Takes events signed after the newest one taken for the resource. That one only
+    /// passes again when the broker hands it out again because it was never settled, or
+    /// as the retry the nodelet published for it. The retry count in the headers is not
+    /// signed, so it has to match the retry expected.
+    fn check_order(&self, key: Option<&LaneKey>, message: &Message) -> Result<(), SigningError> {
+        let (Some(key), Some(signature)) = (key, &message.signature) else {
+            return Ok(());
+        };
+        let mut events = self.events.lock().unwrap();
+        let last = match events.get(key) {
+            Some(last) => Some(*last),
+            None => self
+                .handler
+                .last_event(&key.tenant, &key.name)
+                .map_err(|e| SigningError::OrderUnknown(e.to_string()))?,
+        };
+        if let Some(last) = last {
+            let again =
+                message.redelivered || (message.retries > 0 && last.retry == Some(message.retries));
+            if signature.signed_at < last.signed_at
+                || (signature.signed_at == last.signed_at && !again)
+            {
+                return Err(SigningError::OutOfOrder);
+            }
+        }
+        events.insert(
+            key.clone(),
+            SignedEvent {
+                signed_at: signature.signed_at,
+                retry: None,
+            },
+        );
+        Ok(())
+    }
+
+    fn expect_retry(&self, key: &LaneKey, message: &Message, retries: u32) {
+        let Some(signature) = &message.signature else {
+            return;
+        };
+        self.events.lock().unwrap().insert(
+            key.clone(),
+            SignedEvent {
+                signed_at: signature.signed_at,
+                retry: Some(retries),
+            },
+        );
+    }
+
+    /// Keep the signed time of a taken event and the retry expected for it in the entry of
+    /// its resource.
+    fn record_event(&self, message: &Message, retry: Option<u32>) {
+        let (Some(identifier), Some(signature)) = (identifier(message), &message.signature) else {
+            return;
+        };
+        let event = SignedEvent {
+            signed_at: signature.signed_at,
+            retry,
+        };
+        if let Err(e) = self.handler.record_event(&identifier, event) {
+            warn!(error = %e, %identifier, "cannot record the signed time of the event");
+        }
+    }
+
+    async fn dead_letter(&self, message: &Message, error: &str) {
+        if let Err(e) = self.bus.nack(message, error).await {
+            // Unsettled, so the bus hands it out again after a restart.
+            error!(error = ?e, "failed to dead-letter message");
+            return;
+        }
+        error!(retries = message.retries, "gave up on message");
+        metrics::message_dead_lettered(&message.routing_key);
+        if let Some(report) = failure_report(&message.routing_key, &message.payload, error) {
+            self.send(report);
+        }
+    }
+
+    /// Retrying does not make a forged event any better, it goes aside right away.
+    async fn reject(&self, message: &Message, reason: &str) {
+        warn!(
+            routing_key = message.routing_key,
+            reason, "rejecting message"
+        );
+        if let Err(e) = self.bus.nack(message, &format!("rejected: {reason}")).await {
+            error!(error = ?e, "failed to dead-letter message");
+            return;
+        }
+        metrics::message_rejected(&message.routing_key);
+        self.send(rejection_report(
+            &message.routing_key,
+            &message.payload,
+            reason,
+        ));
+    }
+
+    fn send(&self, report: DeploymentReport) {
+        if self.reports.send(report).is_err() {
+            error!("report channel closed");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    use bonsaidb::local::Database;
+    use cloud::DeploymentState;
+    use futures::StreamExt;
+    use tempfile::TempDir;
+    use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+    use super::*;
+    use crate::bus::{ChannelBus, MessageStream};
+    use crate::driver::{FakeZoneDriver, Operation, ZoneState};
+    use crate::signing::{Signer, SigningConfig};
+    use crate::testing::{self, fixture, identifier, zone, Fixture};
+
+    struct Setup {
+        workers: Workers,
+        bus: Arc<ChannelBus>,
+        messages: MessageStream,
+        nodedb: Database,
+        driver: Arc<FakeZoneDriver>,
+        reports: UnboundedReceiver<DeploymentReport>,
+        report_sender: UnboundedSender<DeploymentReport>,
+        signer: Signer,
+        verifier: Verifier,
+        retry: RetryConfig,
+        progress: UnboundedReceiver<DeploymentReport>,
+        stopping: watch::Sender<bool>,
+        dir: TempDir,
+    }
+
+    /// Workers taking events from a channel bus, trusting the key of `signer` only.
+    async fn setup(retry: RetryConfig) -> Setup {
+        let Fixture {
+            handler,
+            nodedb,
+            driver,
+            reports: progress,
+            dir,
+        } = fixture();
+        let signer = Signer::load_or_generate(&dir.path().join("signing.key")).unwrap();
+        let verifier = Verifier::new(&SigningConfig {
+            trusted_keys: vec![signer.public_key()],
+            ..Default::default()
+        })
+        .unwrap();
+        let bus = Arc::new(ChannelBus::new());
+        let (report_sender, reports) = mpsc::unbounded_channel();
+        let (stopping, stopped) = watch::channel(false);
+        Setup {
+            workers: Workers::new(
+                handler,
+                bus.clone(),
+                verifier.clone(),
+                retry.clone(),
+                report_sender.clone(),
+                stopped,
+                LaneLocks::default(),
+            ),
+            messages: bus.consume().await.unwrap(),
+            bus,
+            nodedb,
+            driver,
+            reports,
+            report_sender,
+            signer,
+            verifier,
+            retry,
+            progress,
+            stopping,
+            dir,
+        }
+    }
+
+    fn ensure_web() -> Vec<u8> {
+        serde_json::to_vec(&DeploymentEvent::Ensure {
+            data: zone(""),
+            identifier: identifier("res://example.org/web@1.0.0"),
+        })
+        .unwrap()
+    }
+
+    impl Setup {
+        /// Hand the next message of the bus to the workers and wait until it is handled.
+        async fn run_next(&mut self) {
+            let message = self.messages.next().await.unwrap().unwrap();
+            self.workers.dispatch(message);
+            self.workers.join_next().await;
+        }
+
+        /// Start over with new workers on the same node database and bus.
+        fn restart(&mut self) {
+            let (handler, progress) = testing::handler(&self.nodedb, &self.driver, self.dir.path());
+            self.progress = progress;
+            self.workers = Workers::new(
+                handler,
+                self.bus.clone(),
+                self.verifier.clone(),
+                self.retry.clone(),
+                self.report_sender.clone(),
+                self.stopping.subscribe(),
+                LaneLocks::default(),
+            );
+        }
+
+        fn final_reports(&mut self) -> Vec<DeploymentReport> {
+            let mut reports = vec![];
+            while let Ok(report) = self.reports.try_recv() {
+                reports.push(report);
+            }
+            reports
+        }
+    }
+
+    #[tokio::test]
+    async fn signed_events_are_handled_and_acknowledged() {
+        let mut setup = setup(RetryConfig::default()).await;
+        let payload = ensure_web();
+        let signature = setup.signer.sign("zones", &payload);
+        setup.bus.send_signed("zones", payload, signature);
+        setup.run_next().await;
+
+        assert_eq!(setup.bus.acked().len(), 1);
+        assert!(setup.bus.unsettled().is_empty());
+        assert_eq!(
+            setup.driver.zone("example.org-web").unwrap().state,
+            ZoneState::Running
+        );
+        assert!(matches!(
+            setup.final_reports().as_slice(),
+            [DeploymentReport::Ensure {
+                result: Some(Ok(())),
+                ..
+            }]
+        ));
+    }
+
+    #[tokio::test]
+    async fn unsigned_events_are_rejected() {
+        let mut setup = setup(RetryConfig::default()).await;
+        setup.bus.send("zones", ensure_web());
+        setup.run_next().await;
+
+        let dead_letters = setup.bus.dead_letters();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].1, "rejected: event is not signed");
+        assert!(setup.driver.zone("example.org-web").is_none());
+        assert!(matches!(
+            setup.final_reports().as_slice(),
+            [DeploymentReport::Rejected { .. }]
+        ));
+    }
+
+    #[tokio::test]
+    async fn events_which_keep_failing_are_dead_lettered() {
+        let mut setup = setup(RetryConfig {
+            max_attempts: 2,
+            base_delay_ms: 1,
+            max_delay_ms: 1,
+        })
+        .await;
+        setup.driver.inject_failure(Operation::Configure, None);
+        let payload = ensure_web();
+        let signature = setup.signer.sign("zones", &payload);
+        setup.bus.send_signed("zones", payload, signature);
+        setup.run_next().await;
+        assert!(setup.bus.dead_letters().is_empty());
+        // The retry comes back as another delivery.
+        setup.run_next().await;
+
+        let dead_letters = setup.bus.dead_letters();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].0.retries, 1);
+        assert!(matches!(
+            setup.final_reports().as_slice(),
+            [DeploymentReport::Ensure {
+                state: DeploymentState::Failed,
+                result: Some(Err(_)),
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn a_panicking_lane_is_removed() {
+        let key = LaneKey {
+            tenant: Some("example.org".to_owned()),
+            name: "web".to_owned(),
+        };
+        let lanes = Mutex::new(HashMap::from([(key.clone(), VecDeque::new())]));
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            let _lane = LaneGuard {
+                lanes: &lanes,
+                key: &key,
+                closed: false,
+            };
+            panic!("handler panicked");
+        }));
+        assert!(result.is_err());
+        assert!(lanes.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn versions_of_a_resource_share_their_lane_lock() {
+        let locks = LaneLocks::default();
+        let held = locks
+            .enter(&LaneKey::from(&identifier("res://example.org/web@1.0.0")))
+            .await;
+        let other_version = LaneKey::from(&identifier("res://example.org/web@2.0.0"));
+        assert!(locks.try_enter(&other_version).is_none());
+        assert!(locks
+            .try_enter(&LaneKey::from(&identifier("res://example.org/db@1.0.0")))
+            .is_some());
+
+        drop(held);
+        assert!(locks.try_enter(&other_version).is_some());
+    }
+
+    #[tokio::test]
+    async fn events_older_than_the_last_one_of_their_resource_are_rejected() {
+        let mut setup = setup(RetryConfig::default()).await;
+        let payload = ensure_web();
+        let newer = setup.signer.sign("zones", &payload);
+        let older = setup
+            .signer
+            .sign_at("zones", &payload, newer.signed_at - 1000);
+        setup.bus.send_signed("zones", payload.clone(), newer);
+        setup.run_next().await;
+        setup.bus.send_signed("zones", payload, older);
+        setup.run_next().await;
+
+        assert_eq!(setup.bus.acked().len(), 1);
+        let dead_letters = setup.bus.dead_letters();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(
+            dead_letters[0].1,
+            "rejected: event is not newer than the last one taken for its resource"
+        );
+    }
+
+    #[tokio::test]
+    async fn replays_are_rejected_after_a_restart() {
+        let mut setup = setup(RetryConfig::default()).await;
+        let payload = ensure_web();
+        let signature = setup.signer.sign("zones", &payload);
+        setup
+            .bus
+            .send_signed("zones", payload.clone(), signature.clone());
+        setup.run_next().await;
+        setup.restart();
+
+        // Captured on the broker and published again.
+        setup.bus.send_signed("zones", payload, signature);
+        setup.run_next().await;
+
+        assert_eq!(setup.bus.acked().len(), 1);
+        let dead_letters = setup.bus.dead_letters();
+        assert_eq!(dead_letters.len(), 1);
+        assert!(dead_letters[0]
+            .1
+            .starts_with("rejected: event is not newer"));
+    }
+
+    #[tokio::test]
+    async fn unsettled_events_are_taken_again_after_a_restart() {
+        let mut setup = setup(RetryConfig::default()).await;
+        let payload = ensure_web();
+        let first = setup.signer.sign("zones", &payload);
+        setup
+            .bus
+            .send_signed("zones", payload.clone(), first.clone());
+        setup.run_next().await;
+
+        // Stopped after the event was checked, before it was settled.
+        let signature = setup
+            .signer
+            .sign_at("zones", &payload, first.signed_at + 1000);
+        let tag = setup.bus.send_signed("zones", payload, signature);
+        let message = setup.messages.next().await.unwrap().unwrap();
+        let key = LaneKey::of(&message).unwrap();
+        setup
+            .workers
+            .shared
+            .check_order(Some(&key), &message)
+            .unwrap();
+        setup.workers.shared.record_event(&message, None);
+        setup.restart();
+
+        setup.bus.redeliver(tag).unwrap();
+        setup.run_next().await;
+
+        assert_eq!(setup.bus.acked().len(), 2);
+        assert!(setup.bus.dead_letters().is_empty());
+    }
+
+    #[tokio::test]
+    async fn the_retry_count_survives_a_restart() {
+        let mut setup = setup(RetryConfig {
+            max_attempts: 3,
+            base_delay_ms: 1,
+            max_delay_ms: 1,
+        })
+        .await;
+        setup.driver.inject_failure(Operation::Configure, None);
+        let payload = ensure_web();
+        let signature = setup.signer.sign("zones", &payload);
+        setup.bus.send_signed("zones", payload, signature);
+        setup.run_next().await;
+        setup.restart();
+        setup.run_next().await;
+        setup.restart();
+        setup.run_next().await;
+
+        assert!(setup.bus.unsettled().is_empty());
+        let dead_letters = setup.bus.dead_letters();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].0.retries, 2);
+    }
+
+    #[tokio::test]
+    async fn retries_cannot_be_forged() {
+        let mut setup = setup(RetryConfig::default()).await;
+        let payload = ensure_web();
+        let signature = setup.signer.sign("zones", &payload);
+        setup
+            .bus
+            .send_signed("zones", payload.clone(), signature.clone());
+        setup.run_next().await;
+
+        // The retry count is not signed, claiming one does not make a replay pass.
+        let tag = setup.bus.send_signed("zones", payload, signature);
+        let mut message = setup.messages.next().await.unwrap().unwrap();
+        assert_eq!(message.tag, tag);
+        message.retries = 1;
+        setup.workers.dispatch(message);
+        setup.workers.join_next().await;
+
+        assert_eq!(setup.bus.acked().len(), 1);
+        assert_eq!(setup.bus.dead_letters().len(), 1);
+    }
+}
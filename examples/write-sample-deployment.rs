@@ -4,7 +4,7 @@ use std::{
 };
 
 use chrono::Utc;
-use cloud::{Deployment, ResourceIdentifier};
+use cloud::{Deployment, DeploymentState, ResourceIdentifier};
 use miette::IntoDiagnostic;
 use semver::Version;
 
@@ -19,10 +19,13 @@ fn main() -> miette::Result<()> {
         created_at: Utc::now().naive_utc(),
         updated_at: None,
         resources: vec![],
+        state: DeploymentState::Configured,
         files: vec![],
         selector: cloud::Selector::Node {
             name: "testnode".to_owned(),
         },
+        progress: None,
+        health: None,
     };
 
     if !Path::new("sample_data").exists() {
@@ -0,0 +1,90 @@
+use std::fmt::{Debug, Formatter};
+
+use arc_bytes::serde::Bytes;
+use bonsaidb::core::api::Api;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{Deployment, ResourceIdentifier};
+
+/// Store a deployment in the controller and roll it out to the nodes.
+#[derive(Debug, Serialize, Deserialize, Api)]
+#[api(name = "apply-deployment", response = (), error = ApplyDeploymentError)]
+pub struct ApplyDeployment {
+    pub deployment: Deployment,
+}
+
+#[derive(Debug, Clone, Error, Serialize, Deserialize)]
+pub enum ApplyDeploymentError {
+    #[error("deployment rejected, it conflicts with the stored topology:\n{}", .conflicts.join("\n"))]
+    Rejected { conflicts: Vec<String> },
+    #[error("invalid deployment: {0}")]
+    Invalid(String),
+    #[error("controller failed to apply the deployment: {0}")]
+    Internal(String),
+}
+
+/// Store the value of a secret in the controller. Zones pick it up the next time their
+/// deployment is applied.
+#[derive(Serialize, Deserialize, Api)]
+#[api(name = "store-secret", response = (), error = StoreSecretError)]
+pub struct StoreSecret {
+    pub identifier: ResourceIdentifier,
+    pub value: Bytes,
+}
+
+// Requests end up in logs, the value must not.
+impl Debug for StoreSecret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StoreSecret")
+            .field("identifier", &self.identifier)
+            .field("value", &"<redacted>")
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Error, Serialize, Deserialize)]
+pub enum StoreSecretError {
+    #[error("invalid secret: {0}")]
+    Invalid(String),
+    #[error("controller failed to store the secret: {0}")]
+    Internal(String),
+}
+
+/// Seal secrets for the node with the key it registered with last. Nodes keep the key
+/// approved before until the new one is approved, `key` has to match it.
+#[derive(Debug, Serialize, Deserialize, Api)]
+#[api(name = "approve-node-key", response = (), error = ApproveNodeKeyError)]
+pub struct ApproveNodeKey {
+    pub node: String,
+    pub key: String,
+}
+
+#[derive(Debug, Clone, Error, Serialize, Deserialize)]
+pub enum ApproveNodeKeyError {
+    #[error("node {0} is not registered")]
+    UnknownNode(String),
+    #[error("node {node} did not offer the key {key}")]
+    NotOffered { node: String, key: String },
+    #[error("controller failed to approve the key: {0}")]
+    Internal(String),
+}
+
+/// Let selectors match the node on the labels it registered with last. Nodes keep the
+/// labels approved before until the new ones are approved, `labels` have to match them.
+#[derive(Debug, Serialize, Deserialize, Api)]
+#[api(name = "approve-node-labels", response = (), error = ApproveNodeLabelsError)]
+pub struct ApproveNodeLabels {
+    pub node: String,
+    pub labels: Vec<String>,
+}
+
+#[derive(Debug, Clone, Error, Serialize, Deserialize)]
+pub enum ApproveNodeLabelsError {
+    #[error("node {0} is not registered")]
+    UnknownNode(String),
+    #[error("node {node} did not offer the labels {}", .labels.join(", "))]
+    NotOffered { node: String, labels: Vec<String> },
+    #[error("controller failed to approve the labels: {0}")]
+    Internal(String),
+}
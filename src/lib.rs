@@ -1,14 +1,17 @@
+use std::cmp::Ordering;
 use std::fmt::Display;
 use arc_bytes::serde::Bytes;
 use bonsaidb::core::key::Key;
 use bonsaidb::core::schema::Collection;
-use chrono::{DateTime, NaiveDateTime};
+use chrono::NaiveDateTime;
 use miette::Diagnostic;
 use semver::Version;
 use serde::{de::Visitor, Deserialize, Serialize};
 use std::str::FromStr;
 use thiserror::Error;
 
+pub mod api;
+
 #[derive(Error, Diagnostic, Debug)]
 pub enum ResourceIdentifierParseError {
     #[error(transparent)]
@@ -21,7 +24,7 @@ pub enum ResourceIdentifierParseError {
     NoVersion,
 }
 
-#[derive(Key, Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
+#[derive(Key, Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct ResourceIdentifier {
     pub tenant: Option<String>,
     pub name: String,
@@ -73,6 +76,16 @@ impl ResourceIdentifier {
             revision: 0,
         }
     }
+
+    /// Orders versions of the same resource by their semantic version and revision. The
+    /// derived order compares the version strings, which puts 0.10.0 before 0.9.0.
+    pub fn cmp_version(&self, other: &Self) -> Ordering {
+        let parse = |version: &str| Version::parse(version).ok();
+        parse(&self.version)
+            .cmp(&parse(&other.version))
+            .then_with(|| self.version.cmp(&other.version))
+            .then_with(|| self.revision.cmp(&other.revision))
+    }
 }
 
 impl FromStr for ResourceIdentifier {
@@ -117,7 +130,7 @@ impl FromStr for ResourceIdentifier {
 impl Display for ResourceIdentifier {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let version_string = if self.revision == 0 {
-            format!("{}", self.version)
+            self.version.to_string()
         } else {
             format!("{}-{}", self.version, self.revision)
         };
@@ -135,6 +148,19 @@ pub enum Selector {
     LabelMatcher { labels: Vec<String> },
 }
 
+impl Selector {
+    /// Whether a deployment with this selector goes to `node`. A label matcher needs all
+    /// of its labels on the node.
+    pub fn matches(&self, node: &Node) -> bool {
+        match self {
+            Selector::Node { name } => &node.name == name,
+            Selector::LabelMatcher { labels } => {
+                labels.iter().all(|label| node.labels.contains(label))
+            }
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Collection)]
 #[collection(name = "deployments", primary_key = ResourceIdentifier)]
 pub struct Deployment {
@@ -146,9 +172,15 @@ pub struct Deployment {
     pub state: DeploymentState,
     pub files: Vec<File>,
     pub selector: Selector,
+    /// The latest status report of the node working on this deployment.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub progress: Option<Progress>,
+    /// How the services of the deployed zone are doing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health: Option<ZoneHealth>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub enum DeploymentState {
     Configured,
     Installed,
@@ -156,8 +188,10 @@ pub enum DeploymentState {
     Started,
     Stopping,
     Stopped,
+    Uninstalling,
     Archived,
     Orphaned,
+    Failed,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -173,11 +207,40 @@ pub enum FileKind {
     Normal,
 }
 
+/// A password, key or certificate kept by cloudadmd for the zones of its tenant. The
+/// value is encrypted with the key of cloudadmd and only ever leaves it sealed for the
+/// node a zone using it is deployed to.
+#[derive(Debug, Serialize, Deserialize, Collection)]
+#[collection(name = "secrets", primary_key = ResourceIdentifier)]
+pub struct Secret {
+    #[natural_id]
+    pub resource_identifier: ResourceIdentifier,
+    pub created_at: NaiveDateTime,
+    pub updated_at: Option<NaiveDateTime>,
+    pub nonce: Bytes,
+    pub ciphertext: Bytes,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub enum DeploymentEvent<T> {
     Ensure { data: T, identifier: ResourceIdentifier },
     Remove { data: T, identifier: ResourceIdentifier },
-    List { requester: String},
+    List {
+        requester: String,
+        /// Only list resources of this tenant.
+        #[serde(default)]
+        tenant: Option<String>,
+        /// Only list resources of this kind.
+        #[serde(default)]
+        kind: Option<ResourceKind>,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceKind {
+    Zone,
+    Network,
+    Secret,
 }
 
 /// Emitted by the nodelet multiple times during the setup process.
@@ -188,13 +251,204 @@ pub enum DeploymentReport {
         identifier: ResourceIdentifier,
         state: DeploymentState,
         result: Option<Result<(), String>>,
+        /// What differed from the spec already deployed on the node.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        changes: Vec<ResourceChange>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        progress: Option<Progress>,
     },
     Remove {
         identifier: ResourceIdentifier,
         state: DeploymentState,
         result: Option<Result<(), String>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        progress: Option<Progress>,
     },
     List {
         resources: Vec<(ResourceIdentifier, DeploymentState)>
     },
+    /// The services of a zone changed their state.
+    Health {
+        identifier: ResourceIdentifier,
+        health: ZoneHealth,
+        services: Vec<ServiceHealth>,
+        /// What the node did about it, like restarting a service or rebooting the zone.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        action: Option<String>,
+    },
+    /// The node refused an event, its signature did not check out.
+    Rejected {
+        routing_key: String,
+        /// Read from the rejected event, so whoever sent it chose it.
+        identifier: Option<ResourceIdentifier>,
+        reason: String,
+    },
+}
+
+/// How disruptive it is to apply a change to a deployed resource.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ChangeImpact {
+    /// Applied while the resource keeps running.
+    Live,
+    Reboot,
+    Reinstall,
+}
+
+/// One difference between the deployed and the requested spec of a resource.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct ResourceChange {
+    pub field: String,
+    pub impact: ChangeImpact,
+    pub description: String,
+}
+
+/// Where a long running operation on a node is at.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct Progress {
+    pub step: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub percent: Option<u8>,
+    /// Output of the step since the previous report.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub log: Vec<String>,
+}
+
+/// State of an SMF service inside a zone, as `svcs` shows it.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ServiceState {
+    Online,
+    /// Running, but with reduced function.
+    Degraded,
+    /// Failed and waiting for an administrator.
+    Maintenance,
+    /// Enabled, but waiting for its dependencies.
+    Offline,
+    Disabled,
+    /// Not known to the zone's service repository.
+    Uninitialized,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct ServiceHealth {
+    pub service: String,
+    pub state: ServiceState,
+}
+
+/// Sums up the services of a zone.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ZoneHealth {
+    Healthy,
+    /// Some services are degraded or waiting for their dependencies.
+    Degraded,
+    /// Some services failed or are disabled.
+    Unhealthy,
+}
+
+/// A node running the nodelet, as registered with cloudadmd.
+#[derive(Debug, Serialize, Deserialize, Collection, Clone)]
+#[collection(name = "nodes", primary_key = String)]
+pub struct Node {
+    #[natural_id]
+    pub name: String,
+    /// Labels selectors match against, the ones an administrator approved.
+    pub labels: Vec<String>,
+    /// Other labels a registration offered, they replace `labels` once an administrator
+    /// approved them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending_labels: Option<Vec<String>>,
+    pub version: String,
+    pub capabilities: Vec<String>,
+    pub capacity: Capacity,
+    #[serde(default)]
+    pub facts: NodeFacts,
+    /// Base64 encoded X25519 key secrets are sealed with for this node. Pinned once an
+    /// administrator approved the key a registration offered.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption_key: Option<String>,
+    /// A key a registration offered, secrets are sealed for it once an administrator
+    /// approved it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending_encryption_key: Option<String>,
+    pub registered_at: NaiveDateTime,
+    pub last_seen: NaiveDateTime,
+    pub status: NodeStatus,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum NodeStatus {
+    Ready,
+    /// Missed its heartbeats.
+    Unreachable,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct Capacity {
+    pub cpus: u32,
+    pub memory_mb: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_zones: Option<u32>,
+}
+
+/// What a nodelet tells cloudadmd about itself.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum NodeEvent {
+    Register(Box<NodeRegistration>),
+    Heartbeat { name: String },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NodeRegistration {
+    pub name: String,
+    pub labels: Vec<String>,
+    pub version: String,
+    pub capabilities: Vec<String>,
+    pub capacity: Capacity,
+    #[serde(default)]
+    pub facts: NodeFacts,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption_key: Option<String>,
+}
+
+/// What the nodelet found out about its host.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct NodeFacts {
+    pub cpus: Option<u32>,
+    pub memory_mb: Option<u64>,
+    #[serde(default)]
+    pub pools: Vec<PoolFacts>,
+    #[serde(default)]
+    pub links: Vec<LinkFacts>,
+    pub os: Option<OsFacts>,
+    #[serde(default)]
+    pub zones: Vec<ZoneFacts>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct PoolFacts {
+    pub name: String,
+    pub size_bytes: u64,
+    pub free_bytes: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct LinkFacts {
+    pub name: String,
+    pub state: String,
+    pub speed_mbps: Option<u64>,
+    pub mtu: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct OsFacts {
+    pub id: String,
+    pub name: String,
+    pub version: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ZoneFacts {
+    pub name: String,
+    pub state: String,
+    pub brand: String,
 }